@@ -0,0 +1,195 @@
+// Operator alerts. Feed workers, Nitter health checks, the Discord handler and the relay checks
+// report whether their last attempt worked, AlertTracker turns the reports into alerts when a
+// component starts or stops failing and alert_listener sends them as encrypted DMs to
+// OPERATOR_PUBKEYS.
+
+use crate::dm;
+use crate::error;
+use crate::outbox::Outbox;
+use crate::relays::{self, RelayPool};
+use crate::simpledb::FeedId;
+use crate::utils;
+use log::{debug, error, warn};
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+
+pub type AlertSender = tokio::sync::mpsc::Sender<ConnectionMessage>;
+pub type AlertReceiver = tokio::sync::mpsc::Receiver<ConnectionMessage>;
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Component {
+    Discord,
+    Nitter,
+    Relay(String),
+    Feed(FeedId),
+}
+
+impl std::fmt::Display for Component {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Component::Discord => write!(f, "Discord"),
+            Component::Nitter => write!(f, "Nitter"),
+            Component::Relay(relay) => write!(f, "Relay {}", relay),
+            Component::Feed(feed) => write!(f, "Feed {}", feed),
+        }
+    }
+}
+
+#[derive(PartialEq, Debug)]
+pub enum ConnectionStatus {
+    Success,
+    Failed,
+}
+
+#[derive(Debug)]
+pub struct ConnectionMessage {
+    pub component: Component,
+    pub status: ConnectionStatus,
+    // What went wrong, empty for successes
+    pub detail: String,
+    pub timestamp: SystemTime,
+}
+
+impl ConnectionMessage {
+    pub fn success(component: Component) -> ConnectionMessage {
+        ConnectionMessage {
+            component,
+            status: ConnectionStatus::Success,
+            detail: String::new(),
+            timestamp: SystemTime::now(),
+        }
+    }
+
+    pub fn failed(component: Component, detail: String) -> ConnectionMessage {
+        ConnectionMessage {
+            component,
+            status: ConnectionStatus::Failed,
+            detail,
+            timestamp: SystemTime::now(),
+        }
+    }
+}
+
+struct Failing {
+    failures: u32,
+    since: SystemTime,
+    alerted_at: Option<SystemTime>,
+}
+
+// Alerts once a component failed `failure_threshold` times in a row, reminds every
+// `repeat_after` while it keeps failing and tells when it works again
+pub struct AlertTracker {
+    failure_threshold: u32,
+    repeat_after: Option<Duration>,
+    failing: HashMap<Component, Failing>,
+}
+
+fn format_duration(duration: Duration) -> String {
+    compound_duration::format_dhms(duration.as_secs())
+}
+
+impl AlertTracker {
+    pub fn new(failure_threshold: u32, repeat_after: Option<Duration>) -> AlertTracker {
+        AlertTracker {
+            failure_threshold: failure_threshold.max(1),
+            repeat_after,
+            failing: HashMap::new(),
+        }
+    }
+
+    pub fn from_config(config: &utils::Config) -> AlertTracker {
+        let repeat_after = match config.alert_repeat_secs {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        };
+        AlertTracker::new(config.alert_failure_threshold, repeat_after)
+    }
+
+    // Alert to send because of `message`, if any
+    pub fn update(&mut self, message: &ConnectionMessage) -> Option<String> {
+        let component = &message.component;
+        match message.status {
+            ConnectionStatus::Success => {
+                let failing = self.failing.remove(component)?;
+                // Failures below the threshold go by quietly
+                failing.alerted_at?;
+                let down_for = message.timestamp.duration_since(failing.since).unwrap_or_default();
+                Some(format!("{} is working again after {}", component, format_duration(down_for)))
+            }
+            ConnectionStatus::Failed => {
+                let failing = self.failing.entry(component.clone()).or_insert(Failing {
+                    failures: 0,
+                    since: message.timestamp,
+                    alerted_at: None,
+                });
+                failing.failures += 1;
+
+                let alert = match failing.alerted_at {
+                    None if failing.failures >= self.failure_threshold => {
+                        format!("{} is failing: {}", component, message.detail)
+                    }
+                    Some(alerted_at)
+                        if self.repeat_after.is_some_and(|repeat_after| {
+                            message.timestamp.duration_since(alerted_at).unwrap_or_default() >= repeat_after
+                        }) =>
+                    {
+                        let down_for = message.timestamp.duration_since(failing.since).unwrap_or_default();
+                        format!("{} is still failing after {}: {}", component, format_duration(down_for), message.detail)
+                    }
+                    _ => return None,
+                };
+                failing.alerted_at = Some(message.timestamp);
+                Some(alert)
+            }
+        }
+    }
+}
+
+pub async fn alert_listener(
+    mut rx: AlertReceiver,
+    outbox: Outbox,
+    keypair: secp256k1::KeyPair,
+    config: utils::Config,
+) {
+    let mut tracker = AlertTracker::from_config(&config);
+
+    while let Some(message) = rx.recv().await {
+        let alert = match tracker.update(&message) {
+            Some(alert) => alert,
+            None => continue,
+        };
+        warn!("{}", alert);
+
+        for operator in &config.operator_pubkeys {
+            match dm::direct_message(&keypair, operator, &alert, config.alert_dm_kind) {
+                Ok(event) => outbox.send(&event),
+                Err(e) => error!("Failed to encrypt the alert for {}: {}", operator, e),
+            }
+        }
+    }
+}
+
+// Opens and drops a websocket connection to `relay`, through Tor with --tor
+async fn check_relay(relay: &str, config: &utils::Config) -> error::Result<()> {
+    let proxy = config.use_tor.then_some(config.tor_socks_address.as_str());
+    relays::connect(relay, proxy).await.map(|_| ())
+}
+
+pub async fn relay_checker(config: utils::Config, pool: RelayPool, alerts: AlertSender) {
+    loop {
+        tokio::time::sleep(Duration::from_secs(config.relay_check_secs)).await;
+        for relay in pool.defaults() {
+            let message = match check_relay(&relay, &config).await {
+                Ok(()) => ConnectionMessage::success(Component::Relay(relay.clone())),
+                Err(e) => {
+                    debug!("Relay check of {} failed: {}", relay, e);
+                    ConnectionMessage::failed(Component::Relay(relay.clone()), e.to_string())
+                }
+            };
+            let _ = alerts.send(message).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests;
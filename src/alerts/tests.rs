@@ -0,0 +1,74 @@
+use super::*;
+use crate::testing::{self, TestBot};
+
+fn at(component: Component, status: ConnectionStatus, secs: u64) -> ConnectionMessage {
+    ConnectionMessage {
+        component,
+        status,
+        detail: "Connection refused".to_string(),
+        timestamp: SystemTime::UNIX_EPOCH + Duration::from_secs(secs),
+    }
+}
+
+#[test]
+fn alerts_after_the_failure_threshold() {
+    let mut tracker = AlertTracker::new(3, None);
+    let relay = Component::Relay("wss://relay.example".to_string());
+
+    assert_eq!(tracker.update(&at(relay.clone(), ConnectionStatus::Failed, 0)), None);
+    assert_eq!(tracker.update(&at(relay.clone(), ConnectionStatus::Failed, 60)), None);
+    assert_eq!(
+        tracker.update(&at(relay.clone(), ConnectionStatus::Failed, 120)).as_deref(),
+        Some("Relay wss://relay.example is failing: Connection refused")
+    );
+    assert_eq!(tracker.update(&at(relay.clone(), ConnectionStatus::Failed, 180)), None);
+    assert_eq!(
+        tracker.update(&at(relay.clone(), ConnectionStatus::Success, 3600)).as_deref(),
+        Some("Relay wss://relay.example is working again after 1h")
+    );
+    assert_eq!(tracker.update(&at(relay, ConnectionStatus::Success, 3660)), None);
+}
+
+#[test]
+fn short_failures_and_other_components_stay_quiet() {
+    let mut tracker = AlertTracker::new(2, None);
+    let feed = Component::Feed(FeedId::twitter("nasa"));
+
+    assert_eq!(tracker.update(&at(feed.clone(), ConnectionStatus::Failed, 0)), None);
+    assert_eq!(tracker.update(&at(Component::Nitter, ConnectionStatus::Failed, 1)), None);
+    assert_eq!(tracker.update(&at(feed.clone(), ConnectionStatus::Success, 2)), None);
+    // The success reset the count
+    assert_eq!(tracker.update(&at(feed, ConnectionStatus::Failed, 3)), None);
+    assert!(tracker.update(&at(Component::Nitter, ConnectionStatus::Failed, 4)).is_some());
+}
+
+#[test]
+fn reminds_while_failing() {
+    let mut tracker = AlertTracker::new(1, Some(Duration::from_secs(86400)));
+
+    assert!(tracker.update(&at(Component::Discord, ConnectionStatus::Failed, 0)).is_some());
+    assert_eq!(tracker.update(&at(Component::Discord, ConnectionStatus::Failed, 3600)), None);
+    assert_eq!(
+        tracker.update(&at(Component::Discord, ConnectionStatus::Failed, 86400)).as_deref(),
+        Some("Discord is still failing after 1d: Connection refused")
+    );
+    assert_eq!(tracker.update(&at(Component::Discord, ConnectionStatus::Failed, 90000)), None);
+}
+
+#[tokio::test]
+async fn operators_get_dms_when_nitter_fails_and_recovers() {
+    let test = TestBot::start(&[]).await;
+    let operator = test.operator.x_only_public_key().0.to_string();
+
+    test.nitter.set_down(0, true);
+    let dms = test.relay.wait_for_to(&operator, 1059, 1).await;
+    let rumor = testing::open_gift_wrap(&test.operator, &dms[0]);
+    assert_eq!(rumor["kind"], 14);
+    assert_eq!(rumor["pubkey"], test.config.botpub);
+    assert!(rumor["content"].as_str().unwrap().starts_with("Nitter is failing"), "{}", rumor["content"]);
+
+    test.nitter.set_down(0, false);
+    let dms = test.relay.wait_for_to(&operator, 1059, 2).await;
+    let rumor = testing::open_gift_wrap(&test.operator, &dms[1]);
+    assert!(rumor["content"].as_str().unwrap().starts_with("Nitter is working again"), "{}", rumor["content"]);
+}
@@ -0,0 +1,208 @@
+// Encrypted direct messages. NIP-04 messages are kind 4 events readable by every client, NIP-17
+// messages are NIP-44 encrypted and gift wrapped (NIP-59), so relays only see a throwaway key
+// and the recipient.
+
+use crate::error::{self, Error};
+use crate::utils;
+use aes::cipher::block_padding::Pkcs7;
+use aes::cipher::{BlockEncryptMut, KeyIvInit, StreamCipher};
+#[cfg(test)]
+use aes::cipher::BlockDecryptMut;
+use base64::Engine;
+use hmac::Mac;
+use rand::{Rng, RngCore};
+use secp256k1::hashes::{sha256, Hash};
+use secp256k1::{KeyPair, SecretKey, XOnlyPublicKey};
+
+type Aes256CbcEnc = cbc::Encryptor<aes::Aes256>;
+#[cfg(test)]
+type Aes256CbcDec = cbc::Decryptor<aes::Aes256>;
+type HmacSha256 = hmac::Hmac<sha2::Sha256>;
+
+const BASE64: base64::engine::GeneralPurpose = base64::engine::general_purpose::STANDARD;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DmKind {
+    Nip04,
+    Nip17,
+}
+
+impl DmKind {
+    pub fn parse(kind: &str) -> Option<DmKind> {
+        match kind {
+            "nip04" => Some(DmKind::Nip04),
+            "nip17" => Some(DmKind::Nip17),
+            _ => None,
+        }
+    }
+}
+
+// Event carrying `text` from `keypair` to `recipient`
+pub fn direct_message(keypair: &KeyPair, recipient: &XOnlyPublicKey, text: &str, kind: DmKind) -> error::Result<nostr_bot::Event> {
+    match kind {
+        DmKind::Nip04 => Ok(nostr_bot::Event::new(
+            keypair,
+            utils::unix_timestamp(),
+            4,
+            vec![vec!["p".to_string(), recipient.to_string()]],
+            nip04_encrypt(&keypair.secret_key(), recipient, text),
+        )),
+        DmKind::Nip17 => gift_wrap(keypair, recipient, text),
+    }
+}
+
+// x coordinate of the ECDH point, the shared secret of NIP-04 and NIP-44
+fn shared_x(seckey: &SecretKey, pubkey: &XOnlyPublicKey) -> [u8; 32] {
+    let pubkey = pubkey.public_key(secp256k1::Parity::Even);
+    let point = secp256k1::ecdh::shared_secret_point(&pubkey, seckey);
+    let mut x = [0u8; 32];
+    x.copy_from_slice(&point[..32]);
+    x
+}
+
+pub fn nip04_encrypt(seckey: &SecretKey, pubkey: &XOnlyPublicKey, text: &str) -> String {
+    let key = shared_x(seckey, pubkey);
+    let mut iv = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut iv);
+
+    let encrypted = Aes256CbcEnc::new(&key.into(), &iv.into()).encrypt_padded_vec_mut::<Pkcs7>(text.as_bytes());
+    format!("{}?iv={}", BASE64.encode(encrypted), BASE64.encode(iv))
+}
+
+// The bot only sends DMs so far, reading them is for the tests
+#[cfg(test)]
+pub fn nip04_decrypt(seckey: &SecretKey, pubkey: &XOnlyPublicKey, content: &str) -> error::Result<String> {
+    let invalid = || Error::Parse("Invalid NIP-04 message".to_string());
+    let (encrypted, iv) = content.split_once("?iv=").ok_or_else(invalid)?;
+    let encrypted = BASE64.decode(encrypted).map_err(|_| invalid())?;
+    let iv: [u8; 16] = BASE64.decode(iv).map_err(|_| invalid())?.try_into().map_err(|_| invalid())?;
+
+    let key = shared_x(seckey, pubkey);
+    let decrypted = Aes256CbcDec::new(&key.into(), &iv.into())
+        .decrypt_padded_vec_mut::<Pkcs7>(&encrypted)
+        .map_err(|_| invalid())?;
+    String::from_utf8(decrypted).map_err(|_| invalid())
+}
+
+// NIP-44 v2 key shared by the two sides of a conversation
+pub fn conversation_key(seckey: &SecretKey, pubkey: &XOnlyPublicKey) -> [u8; 32] {
+    let (key, _) = hkdf::Hkdf::<sha2::Sha256>::extract(Some(b"nip44-v2"), &shared_x(seckey, pubkey));
+    key.into()
+}
+
+// ChaCha20 key and nonce and the HMAC key of a message with `nonce`
+fn message_keys(conversation_key: &[u8; 32], nonce: &[u8; 32]) -> ([u8; 32], [u8; 12], [u8; 32]) {
+    let hkdf = hkdf::Hkdf::<sha2::Sha256>::from_prk(conversation_key).expect("Conversation keys have the length of a PRK");
+    let mut keys = [0u8; 76];
+    hkdf.expand(nonce, &mut keys).expect("76 bytes is a valid HKDF length");
+
+    let (mut chacha_key, mut chacha_nonce, mut hmac_key) = ([0u8; 32], [0u8; 12], [0u8; 32]);
+    chacha_key.copy_from_slice(&keys[..32]);
+    chacha_nonce.copy_from_slice(&keys[32..44]);
+    hmac_key.copy_from_slice(&keys[44..]);
+    (chacha_key, chacha_nonce, hmac_key)
+}
+
+// Messages are padded to hide their exact length
+fn padded_len(len: usize) -> usize {
+    if len <= 32 {
+        return 32;
+    }
+    let next_power = 1 << (usize::BITS - (len - 1).leading_zeros());
+    let chunk = if next_power <= 256 { 32 } else { next_power / 8 };
+    chunk * ((len - 1) / chunk + 1)
+}
+
+fn nip44_mac(hmac_key: &[u8; 32], nonce: &[u8], ciphertext: &[u8]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(hmac_key).expect("HMAC takes keys of any length");
+    mac.update(nonce);
+    mac.update(ciphertext);
+    mac
+}
+
+pub fn nip44_encrypt(conversation_key: &[u8; 32], text: &str) -> error::Result<String> {
+    let mut nonce = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    nip44_encrypt_with_nonce(conversation_key, text, &nonce)
+}
+
+fn nip44_encrypt_with_nonce(conversation_key: &[u8; 32], text: &str, nonce: &[u8; 32]) -> error::Result<String> {
+    let len = text.len();
+    if len == 0 || len > 65535 {
+        return Err(Error::Parse(format!("NIP-44 messages have 1 to 65535 bytes, not {}", len)));
+    }
+
+    let (chacha_key, chacha_nonce, hmac_key) = message_keys(conversation_key, nonce);
+    let mut ciphertext = Vec::with_capacity(2 + padded_len(len));
+    ciphertext.extend_from_slice(&(len as u16).to_be_bytes());
+    ciphertext.extend_from_slice(text.as_bytes());
+    ciphertext.resize(2 + padded_len(len), 0);
+    chacha20::ChaCha20::new(&chacha_key.into(), &chacha_nonce.into()).apply_keystream(&mut ciphertext);
+
+    let mac = nip44_mac(&hmac_key, nonce, &ciphertext).finalize().into_bytes();
+    let mut payload = vec![2u8];
+    payload.extend_from_slice(nonce);
+    payload.extend_from_slice(&ciphertext);
+    payload.extend_from_slice(&mac);
+    Ok(BASE64.encode(payload))
+}
+
+pub fn nip44_decrypt(conversation_key: &[u8; 32], payload: &str) -> error::Result<String> {
+    let invalid = |reason: &str| Error::Parse(format!("Invalid NIP-44 message: {}", reason));
+    let payload = BASE64.decode(payload).map_err(|_| invalid("not base64"))?;
+    if payload.len() < 99 || payload.len() > 65603 {
+        return Err(invalid("wrong length"));
+    }
+    if payload[0] != 2 {
+        return Err(invalid("unknown version"));
+    }
+
+    let (nonce, rest) = payload[1..].split_at(32);
+    let (ciphertext, mac) = rest.split_at(rest.len() - 32);
+    let mut nonce_bytes = [0u8; 32];
+    nonce_bytes.copy_from_slice(nonce);
+    let (chacha_key, chacha_nonce, hmac_key) = message_keys(conversation_key, &nonce_bytes);
+    nip44_mac(&hmac_key, nonce, ciphertext).verify_slice(mac).map_err(|_| invalid("wrong MAC"))?;
+
+    let mut padded = ciphertext.to_vec();
+    chacha20::ChaCha20::new(&chacha_key.into(), &chacha_nonce.into()).apply_keystream(&mut padded);
+    let len = u16::from_be_bytes([padded[0], padded[1]]) as usize;
+    if len == 0 || padded.len() != 2 + padded_len(len) {
+        return Err(invalid("wrong padding"));
+    }
+    String::from_utf8(padded[2..2 + len].to_vec()).map_err(|_| invalid("not UTF-8"))
+}
+
+// Gift wraps are dated up to two days back so their time doesn't give the message away
+fn random_past(now: u64) -> u64 {
+    now - rand::thread_rng().gen_range(0..2 * 24 * 60 * 60)
+}
+
+// NIP-17 private message: a kind 14 event (unsigned, the "rumor"), sealed into a kind 13 event
+// signed by the sender and wrapped into a kind 1059 event signed by a random key
+pub fn gift_wrap(keypair: &KeyPair, recipient: &XOnlyPublicKey, text: &str) -> error::Result<nostr_bot::Event> {
+    let now = utils::unix_timestamp();
+    let tags = vec![vec!["p".to_string(), recipient.to_string()]];
+
+    let (sender, _) = keypair.x_only_public_key();
+    let serialized = serde_json::json!([0, sender.to_string(), now, 14, tags, text]).to_string();
+    let rumor = serde_json::json!({
+        "id": sha256::Hash::hash(serialized.as_bytes()).to_string(),
+        "pubkey": sender.to_string(),
+        "created_at": now,
+        "kind": 14,
+        "tags": tags,
+        "content": text,
+    });
+
+    let sealed = nip44_encrypt(&conversation_key(&keypair.secret_key(), recipient), &rumor.to_string())?;
+    let seal = nostr_bot::Event::new(keypair, random_past(now), 13, vec![], sealed);
+
+    let wrapper = utils::get_random_keypair();
+    let seal = serde_json::to_string(&seal).map_err(|e| Error::Parse(e.to_string()))?;
+    let wrapped = nip44_encrypt(&conversation_key(&wrapper.secret_key(), recipient), &seal)?;
+    Ok(nostr_bot::Event::new(&wrapper, random_past(now), 1059, tags, wrapped))
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,91 @@
+use super::*;
+use serde_json::Value;
+use std::str::FromStr;
+
+fn keypair(secret: &str) -> KeyPair {
+    KeyPair::from_seckey_str(&secp256k1::Secp256k1::new(), secret).unwrap()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// First vector of nip44.vectors.json
+#[test]
+fn nip44_matches_the_reference_vector() {
+    let sender = keypair("0000000000000000000000000000000000000000000000000000000000000001");
+    let recipient = keypair("0000000000000000000000000000000000000000000000000000000000000002");
+    let key = conversation_key(&sender.secret_key(), &recipient.x_only_public_key().0);
+    assert_eq!(hex(&key), "c41c775356fd92eadc63ff5a0dc1da211b268cbea22316767095b2871ea1412d");
+
+    let mut nonce = [0u8; 32];
+    nonce[31] = 1;
+    let payload = nip44_encrypt_with_nonce(&key, "a", &nonce).unwrap();
+    assert_eq!(
+        payload,
+        "AgAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAABee0G5VSK0/9YypIObAtDKfYEAjD35uVkHyB0F4DwrcNaCXlCWZKaArsGrY6M9wnuTMxWfp1RTN9Xga8no+kF5Vsb"
+    );
+
+    // Both sides get the same key
+    assert_eq!(conversation_key(&recipient.secret_key(), &sender.x_only_public_key().0), key);
+    assert_eq!(nip44_decrypt(&key, &payload).unwrap(), "a");
+}
+
+#[test]
+fn nip44_pads_and_rejects_tampering() {
+    assert_eq!(padded_len(1), 32);
+    assert_eq!(padded_len(33), 64);
+    assert_eq!(padded_len(257), 320);
+    assert_eq!(padded_len(65535), 65536);
+
+    let key = [7u8; 32];
+    let text = "Nitter is failing: ".repeat(40);
+    let payload = nip44_encrypt(&key, &text).unwrap();
+    assert_eq!(nip44_decrypt(&key, &payload).unwrap(), text);
+
+    let mut bytes = BASE64.decode(&payload).unwrap();
+    bytes[40] ^= 1;
+    assert!(nip44_decrypt(&key, &BASE64.encode(bytes)).is_err());
+    assert!(nip44_decrypt(&[8u8; 32], &payload).is_err());
+    assert!(nip44_encrypt(&key, "").is_err());
+}
+
+#[test]
+fn nip04_round_trip() {
+    let sender = utils::get_random_keypair();
+    let recipient = utils::get_random_keypair();
+    let content = nip04_encrypt(&sender.secret_key(), &recipient.x_only_public_key().0, "Discord is failing");
+    assert!(content.contains("?iv="));
+    assert_eq!(
+        nip04_decrypt(&recipient.secret_key(), &sender.x_only_public_key().0, &content).unwrap(),
+        "Discord is failing"
+    );
+}
+
+#[test]
+fn gift_wraps_hide_the_sender() {
+    let sender = utils::get_random_keypair();
+    let recipient = utils::get_random_keypair();
+    let (recipient_pubkey, _) = recipient.x_only_public_key();
+    let wrap = gift_wrap(&sender, &recipient_pubkey, "Relay \"a\" is back").unwrap();
+    let wrap: Value = serde_json::from_str(&serde_json::to_string(&wrap).unwrap()).unwrap();
+
+    assert_eq!(wrap["kind"], 1059);
+    assert_ne!(wrap["pubkey"], sender.x_only_public_key().0.to_string());
+    assert_eq!(wrap["tags"][0][1], recipient_pubkey.to_string());
+    assert!(crate::testing::verify_event(&wrap));
+
+    let wrapper = XOnlyPublicKey::from_str(wrap["pubkey"].as_str().unwrap()).unwrap();
+    let seal = nip44_decrypt(&conversation_key(&recipient.secret_key(), &wrapper), wrap["content"].as_str().unwrap()).unwrap();
+    let seal: Value = serde_json::from_str(&seal).unwrap();
+    assert_eq!(seal["kind"], 13);
+    assert_eq!(seal["pubkey"], sender.x_only_public_key().0.to_string());
+    assert!(crate::testing::verify_event(&seal));
+
+    let sender_pubkey = XOnlyPublicKey::from_str(seal["pubkey"].as_str().unwrap()).unwrap();
+    let rumor = nip44_decrypt(&conversation_key(&recipient.secret_key(), &sender_pubkey), seal["content"].as_str().unwrap()).unwrap();
+    let rumor: Value = serde_json::from_str(&rumor).unwrap();
+    assert_eq!(rumor["kind"], 14);
+    assert_eq!(rumor["content"], "Relay \"a\" is back");
+    assert_eq!(rumor["pubkey"], seal["pubkey"]);
+}
@@ -1,8 +1,18 @@
 use log::{error, debug, info};
 use std::fmt::Write;
 use rand::Rng;
+use crate::alerts::{self, Component, ConnectionMessage};
+use crate::dm::{self, DmKind};
+use crate::error::{self, Error};
 use crate::simpledb;
 use crate::fetch;
+use crate::media;
+use crate::nitter;
+use crate::outbox;
+use crate::relays;
+use crate::schedule;
+use crate::scheduler;
+use crate::signer::{self, Signer};
 use crate::utils;
 use serenity::model::id::ChannelId;
 use tokio::sync::Mutex;
@@ -13,28 +23,19 @@ use std::io::Write as IoWrite;
 use std::collections::HashMap;
 use serde::{Serialize, Deserialize};
 
-type Receiver = tokio::sync::mpsc::Receiver<ConnectionMessage>;
-type ErrorSender = tokio::sync::mpsc::Sender<ConnectionMessage>;
-
-#[derive(PartialEq, Debug)]
-enum ConnectionStatus {
-    Success,
-    Failed,
-}
-
-#[derive(Debug)]
-pub struct ConnectionMessage {
-    status: ConnectionStatus,
-    timestamp: std::time::SystemTime,
-}
-
 pub struct DostrState {
     pub config: utils::Config,
     pub db: simpledb::Database,
-    pub sender: nostr_bot::Sender,
-    pub error_sender: tokio::sync::mpsc::Sender<ConnectionMessage>,
+    pub error_sender: alerts::AlertSender,
     pub started_timestamp: u64,
     pub discord_context: std::sync::Arc<tokio::sync::Mutex<Option<serenity::prelude::Context>>>,
+    pub media_cache: media::MediaCache,
+    pub link_cache: fetch::LinkCache,
+    pub nitter: nitter::Nitter,
+    pub feeds: fetch::Feeds,
+    pub scheduler: scheduler::Scheduler,
+    pub outbox: outbox::Outbox,
+    pub relays: relays::RelayPool,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -44,86 +45,93 @@ struct NameDirectory {
 
 pub type State = nostr_bot::State<DostrState>;
 
-pub async fn error_listener(
-    mut rx: Receiver,
-    sender: nostr_bot::Sender,
-    keypair: secp256k1::KeyPair,
-) {
-    // If the message of the same kind as last one was received in less than this, discard it to
-    // prevent spamming
-    let discard_period = std::time::Duration::from_secs(360000);
+pub async fn channel_relays(
+    event: nostr_bot::Event,
+    state: State,
+    bot: nostr_bot::BotInfo,
+) -> nostr_bot::EventNonSigned {
+    let text = relays_report(&state, bot.connected_relays().await).await;
+    nostr_bot::get_reply(event, text)
+}
 
-    let mut last_accepted_message = ConnectionMessage {
-        status: ConnectionStatus::Success,
-        timestamp: std::time::SystemTime::now() - discard_period,
-    };
+// `connected` are the relays the bot reads commands from, the rest is what the outbox saw
+async fn relays_report(state: &State, connected: Vec<String>) -> String {
+    let state_lock = state.lock().await;
+    let (pool, outbox) = (state_lock.relays.clone(), state_lock.outbox.clone());
+    drop(state_lock);
 
-    while let Some(message) = rx.recv().await {
-        let mut message_to_send = std::option::Option::<String>::None;
-        
-        if message.status != last_accepted_message.status {
-            match message.status {
-                ConnectionStatus::Success => {
-                 //   message_to_send = Some("Connection to Discord reestablished! :)".to_string());
-                }
-                ConnectionStatus::Failed => {
-                 //   message_to_send = Some("I can't connect to Discord right now :(.".to_string());
-                }
-            }
+    let mut text = "Right now I'm connected to these relays:\n".to_string();
+    for relay in connected {
+        writeln!(text, "{}", relay).unwrap();
+    }
 
-            last_accepted_message = message;
-            
-        } else {
-            let duration_since_last_accepted = message
-                .timestamp
-                .duration_since(last_accepted_message.timestamp)
-                .unwrap();
-
-            debug!(
-                "Since last accepted message: {:?}, discard period: {:?}",
-                duration_since_last_accepted, discard_period
-            );
-
-            if duration_since_last_accepted >= discard_period {
-                match message.status {
-                    ConnectionStatus::Success => {}
-                    ConnectionStatus::Failed => {
-                        message_to_send =
-                            Some("I'm still unable to connect to Discord :(".to_string());
-                    }
-                }
-                last_accepted_message = message;
-            }
+    let defaults = pool.defaults();
+    let (default_statuses, other_statuses): (Vec<_>, Vec<_>) =
+        pool.statuses().into_iter().partition(|(relay, _)| defaults.contains(relay));
+    writeln!(text, "\nI publish to:").unwrap();
+    for (relay, status) in default_statuses {
+        writeln!(text, "{}: {}", relay, status).unwrap();
+    }
+    if !other_statuses.is_empty() {
+        writeln!(text, "\nFeeds with relays of their own publish to:").unwrap();
+        for (relay, status) in other_statuses {
+            writeln!(text, "{}: {}", relay, status).unwrap();
         }
-     //   println!("Message to send: {:?}", message_to_send);
-
-        if let Some(message_to_send) = message_to_send {
-            let event = nostr_bot::EventNonSigned {
-                created_at: utils::unix_timestamp(),
-                kind: 1,
-                tags: vec![],
-                content: message_to_send,
-            }
-            .sign(&keypair);
+    }
 
-            sender.lock().await.send(event).await;
-        }
+    let pending = outbox.pending();
+    if pending > 0 {
+        write!(text, "\n{} events are waiting to be accepted by all relays.", pending).unwrap();
     }
+    text
 }
 
-pub async fn channel_relays(
-    event: nostr_bot::Event,
-    _state: State,
-    bot: nostr_bot::BotInfo,
-) -> nostr_bot::EventNonSigned {
-    let mut text = "Right now I'm connected to these relays:\n".to_string();
+// Commands changing what and where the bot publishes are for OPERATOR_PUBKEYS only
+fn is_operator(config: &utils::Config, event: &nostr_bot::Event) -> bool {
+    utils::parse_pubkey(&event.pubkey).is_some_and(|pubkey| config.operator_pubkeys.contains(&pubkey))
+}
 
-    let relays = bot.connected_relays().await;
-    for relay in relays {
-        writeln!(text, "{}", relay).unwrap();
+// !relay add <url> and !relay remove <url> change the default relays
+pub async fn channel_relay(event: nostr_bot::Event, state: State) -> nostr_bot::EventNonSigned {
+    if !is_operator(&state.lock().await.config, &event) {
+        debug!("!relay from {}, who is not an operator.", event.pubkey);
+        return nostr_bot::get_reply(event, "Error: Only operators can change my relays.".to_string());
+    }
+    let content = event.content.clone();
+    let words = content.split_whitespace().collect::<Vec<_>>();
+    let (action, relay) = match words[..] {
+        [_, action, relay] if action == "add" || action == "remove" => (action, relay),
+        _ => {
+            debug!("Invalid !relay command >{}<.", event.content);
+            return nostr_bot::get_reply(event, "Error: Use !relay add <url> or !relay remove <url>.".to_string());
+        }
+    };
+    if !utils::is_relay_url(relay) {
+        return nostr_bot::get_reply(event, format!("Error: Invalid relay {}, use ws:// or wss:// URLs.", relay));
     }
 
-    nostr_bot::get_reply(event, text)
+    let pool = state.lock().await.relays.clone();
+    let changed = if action == "add" {
+        pool.add(relay)
+    } else if pool.defaults() == [relay] {
+        return nostr_bot::get_reply(event, format!("Error: {} is my only relay, add another one first.", relay));
+    } else {
+        pool.remove(relay)
+    };
+
+    let reply = match changed {
+        Ok(true) => {
+            info!("Default relays changed by {}: {} {}", event.pubkey, action, relay);
+            format!("Hi, I now publish to:\n{}", pool.defaults().join("\n"))
+        }
+        Ok(false) if action == "add" => format!("Hi, I already publish to {}.", relay),
+        Ok(false) => format!("Hi, I don't publish to {}.", relay),
+        Err(e) => {
+            error!("Failed to save the relays: {}", e);
+            format!("Error: I couldn't save the relays ({}).", e)
+        }
+    };
+    nostr_bot::get_reply(event, reply)
 }
 
 pub async fn channel_list(event: nostr_bot::Event, state: State) -> nostr_bot::EventNonSigned {
@@ -191,45 +199,33 @@ pub async fn channel_random(event: nostr_bot::Event, state: State) -> nostr_bot:
 
 
 pub async fn channel_add(event: nostr_bot::Event, state: State) -> nostr_bot::EventNonSigned {
-    
-let config = utils::parse_config();
+let config = state.lock().await.config.clone();
 let words = event.content.split_whitespace().collect::<Vec<_>>();
 if words.len() < 2 {
     debug!("Invalid !add command >{}< (missing account name).", event.content);
     return nostr_bot::get_reply(event, "Error: Missing account name.".to_string());
 }
 
-let input = words[1].trim();
-let (channel_id, channel_name) = if input.starts_with('@') {
-    // This is a Twitter handle.
-    let channel_id = format!("https://{}/{}/rss", &config.nitter_instance, &input[1..]);  // removing '@'
-    let channel_name = input[1..].to_string();  // removing '@', no reference
-    (channel_id, channel_name)
-} else {
-    // This is a Discord channel ID.
-    let data: Vec<String> = input
-        .splitn(2, ':')
-        .map(String::from)
-        .collect();
-
-    let channel_id = data[0].clone();
-    let channel_name = if data.len() > 1 { 
-        data[1..].join(":").trim().to_string() 
-    } else { 
-        channel_id.clone()
-    }; // if name is not provided, use the channel id as the name
-    (channel_id, channel_name)
-};
+let (feed_id, channel_name) = parse_feed(words[1].trim(), words.get(2).copied());
 
     let db = state.lock().await.db.clone();
-    let config = state.lock().await.config.clone();
 
-    if db.lock().unwrap().contains_key(&channel_id) {
-        let keypair = simpledb::get_channel_keypair(&channel_id, db);
+    if db.lock().unwrap().contains_key(&feed_id) {
+        if let Some(owner) = db.lock().unwrap().get_options(&feed_id).handed_over_to {
+            let name = words[1].to_string();
+            return handed_over_reply(event, &name, &owner);
+        }
+        let keypair = match simpledb::get_channel_keypair(&feed_id, db) {
+            Ok(keypair) => keypair,
+            Err(e) => {
+                error!("Failed to read the key of {}: {}", feed_id, e);
+                return nostr_bot::get_reply(event, format!("Error: I couldn't read the key of {}.", feed_id));
+            }
+        };
         let (pubkey, _parity) = keypair.x_only_public_key();
         debug!(
-            "Channel ID {} already added before. Sending existing pubkey {}",
-            channel_id, pubkey
+            "Feed {} already added before. Sending existing pubkey {}",
+            feed_id, pubkey
         );
         return get_channel_response(event, &pubkey.to_string());
     }
@@ -239,115 +235,432 @@ let (channel_id, channel_name) = if input.starts_with('@') {
             format!("Hi, sorry, couldn't add new channel. I'm already running at my max capacity ({} channels).", config.max_follows));
     }
 
+    let keypair = utils::new_feed_keypair(&config, &feed_id);
+    let seckey = keypair.display_secret().to_string();
+    let encryption = db.lock().unwrap().encryption();
+    let inserted = match simpledb::stored_key(encryption, seckey.clone()).await {
+        Ok(stored) => db.lock().unwrap().insert_stored(feed_id.clone(), seckey, stored, channel_name.clone()),
+        Err(e) => Err(e),
+    };
+    if let Err(e) = inserted {
+        error!("Failed to add {} to the database: {}", feed_id, e);
+        return nostr_bot::get_reply(event, format!("Error: I couldn't save {} ({}).", feed_id, e));
+    }
+
     let state_lock = state.lock().await;
     let discord_context_option = state_lock.discord_context.lock().await.clone();
-    let keypair = utils::get_random_keypair();
-
-    db.lock()
-        .unwrap()
-        .insert( channel_id.clone(), keypair.display_secret().to_string(), channel_name.clone(),)
-        .unwrap();
-
     let (xonly_pubkey, _) = keypair.x_only_public_key();
 
     info!(
-        "Starting worker for channel ID {}, pubkey {}",
-        channel_id, xonly_pubkey
+        "Starting worker for feed {}, pubkey {}",
+        feed_id, xonly_pubkey
     );
 
     // Convert the xonly_pubkey to a string for use in the JSON file.
     let public_key_string = format!("{}", xonly_pubkey); 
 
     // Update the JSON file
-    let result = update_json_file(channel_name.clone(), public_key_string);
+    let result = update_json_file(&config.nostr_json_path(), channel_name.clone(), public_key_string);
     if let Err(e) = result {
         error!("Failed to update JSON file: {:?}", e);
         // you could return an error here or decide how to handle it
     }
 
-    let sender = state_lock.sender.clone();
+    let outbox = state_lock.outbox.clone();
     let tx = state_lock.error_sender.clone();
-    let refresh_interval_secs = config.refresh_interval_secs;
     let state_clone = state.clone();
 
-    // Check if channel_id is a number (Discord) or a URL (RSS feed)
-    match channel_id.parse::<u64>() {
-        Ok(channel_id_num) => {
+    match feed_id {
+        simpledb::FeedId::Discord(channel_id) => {
             // Handle as Discord channel
-            let channel_id_num = ChannelId(channel_id_num);
+            let channel_id_num = ChannelId(channel_id);
 
             if let Some(discord_context) = discord_context_option {
-                let channel_type = fetch::ChannelType::Discord(channel_id_num);
-            
                 if fetch::channel_exists(&channel_id_num, Arc::new(discord_context)).await {
                     tokio::spawn(async move {
                         update_channel(
-                            channel_type,
+                            feed_id,
                             &keypair,
-                            sender,
+                            outbox,
                             tx,
-                            refresh_interval_secs,
                             state_clone,
                             channel_name,
                         )
                         .await;
                     });
             
-                    return get_channel_response(event, &xonly_pubkey.to_string());
+                    get_channel_response(event, &xonly_pubkey.to_string())
                 } else {
-                    return nostr_bot::get_reply(
+                    nostr_bot::get_reply(
                         event,
                         format!("Hi, I wasn't able to find channel ID {} on Discord.", channel_id),
-                    );
+                    )
                 }
             } else {
-                return nostr_bot::get_reply(
+                nostr_bot::get_reply(
                     event,
-                    format!("Hi, I can't add Discord channels at this time because I don't have access to Discord."),
-                );
+                    "Hi, I can't add Discord channels at this time because I don't have access to Discord.".to_string(),
+                )
             }
         }
-        Err(_) => {
-            // Handle as RSS feed
-            let channel_type = fetch::ChannelType::RSS(channel_id.clone());
-
+        _ => {
+            // Handle as Twitter or RSS feed
             tokio::spawn(async move {
                 update_channel(
-                    channel_type,
+                    feed_id,
                     &keypair,
-                    sender,
+                    outbox,
                     tx,
-                    refresh_interval_secs,
                     state_clone,
                     channel_name,
                 )
                 .await;
             });
         
-            return get_channel_response(event, &xonly_pubkey.to_string());
+            get_channel_response(event, &xonly_pubkey.to_string())
+        }
+    }
+}
+
+// Feed given to a command: a RSS URL, a Discord channel ID or a Twitter handle. Returns the feed
+// and the name used for NIP05.
+fn parse_feed(input: &str, name: Option<&str>) -> (simpledb::FeedId, String) {
+    if input.starts_with("http://") || input.starts_with("https://") {
+        // This is a RSS feed, the optional name defaults to the host of the URL.
+        let channel_name = match name {
+            Some(name) => name.to_string(),
+            None => url::Url::parse(input)
+                .ok()
+                .and_then(|url| url.host_str().map(String::from))
+                .unwrap_or_else(|| "rss".to_string()),
+        };
+        (simpledb::FeedId::Rss(input.to_string()), channel_name)
+    } else if let Ok(channel_id) = input.split(':').next().unwrap_or_default().parse::<u64>() {
+        // This is a Discord channel ID, optionally followed by ':' and the Twitter account name.
+        let channel_name = match input.split_once(':') {
+            Some((_, name)) if !name.trim().is_empty() => name.trim().to_string(),
+            _ => channel_id.to_string(),
+        }; // if name is not provided, use the channel id as the name
+        (simpledb::FeedId::Discord(channel_id), channel_name)
+    } else {
+        // This is a Twitter handle, '@' is optional.
+        let handle = input.strip_prefix('@').unwrap_or(input);
+        (simpledb::FeedId::twitter(handle), handle.to_string())
+    }
+}
+
+pub async fn channel_interval(event: nostr_bot::Event, state: State) -> nostr_bot::EventNonSigned {
+    let content = event.content.clone();
+    let words = content.split_whitespace().collect::<Vec<_>>();
+    if words.len() < 2 {
+        debug!("Invalid !interval command >{}< (missing account name).", event.content);
+        return nostr_bot::get_reply(event, "Error: Missing account name.".to_string());
+    }
+
+    let (feed_id, _) = parse_feed(words[1].trim(), None);
+    let state_lock = state.lock().await;
+    let (db, config) = (state_lock.db.clone(), state_lock.config.clone());
+    drop(state_lock);
+    if !db.lock().unwrap().contains_key(&feed_id) {
+        return nostr_bot::get_reply(event, format!("Hi, I'm not following {}.", words[1]));
+    }
+    if let Some(owner) = db.lock().unwrap().get_options(&feed_id).handed_over_to {
+        return handed_over_reply(event, words[1], &owner);
+    }
+    if words.len() > 2 && !is_operator(&config, &event) {
+        debug!("!interval change from {}, who is not an operator.", event.pubkey);
+        return nostr_bot::get_reply(event, "Error: Only operators can change the interval of a feed.".to_string());
+    }
+
+    let mut options = db.lock().unwrap().get_options(&feed_id);
+    let reply = match words.get(2) {
+        None => match options.interval_secs {
+            Some(secs) => format!("Hi, {} is checked every {} s.", words[1], secs),
+            None => format!("Hi, {} is checked at an adaptive interval.", words[1]),
+        },
+        Some(&"auto") => {
+            options.interval_secs = None;
+            format!("Hi, {} is now checked at an adaptive interval.", words[1])
+        }
+        Some(value) => match value.parse::<u64>() {
+            Ok(secs) if secs > 0 => {
+                options.interval_secs = Some(secs);
+                format!("Hi, {} is now checked every {} s.", words[1], secs)
+            }
+            _ => return nostr_bot::get_reply(event, format!("Error: Invalid interval {}, use seconds or auto.", value)),
+        },
+    };
+
+    if words.len() > 2 {
+        if let Err(e) = db.lock().unwrap().set_options(&feed_id, options) {
+            error!("Failed to set the interval of {}: {}", feed_id, e);
+            return nostr_bot::get_reply(event, format!("Error: I couldn't save the interval ({}).", e));
         }
     }
+
+    nostr_bot::get_reply(event, reply)
+}
+
+// Relays a feed publishes its notes to, its own set or the default relays
+fn feed_relays(defaults: &[String], options: &simpledb::FeedOptions) -> Vec<String> {
+    if options.relays.is_empty() {
+        defaults.to_vec()
+    } else {
+        options.relays.clone()
+    }
+}
+
+// NIP-65 relay list, tells clients where to find the notes of a mirror
+fn relay_list(relays: &[String]) -> nostr_bot::EventNonSigned {
+    nostr_bot::EventNonSigned {
+        created_at: utils::unix_timestamp(),
+        kind: 10002,
+        tags: relays.iter().map(|relay| vec!["r".to_string(), relay.clone()]).collect(),
+        content: String::new(),
+    }
 }
 
-fn update_json_file(channel_name: String, public_key: String) -> std::io::Result<()> {
+// Profiles and relay lists of mirrors go to the default relays as well, so clients can find the
+// feed's own relays from there
+fn discovery_relays(defaults: &[String], relays: &[String]) -> Vec<String> {
+    let mut everywhere = defaults.to_vec();
+    everywhere.extend(relays.iter().filter(|relay| !defaults.contains(relay)).cloned());
+    everywhere
+}
+
+async fn publish_profile(outbox: &outbox::Outbox, defaults: &[String], signer: &Signer, profile: nostr_bot::EventNonSigned, relays: &[String]) -> error::Result<()> {
+    let everywhere = discovery_relays(defaults, relays);
+    outbox.send_to(&signer.sign(profile).await?, &everywhere);
+    outbox.send_to(&signer.sign(relay_list(relays)).await?, &everywhere);
+    Ok(())
+}
+
+// Signer of `feed`, its key unless it has a bunker
+// NIP-42 AUTH for the events of a feed goes through its signer, under the key it signs with
+async fn add_feed_signer(pool: &relays::RelayPool, feed: &simpledb::FeedId, signer: &Signer) {
+    match signer.public_key().await {
+        Ok(pubkey) => pool.add_signer(&pubkey, signer.clone()),
+        Err(e) => error!("Unable to get the key of the signer of {}: {}", feed, e),
+    }
+}
+
+fn feed_signer(feed: &simpledb::FeedId, keypair: &secp256k1::KeyPair, options: &simpledb::FeedOptions, config: &utils::Config) -> Signer {
+    Signer::for_feed(keypair, options, config).unwrap_or_else(|e| {
+        error!("Unable to use the bunker of {}, signing with its key: {}", feed, e);
+        Signer::Local(*keypair)
+    })
+}
+
+pub async fn channel_feed_relays(event: nostr_bot::Event, state: State) -> nostr_bot::EventNonSigned {
+    let content = event.content.clone();
+    let words = content.split_whitespace().collect::<Vec<_>>();
+    if words.len() < 2 {
+        debug!("Invalid !feedrelays command >{}< (missing account name).", event.content);
+        return nostr_bot::get_reply(event, "Error: Missing account name.".to_string());
+    }
+
+    let (feed_id, _) = parse_feed(words[1].trim(), None);
+    let state_lock = state.lock().await;
+    let (db, pool, outbox, config) = (state_lock.db.clone(), state_lock.relays.clone(), state_lock.outbox.clone(), state_lock.config.clone());
+    drop(state_lock);
+    if !db.lock().unwrap().contains_key(&feed_id) {
+        return nostr_bot::get_reply(event, format!("Hi, I'm not following {}.", words[1]));
+    }
+    if let Some(owner) = db.lock().unwrap().get_options(&feed_id).handed_over_to {
+        return handed_over_reply(event, words[1], &owner);
+    }
+    if words.len() > 2 && !is_operator(&config, &event) {
+        debug!("!feedrelays change from {}, who is not an operator.", event.pubkey);
+        return nostr_bot::get_reply(event, "Error: Only operators can change the relays of a feed.".to_string());
+    }
+    let defaults = pool.defaults();
+
+    let mut options = db.lock().unwrap().get_options(&feed_id);
+    match words.get(2) {
+        None => {}
+        Some(&"default") => options.relays = vec![],
+        Some(_) => {
+            let relays = words[2..].iter().map(|relay| relay.to_string()).collect::<Vec<_>>();
+            if let Some(invalid) = relays.iter().find(|relay| !utils::is_relay_url(relay)) {
+                return nostr_bot::get_reply(event, format!("Error: Invalid relay {}, use ws:// or wss:// URLs.", invalid));
+            }
+            options.relays = relays;
+        }
+    }
+
+    let relays = feed_relays(&defaults, &options);
+    if words.len() > 2 {
+        if let Err(e) = db.lock().unwrap().set_options(&feed_id, options.clone()) {
+            error!("Failed to set the relays of {}: {}", feed_id, e);
+            return nostr_bot::get_reply(event, format!("Error: I couldn't save the relays ({}).", e));
+        }
+        let signed = match simpledb::get_channel_keypair(&feed_id, db) {
+            Ok(keypair) => feed_signer(&feed_id, &keypair, &options, &config).sign(relay_list(&relays)).await,
+            Err(e) => Err(e),
+        };
+        match signed {
+            Ok(relay_list) => outbox.send_to(&relay_list, &discovery_relays(&defaults, &relays)),
+            Err(e) => error!("Failed to publish the relay list of {}: {}", feed_id, e),
+        }
+    }
+
+    let reply = if options.relays.is_empty() {
+        format!("Hi, {} publishes to the default relays:\n{}", words[1], relays.join("\n"))
+    } else {
+        format!("Hi, {} publishes to:\n{}", words[1], relays.join("\n"))
+    };
+    nostr_bot::get_reply(event, reply)
+}
+
+pub async fn channel_feed_signer(event: nostr_bot::Event, state: State) -> nostr_bot::EventNonSigned {
+    let content = event.content.clone();
+    let words = content.split_whitespace().collect::<Vec<_>>();
+    if words.len() < 2 {
+        debug!("Invalid !feedsigner command >{}< (missing account name).", event.content);
+        return nostr_bot::get_reply(event, "Error: Missing account name.".to_string());
+    }
+
+    let (feed_id, _) = parse_feed(words[1].trim(), None);
+    let state_lock = state.lock().await;
+    let (db, config) = (state_lock.db.clone(), state_lock.config.clone());
+    drop(state_lock);
+    let keypair = match simpledb::get_channel_keypair(&feed_id, db.clone()) {
+        Ok(keypair) => keypair,
+        Err(_) => return nostr_bot::get_reply(event, format!("Hi, I'm not following {}.", words[1])),
+    };
+    if let Some(owner) = db.lock().unwrap().get_options(&feed_id).handed_over_to {
+        return handed_over_reply(event, words[1], &owner);
+    }
+    if words.len() > 2 && !is_operator(&config, &event) {
+        debug!("!feedsigner change from {}, who is not an operator.", event.pubkey);
+        return nostr_bot::get_reply(event, "Error: Only operators can change the signer of a feed.".to_string());
+    }
+    let client = utils::bunker_client_keypair(&nostr_bot::keypair_from_secret(&config.secret)).x_only_public_key().0;
+
+    let mut options = db.lock().unwrap().get_options(&feed_id);
+    let reply = match words.get(2) {
+        None => match options.bunker.as_deref().map(|uri| signer::Bunker::from_config(uri, &config)) {
+            Some(Ok(bunker)) => format!("Hi, {} is signed by a bunker on {}.", words[1], bunker.relay()),
+            Some(Err(e)) => format!("Hi, {} has an invalid bunker ({}), I sign with its key.", words[1], e),
+            None => format!("Hi, I sign {} with its key.", words[1]),
+        },
+        Some(&"local") => {
+            options.bunker = None;
+            format!("Hi, I now sign {} with its key.", words[1])
+        }
+        Some(uri) => {
+            let bunker = match signer::Bunker::from_config(uri, &config) {
+                Ok(bunker) => bunker,
+                Err(e) => return nostr_bot::get_reply(event, format!("Error: {}, use bunker://<key>?relay=wss://...", e)),
+            };
+            // A bunker signing with another key would hand the feed to a different account
+            let expected = keypair.x_only_public_key().0;
+            match bunker.public_key().await {
+                Ok(pubkey) if pubkey == expected => {}
+                Ok(pubkey) => {
+                    return nostr_bot::get_reply(event, format!("Error: The bunker signs as {}, not as {} ({}).", pubkey, words[1], expected));
+                }
+                Err(e) => {
+                    let reply = format!("Error: I couldn't use the bunker ({}). It has to authorize my client key {}.", e, client);
+                    return nostr_bot::get_reply(event, reply);
+                }
+            }
+            options.bunker = Some(uri.to_string());
+            format!("Hi, {} is now signed by the bunker on {}.", words[1], bunker.relay())
+        }
+    };
+
+    if words.len() > 2 {
+        if let Err(e) = db.lock().unwrap().set_options(&feed_id, options) {
+            error!("Failed to set the signer of {}: {}", feed_id, e);
+            return nostr_bot::get_reply(event, format!("Error: I couldn't save the signer ({}).", e));
+        }
+    }
+
+    nostr_bot::get_reply(event, reply)
+}
+
+// Reply to commands about a feed whose owner took it over with !claim
+fn handed_over_reply(event: nostr_bot::Event, name: &str, owner: &str) -> nostr_bot::EventNonSigned {
+    nostr_bot::get_reply(event, format!("Hi, {} was handed over to its owner {}, I don't post as it anymore.", name, owner))
+}
+
+// Sends the key of a feed to its owner and stops posting as it, operators only
+pub async fn channel_claim(event: nostr_bot::Event, state: State) -> nostr_bot::EventNonSigned {
+    let content = event.content.clone();
+    let words = content.split_whitespace().collect::<Vec<_>>();
+    let state_lock = state.lock().await;
+    let (db, outbox, config) = (state_lock.db.clone(), state_lock.outbox.clone(), state_lock.config.clone());
+    drop(state_lock);
+
+    if !is_operator(&config, &event) {
+        debug!("!claim from {}, who is not an operator.", event.pubkey);
+        return nostr_bot::get_reply(event, "Error: Only operators can hand feeds over.".to_string());
+    }
+    if words.len() < 3 {
+        debug!("Invalid !claim command >{}< (missing account name or key).", event.content);
+        return nostr_bot::get_reply(event, "Error: Use !claim <account> <npub of the owner>.".to_string());
+    }
+    let owner = match utils::parse_pubkey(words[2]) {
+        Some(owner) => owner,
+        None => return nostr_bot::get_reply(event, format!("Error: Invalid public key {}.", words[2])),
+    };
+
+    let (feed_id, _) = parse_feed(words[1].trim(), None);
+    let keypair = match simpledb::get_channel_keypair(&feed_id, db.clone()) {
+        Ok(keypair) => keypair,
+        Err(_) => return nostr_bot::get_reply(event, format!("Hi, I'm not following {}.", words[1])),
+    };
+    let mut options = db.lock().unwrap().get_options(&feed_id);
+    // Sending the key again to the same owner is fine, e.g. if the DM got lost
+    if let Some(previous) = options.handed_over_to.as_ref().filter(|previous| **previous != owner.to_string()) {
+        return nostr_bot::get_reply(event, format!("Error: {} was already handed over to {}.", words[1], previous));
+    }
+
+    let text = format!(
+        "Hi, here is the secret key of {} ({}), import it into your nostr client to post as this account:\n\n{}\n\nI don't post as it anymore. The key was kept on my server, move to a new key if you'd rather not trust that.",
+        words[1],
+        keypair.x_only_public_key().0,
+        utils::encode_nsec(&keypair.secret_key())
+    );
+    let bot_keypair = nostr_bot::keypair_from_secret(&config.secret);
+    match dm::direct_message(&bot_keypair, &owner, &text, DmKind::Nip17) {
+        Ok(dm) => outbox.send(&dm),
+        Err(e) => {
+            error!("Failed to encrypt the key of {} for {}: {}", feed_id, owner, e);
+            return nostr_bot::get_reply(event, format!("Error: I couldn't encrypt the key ({}).", e));
+        }
+    }
+
+    options.handed_over_to = Some(owner.to_string());
+    if let Err(e) = db.lock().unwrap().set_options(&feed_id, options) {
+        error!("Failed to mark {} as handed over: {}", feed_id, e);
+        return nostr_bot::get_reply(event, format!("Error: I sent the key but couldn't save the handover ({}).", e));
+    }
+    info!("Handed {} over to {}", feed_id, owner);
+
+    nostr_bot::get_reply(event, format!("Hi, I sent the key of {} to {} as an encrypted DM and stopped posting as it.", words[1], owner))
+}
+
+fn update_json_file(path: &std::path::Path, channel_name: String, public_key: String) -> error::Result<()> {
     // Load the JSON file
-    let mut file = File::open("web/.well-known/nostr.json")?;
+    let mut file = File::open(path)?;
     let mut contents = String::new();
     file.read_to_string(&mut contents)?;
 
     // Parse the JSON data
     let mut directory: NameDirectory = serde_json::from_str(&contents)
-        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        .map_err(|e| Error::Parse(format!("Invalid {}: {}", path.display(), e)))?;
 
     // Add new channel_name and public key to the directory
     directory.names.insert(channel_name, public_key);
 
     // Convert the updated directory back to JSON
     let updated_json = serde_json::to_string_pretty(&directory)
-        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        .map_err(|e| Error::Storage(e.to_string()))?;
 
     // Write the updated JSON back to the file
-    let mut file = File::create("web/.well-known/nostr.json")?;
+    let mut file = File::create(path)?;
     file.write_all(updated_json.as_bytes())?;
 
     Ok(())
@@ -385,40 +698,23 @@ pub async fn start_existing(state: State) {
     let state_lock = state.lock().await;
     let follows = state_lock.db.lock().unwrap().get_follows();
 
-    for (channel_id, (keypair, channel_name)) in follows {
+    for (feed_id, (keypair, channel_name)) in follows {
 
-        let refresh = state_lock.config.refresh_interval_secs;
-        let sender_clone = state_lock.sender.clone();
+        let outbox_clone = state_lock.outbox.clone();
         let error_sender_clone = state_lock.error_sender.clone();
         let state_clone = state.clone();
 
-        if let Ok(channel_id_num) = channel_id.parse::<u64>() {
-            tokio::spawn(async move {
-                update_channel(
-                    fetch::ChannelType::Discord(ChannelId(channel_id_num)),
-                    &keypair,
-                    sender_clone,
-                    error_sender_clone,
-                    refresh,
-                    state_clone,
-                    channel_name.clone(),
-                )
-                .await;
-            });
-        } else {
-            tokio::spawn(async move {
-                update_channel(
-                    fetch::ChannelType::RSS(channel_id.clone()),
-                    &keypair,
-                    sender_clone,
-                    error_sender_clone,
-                    refresh,
-                    state_clone,
-                    channel_name.clone(),
-                )
-                .await;
-            });
-        }
+        tokio::spawn(async move {
+            update_channel(
+                feed_id,
+                &keypair,
+                outbox_clone,
+                error_sender_clone,
+                state_clone,
+                channel_name.clone(),
+            )
+            .await;
+        });
     }
 
     info!("Done starting tasks for followed channels.");
@@ -437,55 +733,80 @@ async fn fake_worker(channel_id: String, refresh_interval_secs: u64) {
 }
 
 pub async fn update_channel(
-    channel: fetch::ChannelType,
+    feed: simpledb::FeedId,
     keypair: &secp256k1::KeyPair,
-    sender: nostr_bot::Sender,
-    tx: ErrorSender,
-    refresh_interval_secs: u64,
+    outbox: outbox::Outbox,
+    tx: alerts::AlertSender,
     state: Arc<Mutex<DostrState>>,
     channel_name: String,
 ) {
-    let config = utils::parse_config();
     let state_lock = state.lock().await;
+    let config = state_lock.config.clone();
     let discord_context_option = state_lock.discord_context.lock().await.clone();
+    let media_cache = state_lock.media_cache.clone();
+    let link_cache = state_lock.link_cache.clone();
+    let nitter = state_lock.nitter.clone();
+    let feeds = state_lock.feeds.clone();
+    let db = state_lock.db.clone();
+    let scheduler = state_lock.scheduler.clone();
+    let pool = state_lock.relays.clone();
     drop(state_lock);
-
-    match channel {
-        fetch::ChannelType::Discord(channel_id) => {
+    let options = db.lock().unwrap().get_options(&feed);
+    let mut signer = feed_signer(&feed, keypair, &options, &config);
+    add_feed_signer(&pool, &feed, &signer).await;
+    // The signer is rebuilt when !feedsigner changes the bunker
+    let mut bunker = options.bunker;
+    let mut schedule = schedule::PollSchedule::from_config(&config);
+    let mut delay = schedule.initial_delay();
+    let media_server = media::MediaServer::from_config(&config, feeds.client().clone());
+    let probe_mode = media::ProbeMode::from_config(&config);
+    let link_expander = fetch::LinkExpander::from_config(&config, link_cache);
+
+    match feed {
+        simpledb::FeedId::Discord(channel_id) => {
+            let channel_id = ChannelId(channel_id);
             if let Some(discord_context) = discord_context_option {
                 let discord_context = Arc::new(discord_context);
-                let rssfeed = format!("https://{}/{}/rss", &config.nitter_instance, channel_name);
-                let pfp = fetch::get_pic_url(&rssfeed).await;
-                let about = fetch::get_about(&rssfeed).await;
-                let display_name = fetch::get_display_name(&rssfeed).await;
-                let banner = fetch::get_banner_link(&rssfeed).await;
-
-                let event = nostr_bot::Event::new(
-                    keypair,
-                    utils::unix_timestamp(),
-                    0,
-                    vec![],
-                    format!(
+                let turn = scheduler.turn(std::time::Duration::ZERO).await;
+                let profile = fetch::get_profile(&nitter, &channel_name).await;
+                drop(turn);
+
+                let event = nostr_bot::EventNonSigned {
+                    created_at: utils::unix_timestamp(),
+                    kind: 0,
+                    tags: vec![],
+                    content: format!(
                         r#"{{
                             "name":"{}",
                             "display_name":"{}",
                             "about":"{} \n\nDiscord feed generated by @{}",
                             "picture":"{}",
                             "banner":"{}",
-                            "nip05":"{}@{}"
+                            "nip05":"{}@{}",
+                            "bot":true
                         }}"#,
-                        channel_name, display_name, about, &config.botpub, pfp, banner, channel_name, &config.domain
+                        channel_name, profile.display_name, profile.about, &config.botpub, profile.picture, profile.banner, channel_name, &config.domain
                     ),
-                );
+                };
 
-                sender.lock().await.send(event).await;
+                let relays = feed_relays(&pool.defaults(), &db.lock().unwrap().get_options(&feed));
+                if let Err(e) = publish_profile(&outbox, &pool.defaults(), &signer, event, &relays).await {
+                    error!("Failed to publish the profile of {}: {}", feed, e);
+                }
 
                 let mut since: chrono::DateTime<chrono::offset::Utc> =
                     std::time::SystemTime::now().into();
+                // Messages that couldn't be signed, they go first on the next poll
+                let mut unsigned = vec![];
 
                 loop {
-                    tokio::time::sleep(std::time::Duration::from_secs(refresh_interval_secs))
-                        .await;
+                    let turn = scheduler.turn(delay).await;
+                    let options = db.lock().unwrap().get_options(&feed);
+                    if let Some(owner) = &options.handed_over_to {
+                        info!("{} was handed over to {}, stopping its worker", feed, owner);
+                        return;
+                    }
+                    schedule.set_fixed(options.interval_secs);
 
                     let until = std::time::SystemTime::now().into();
 
@@ -496,35 +817,55 @@ pub async fn update_channel(
                         until,
                     )
                     .await;
+                    // The turn only covers the fetch, other feeds go on while the notes are made
+                    drop(turn);
+
+                    if options.bunker != bunker {
+                        signer = feed_signer(&feed, keypair, &options, &config);
+                        add_feed_signer(&pool, &feed, &signer).await;
+                        bunker = options.bunker.clone();
+                    }
+                    let relays = feed_relays(&pool.defaults(), &options);
 
                     match new_messages {
                         Ok(new_messages) => {
                             since = until;
-
-                            for message in new_messages.iter().rev() {
-                                let event_non_signed = fetch::get_discord_event(&message).await;
-                                let signed_event = event_non_signed.sign(keypair);
-                                sender.lock().await.send(signed_event).await;
+                            schedule.on_success(new_messages.len());
+
+                            let mut sign_error = None;
+                            let mut messages = std::mem::take(&mut unsigned).into_iter().chain(new_messages.into_iter().rev());
+                            while let Some(message) = messages.next() {
+                                let mut event_non_signed = fetch::get_discord_event(&message).await;
+                                if let Some(media_server) = &media_server {
+                                    media::rehost_media(&mut event_non_signed, media_server, &signer).await;
+                                }
+                                media::add_imeta_tags(&mut event_non_signed, &media_cache, probe_mode, feeds.client()).await;
+                                match signer.sign(event_non_signed).await {
+                                    Ok(signed_event) => outbox.send_to(&signed_event, &relays),
+                                    // The rest of the batch would most likely fail the same way
+                                    Err(e) => {
+                                        unsigned = std::iter::once(message).chain(messages).collect();
+                                        error!("Failed to sign a note of {}, keeping {} notes for the next poll: {}", feed, unsigned.len(), e);
+                                        sign_error = Some(e);
+                                        break;
+                                    }
+                                }
                             }
 
-                            tx.send(ConnectionMessage {
-                                status: ConnectionStatus::Success,
-                                timestamp: std::time::SystemTime::now(),
-                            })
-                            .await
-                            .unwrap();
+                            let message = match sign_error {
+                                None => ConnectionMessage::success(Component::Feed(feed.clone())),
+                                Some(e) => ConnectionMessage::failed(Component::Feed(feed.clone()), format!("Unable to sign: {}", e)),
+                            };
+                            let _ = tx.send(message).await;
                         }
                         Err(e) => {
-                            tx.send(ConnectionMessage {
-                                status: ConnectionStatus::Failed,
-                                timestamp: std::time::SystemTime::now(),
-                            })
-                            .await
-                            .unwrap();
+                            let _ = tx.send(ConnectionMessage::failed(Component::Feed(feed.clone()), e.to_string())).await;
 
                             error!("Failed to get new messages for channel {}: {}", channel_id, e);
+                            schedule.on_error(None);
                         }
                     }
+                    delay = schedule.next_delay();
                 }
             } else {
                 error!(
@@ -533,75 +874,116 @@ pub async fn update_channel(
                 );
             }
         }
-        fetch::ChannelType::RSS(channel_id) => {
-            let rssfeed = format!("https://{}/{}/rss", &config.nitter_instance, channel_name);
-            let pfp = fetch::get_pic_url(&rssfeed).await;
-            let about = fetch::get_about(&rssfeed).await;
-            let display_name = fetch::get_display_name(&rssfeed).await;
-            let banner = fetch::get_banner_link(&rssfeed).await;
-
-            let event = nostr_bot::Event::new(
-                keypair,
-                utils::unix_timestamp(),
-                0,
-                vec![],
-                format!(
+        feed => {
+            let turn = scheduler.turn(std::time::Duration::ZERO).await;
+            let (profile, source) = match &feed {
+                simpledb::FeedId::Rss(url) => (fetch::get_rss_profile(&feeds, url).await, "RSS"),
+                _ => (fetch::get_profile(&nitter, &channel_name).await, "Twitter"),
+            };
+            drop(turn);
+
+            let event = nostr_bot::EventNonSigned {
+                created_at: utils::unix_timestamp(),
+                kind: 0,
+                tags: vec![],
+                content: format!(
                     r#"{{
                         "name":"{}",
                         "display_name":"{}",
-                        "about":"{} \n\nTwitter feed generated by @{}",
+                        "about":"{} \n\n{} feed generated by @{}",
                         "picture":"{}",
                         "banner":"{}",
-                        "nip05":"{}@{}"
+                        "nip05":"{}@{}",
+                        "bot":true
                     }}"#,
-                    channel_name, display_name, about, &config.botpub, pfp, banner, channel_name, &config.domain
+                    channel_name, profile.display_name, profile.about, source, &config.botpub, profile.picture, profile.banner, channel_name, &config.domain
                 ),
-            );
+            };
 
-            sender.lock().await.send(event).await;
+            let relays = feed_relays(&pool.defaults(), &db.lock().unwrap().get_options(&feed));
+            if let Err(e) = publish_profile(&outbox, &pool.defaults(), &signer, event, &relays).await {
+                error!("Failed to publish the profile of {}: {}", feed, e);
+            }
             
             let mut since: chrono::DateTime<chrono::offset::Utc> =
                 chrono::offset::Utc::now();
+            // Items that couldn't be signed, they go first on the next poll
+            let mut unsigned = vec![];
         
             loop {
-                tokio::time::sleep(std::time::Duration::from_secs(refresh_interval_secs)).await;
+                let turn = scheduler.turn(delay).await;
+                let options = db.lock().unwrap().get_options(&feed);
+                if let Some(owner) = &options.handed_over_to {
+                    info!("{} was handed over to {}, stopping its worker", feed, owner);
+                    return;
+                }
+                schedule.set_fixed(options.interval_secs);
         
                 let until = chrono::offset::Utc::now();
         
-                let new_items = fetch::get_new_rss_items(&rssfeed, &since, &until).await;
+                let new_items = match &feed {
+                    simpledb::FeedId::Twitter(handle) => fetch::get_new_rss_items(&nitter, handle, &since, &until, &config.link_frontend).await,
+                    simpledb::FeedId::Rss(url) => fetch::get_new_feed_items(&feeds, url, &since, &until).await,
+                    simpledb::FeedId::Discord(_) => unreachable!("Discord channels are handled above"),
+                };
+                // The turn only covers the fetch, other feeds go on while the notes are made
+                drop(turn);
+
+                if options.bunker != bunker {
+                    signer = feed_signer(&feed, keypair, &options, &config);
+                    add_feed_signer(&pool, &feed, &signer).await;
+                    bunker = options.bunker.clone();
+                }
+                let relays = feed_relays(&pool.defaults(), &options);
         
                 match new_items {
                     Ok(items) => {
                         since = until;
+                        schedule.on_success(items.len());
         
-                        for item in items.into_iter() {
-                            let event_non_signed = fetch::get_rss_event(&item).await;
-                            let signed_event = event_non_signed.sign(keypair);
-                            sender.lock().await.send(signed_event).await;
+                        let mut sign_error = None;
+                        let mut items = std::mem::take(&mut unsigned).into_iter().chain(items);
+                        while let Some(mut item) = items.next() {
+                            if let Some(link_expander) = &link_expander {
+                                item.description = fetch::expand_short_links(&item.description, link_expander).await;
+                            }
+                            let mut event_non_signed = fetch::get_rss_event(&item).await;
+                            if let Some(media_server) = &media_server {
+                                media::rehost_media(&mut event_non_signed, media_server, &signer).await;
+                            }
+                            media::add_imeta_tags(&mut event_non_signed, &media_cache, probe_mode, feeds.client()).await;
+                            match signer.sign(event_non_signed).await {
+                                Ok(signed_event) => outbox.send_to(&signed_event, &relays),
+                                // The rest of the batch would most likely fail the same way
+                                Err(e) => {
+                                    unsigned = std::iter::once(item).chain(items).collect();
+                                    error!("Failed to sign a note of {}, keeping {} notes for the next poll: {}", feed, unsigned.len(), e);
+                                    sign_error = Some(e);
+                                    break;
+                                }
+                            }
                         }
         
-                        tx.send(ConnectionMessage {
-                            status: ConnectionStatus::Success,
-                            timestamp: std::time::SystemTime::now(),
-                        })
-                        .await
-                        .unwrap();
+                        let message = match sign_error {
+                            None => ConnectionMessage::success(Component::Feed(feed.clone())),
+                            Some(e) => ConnectionMessage::failed(Component::Feed(feed.clone()), format!("Unable to sign: {}", e)),
+                        };
+                        let _ = tx.send(message).await;
                     }
                     Err(e) => {
-                        tx.send(ConnectionMessage {
-                            status: ConnectionStatus::Failed,
-                            timestamp: std::time::SystemTime::now(),
-                        })
-                        .await
-                        .unwrap();
+                        let _ = tx.send(ConnectionMessage::failed(Component::Feed(feed.clone()), e.to_string())).await;
         
                         error!(
-                            "Failed to get new items for RSS channel {}: {}",
-                            channel_id, e
+                            "Failed to get new items for feed {}: {}",
+                            feed, e
                         );
+                        schedule.on_error(e.retry_after());
                     }
                 }
+                delay = schedule.next_delay();
             }
         }        
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests;
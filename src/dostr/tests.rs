@@ -0,0 +1,401 @@
+// End-to-end tests of the feed workers against the mock relay and Nitter instance in
+// crate::testing.
+
+use super::*;
+use crate::testing::TestBot;
+use serde_json::Value;
+
+fn content(event: &Value) -> String {
+    event["content"].as_str().unwrap().to_string()
+}
+
+fn has_tag(event: &Value, tag: &[&str]) -> bool {
+    event["tags"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .any(|t| t.as_array().unwrap().iter().map(|v| v.as_str().unwrap()).eq(tag.iter().copied()))
+}
+
+// Public key the reply to !add points to
+fn mentioned_pubkey(reply: &nostr_bot::EventNonSigned) -> String {
+    reply.tags.last().unwrap()[1].clone()
+}
+
+#[tokio::test]
+async fn start_existing_mirrors_only_new_tweets() {
+    let old = "Posted before the bot started";
+    let test = TestBot::start(&["nasa"]).await;
+    test.nitter.post_old("nasa", old);
+    let pubkey = test.mirror_pubkey("nasa").await;
+
+    let profiles = test.relay.wait_for(&pubkey, 0, 1).await;
+    let profile: Value = serde_json::from_str(&content(&profiles[0])).unwrap();
+    assert_eq!(profile["name"], "nasa");
+    assert_eq!(profile["display_name"], "NASA ");
+    assert_eq!(profile["nip05"], "nasa@example.com");
+    assert_eq!(profile["bot"], true);
+
+    let id = test.nitter.post("nasa", "Liftoff! <a href=\"https://nitter.example/search?q=%23Artemis\">#Artemis</a>");
+    let notes = test.relay.wait_for(&pubkey, 1, 1).await;
+    assert_eq!(notes.len(), 1);
+    assert_eq!(content(&notes[0]), "Liftoff! #Artemis");
+    assert!(has_tag(&notes[0], &["t", "artemis"]));
+    let link = format!("https://x.com/nasa/status/{}", id);
+    assert!(has_tag(&notes[0], &["proxy", &link, "rss"]));
+
+    // The next poll finds nothing new
+    test.nitter.post("nasa", "Second");
+    let notes = test.relay.wait_for(&pubkey, 1, 2).await;
+    assert_eq!(notes.iter().map(content).collect::<Vec<_>>(), vec!["Liftoff! #Artemis", "Second"]);
+    assert!(test.relay.events().iter().all(|event| !content(event).contains(old)));
+    // Polls of the unchanged feed are answered with 304, quiet feeds are polled at least every
+    // MAX_REFRESH_INTERVAL_SECS
+    tokio::time::sleep(std::time::Duration::from_secs(3)).await;
+    assert!(test.nitter.not_modified() > 0);
+}
+
+#[tokio::test]
+async fn channel_add_starts_a_mirror() {
+    let test = TestBot::start(&[]).await;
+
+    let reply = test.command(channel_add, "!add @jack").await;
+    let pubkey = mentioned_pubkey(&reply);
+    assert_eq!(pubkey, test.mirror_pubkey("jack").await);
+    assert_eq!(test.nostr_json()["names"]["jack"], pubkey.as_str());
+
+    test.relay.wait_for(&pubkey, 0, 1).await;
+    let id = test.nitter.post_video("jack", "just setting up my twttr", "https://video.twimg.com/tweet_video/jack.mp4");
+    let notes = test.relay.wait_for(&pubkey, 1, 1).await;
+    assert_eq!(
+        content(&notes[0]),
+        "just setting up my twttr\n\nhttps://video.twimg.com/tweet_video/jack.mp4"
+    );
+    assert!(test.nitter.requests().contains(&format!("/jack/status/{}", id)));
+
+    // Adding the same account again returns the existing mirror without starting another worker
+    let reply = test.command(channel_add, "!add @jack").await;
+    assert_eq!(mentioned_pubkey(&reply), pubkey);
+    tokio::time::sleep(std::time::Duration::from_secs(2)).await;
+    assert_eq!(test.relay.events_by(&pubkey, 0).len(), 1);
+}
+
+#[tokio::test]
+async fn channel_add_respects_max_follows() {
+    let test = TestBot::start(&[]).await;
+    test.state.lock().await.config.max_follows = 1;
+
+    test.command(channel_add, "!add @jack").await;
+    let reply = test.command(channel_add, "!add @nasa").await;
+    assert!(reply.content.contains("max capacity (1 channels)"), "{}", reply.content);
+    assert!(test.nostr_json()["names"].get("nasa").is_none());
+}
+
+#[tokio::test]
+async fn channel_interval_sets_fixed_intervals() {
+    let test = TestBot::start(&["nasa"]).await;
+    let db = test.state.lock().await.db.clone();
+    let nasa = simpledb::FeedId::twitter("nasa");
+
+    let reply = test.command_as(&test.operator, channel_interval, "!interval @NASA 600").await;
+    assert!(reply.content.contains("every 600 s"), "{}", reply.content);
+    assert_eq!(db.lock().unwrap().get_options(&nasa).interval_secs, Some(600));
+
+    // Anyone can ask, only operators can change it
+    let reply = test.command(channel_interval, "!interval nasa").await;
+    assert!(reply.content.contains("every 600 s"), "{}", reply.content);
+    let reply = test.command(channel_interval, "!interval nasa 5").await;
+    assert!(reply.content.contains("Only operators"), "{}", reply.content);
+    assert_eq!(db.lock().unwrap().get_options(&nasa).interval_secs, Some(600));
+
+    let reply = test.command_as(&test.operator, channel_interval, "!interval nasa soon").await;
+    assert!(reply.content.starts_with("Error"), "{}", reply.content);
+
+    test.command_as(&test.operator, channel_interval, "!interval nasa auto").await;
+    assert_eq!(db.lock().unwrap().get_options(&nasa).interval_secs, None);
+
+    let reply = test.command(channel_interval, "!interval @jack 60").await;
+    assert!(reply.content.contains("not following"), "{}", reply.content);
+}
+
+#[tokio::test]
+async fn channel_feed_relays_moves_notes_to_other_relays() {
+    let test = TestBot::start(&["nasa"]).await;
+    let pubkey = test.mirror_pubkey("nasa").await;
+    let relay_lists = test.relay.wait_for(&pubkey, 10002, 1).await;
+    assert!(has_tag(&relay_lists[0], &["r", test.relay.url()]));
+
+    let topic_relay = crate::testing::MockRelay::start().await;
+    let reply = test.command_as(&test.operator, channel_feed_relays, &format!("!feedrelays @nasa {}", topic_relay.url())).await;
+    assert!(reply.content.contains(topic_relay.url()), "{}", reply.content);
+    let reply = test.command_as(&test.operator, channel_feed_relays, "!feedrelays @nasa https://relay.example").await;
+    assert!(reply.content.starts_with("Error"), "{}", reply.content);
+
+    // The new relay list goes to the default relays too, so clients can find the topic relay
+    let relay_lists = test.relay.wait_for(&pubkey, 10002, 2).await;
+    assert!(has_tag(&relay_lists[1], &["r", topic_relay.url()]));
+    assert!(!has_tag(&relay_lists[1], &["r", test.relay.url()]));
+    topic_relay.wait_for(&pubkey, 10002, 1).await;
+
+    test.nitter.post("nasa", "For the topic relay");
+    let notes = topic_relay.wait_for(&pubkey, 1, 1).await;
+    assert_eq!(content(&notes[0]), "For the topic relay");
+    assert!(test.relay.events_by(&pubkey, 1).is_empty());
+
+    let reply = test.command_as(&test.operator, channel_feed_relays, "!feedrelays nasa default").await;
+    assert!(reply.content.contains("default relays"), "{}", reply.content);
+    test.nitter.post("nasa", "Back home");
+    test.relay.wait_for(&pubkey, 1, 1).await;
+}
+
+#[tokio::test]
+async fn channel_feed_relays_changes_are_for_operators_only() {
+    let test = TestBot::start(&["nasa"]).await;
+    let other_relay = crate::testing::MockRelay::start().await;
+    let reply = test.command(channel_feed_relays, &format!("!feedrelays @nasa {}", other_relay.url())).await;
+    assert!(reply.content.starts_with("Error: Only operators"), "{}", reply.content);
+    let db = test.state.lock().await.db.clone();
+    assert!(db.lock().unwrap().get_options(&simpledb::FeedId::twitter("nasa")).relays.is_empty());
+
+    // Looking is fine
+    let reply = test.command(channel_feed_relays, "!feedrelays @nasa").await;
+    assert!(reply.content.contains("default relays"), "{}", reply.content);
+}
+
+#[tokio::test]
+async fn channel_relay_changes_the_default_relays() {
+    let test = TestBot::start(&["nasa"]).await;
+    let pubkey = test.mirror_pubkey("nasa").await;
+    test.relay.wait_for(&pubkey, 0, 1).await;
+
+    let new_relay = crate::testing::MockRelay::start().await;
+    let reply = test.command_as(&test.operator, channel_relay, &format!("!relay add {}", new_relay.url())).await;
+    assert!(reply.content.contains(new_relay.url()), "{}", reply.content);
+    let reply = test.command_as(&test.operator, channel_relay, &format!("!relay add {}", new_relay.url())).await;
+    assert!(reply.content.contains("already"), "{}", reply.content);
+    let reply = test.command_as(&test.operator, channel_relay, "!relay add https://relay.example").await;
+    assert!(reply.content.starts_with("Error"), "{}", reply.content);
+
+    test.nitter.post("nasa", "Everywhere");
+    test.relay.wait_for(&pubkey, 1, 1).await;
+    new_relay.wait_for(&pubkey, 1, 1).await;
+
+    let report = relays_report(&test.state, vec![]).await;
+    assert!(report.contains(&format!("{}: connected, ", new_relay.url())), "{}", report);
+
+    test.command_as(&test.operator, channel_relay, &format!("!relay remove {}", test.relay.url())).await;
+    // Let a poll that started before the change finish
+    tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+    test.nitter.post("nasa", "Only on the new relay");
+    let notes = new_relay.wait_for(&pubkey, 1, 2).await;
+    assert_eq!(content(&notes[1]), "Only on the new relay");
+    assert_eq!(test.relay.events_by(&pubkey, 1).len(), 1);
+
+    let reply = test.command_as(&test.operator, channel_relay, &format!("!relay remove {}", new_relay.url())).await;
+    assert!(reply.content.starts_with("Error"), "{}", reply.content);
+    assert_eq!(test.state.lock().await.relays.defaults(), vec![new_relay.url().to_string()]);
+}
+
+#[tokio::test]
+async fn channel_relay_is_for_operators_only() {
+    let test = TestBot::start(&[]).await;
+    let new_relay = crate::testing::MockRelay::start().await;
+    let reply = test.command(channel_relay, &format!("!relay add {}", new_relay.url())).await;
+    assert!(reply.content.starts_with("Error: Only operators"), "{}", reply.content);
+    let reply = test.command(channel_relay, &format!("!relay remove {}", test.relay.url())).await;
+    assert!(reply.content.starts_with("Error: Only operators"), "{}", reply.content);
+    assert_eq!(test.state.lock().await.relays.defaults(), vec![test.relay.url().to_string()]);
+}
+
+#[tokio::test]
+async fn mirrors_authenticate_to_relays_that_require_it() {
+    let test = TestBot::start(&["nasa"]).await;
+    let pubkey = test.mirror_pubkey("nasa").await;
+    let members_relay = crate::testing::MockRelay::start().await;
+    members_relay.set_requiring_auth(true);
+    test.command_as(&test.operator, channel_relay, &format!("!relay add {}", members_relay.url())).await;
+
+    test.nitter.post("nasa", "For members");
+    let notes = members_relay.wait_for(&pubkey, 1, 1).await;
+    assert_eq!(content(&notes[0]), "For members");
+    assert!(members_relay.authenticated().contains(&pubkey));
+
+    let report = relays_report(&test.state, vec![]).await;
+    assert!(report.contains("requires AUTH"), "{}", report);
+}
+
+#[tokio::test]
+async fn channel_feed_signer_moves_signing_to_a_bunker() {
+    let test = TestBot::start(&["nasa"]).await;
+    let pubkey = test.mirror_pubkey("nasa").await;
+    test.relay.wait_for(&pubkey, 0, 1).await;
+    let db = test.state.lock().await.db.clone();
+    let keypair = simpledb::get_channel_keypair(&simpledb::FeedId::twitter("nasa"), db).unwrap();
+
+    let bunker = crate::testing::MockBunker::start(keypair, Some("s3cret")).await;
+    let reply = test.command(channel_feed_signer, &format!("!feedsigner @nasa {}", bunker.uri(Some("s3cret")))).await;
+    assert!(reply.content.starts_with("Error: Only operators"), "{}", reply.content);
+
+    // Bunkers signing with another key are refused
+    let other = crate::testing::MockBunker::start(utils::get_random_keypair(), None).await;
+    let reply = test.command_as(&test.operator, channel_feed_signer, &format!("!feedsigner @nasa {}", other.uri(None))).await;
+    assert!(reply.content.starts_with("Error: The bunker signs as"), "{}", reply.content);
+
+    let reply = test.command_as(&test.operator, channel_feed_signer, &format!("!feedsigner @nasa {}", bunker.uri(Some("wrong")))).await;
+    assert!(reply.content.contains("authorize my client key"), "{}", reply.content);
+    let reply = test.command_as(&test.operator, channel_feed_signer, &format!("!feedsigner @nasa {}", bunker.uri(Some("s3cret")))).await;
+    assert!(reply.content.contains("now signed by the bunker"), "{}", reply.content);
+    let reply = test.command(channel_feed_signer, "!feedsigner nasa").await;
+    assert!(reply.content.contains("signed by a bunker"), "{}", reply.content);
+
+    test.nitter.post("nasa", "Signed \"remotely\"");
+    let notes = test.relay.wait_for(&pubkey, 1, 1).await;
+    assert_eq!(content(&notes[0]), "Signed \"remotely\"");
+    assert!(bunker.signed().iter().any(|event| event["id"] == notes[0]["id"]));
+
+    let reply = test.command_as(&test.operator, channel_feed_signer, "!feedsigner nasa local").await;
+    assert!(reply.content.contains("with its key"), "{}", reply.content);
+    test.nitter.post("nasa", "Signed here");
+    let notes = test.relay.wait_for(&pubkey, 1, 2).await;
+    assert!(!bunker.signed().iter().any(|event| event["id"] == notes[1]["id"]));
+}
+
+#[tokio::test]
+async fn notes_wait_for_an_unreachable_bunker() {
+    let test = TestBot::start(&["nasa"]).await;
+    let pubkey = test.mirror_pubkey("nasa").await;
+    test.relay.wait_for(&pubkey, 0, 1).await;
+    let db = test.state.lock().await.db.clone();
+    let keypair = simpledb::get_channel_keypair(&simpledb::FeedId::twitter("nasa"), db).unwrap();
+
+    let bunker = crate::testing::MockBunker::start(keypair, None).await;
+    let reply = test.command_as(&test.operator, channel_feed_signer, &format!("!feedsigner @nasa {}", bunker.uri(None))).await;
+    assert!(reply.content.contains("now signed by the bunker"), "{}", reply.content);
+
+    bunker.set_offline(true);
+    test.nitter.post("nasa", "First");
+    test.nitter.post("nasa", "Second");
+    // A few polls fail to sign
+    tokio::time::sleep(std::time::Duration::from_secs(4)).await;
+    assert!(test.relay.events().iter().all(|event| event["pubkey"] != pubkey.as_str() || event["kind"] != 1));
+
+    bunker.set_offline(false);
+    let notes = test.relay.wait_for(&pubkey, 1, 2).await;
+    let mut contents = notes.iter().map(content).collect::<Vec<_>>();
+    contents.sort();
+    assert_eq!(contents, vec!["First", "Second"]);
+    assert!(notes.iter().all(|note| bunker.signed().iter().any(|event| event["id"] == note["id"])));
+}
+
+#[tokio::test]
+async fn channel_claim_hands_the_key_over() {
+    use bech32::{FromBase32, ToBase32};
+    let test = TestBot::start(&["nasa"]).await;
+    let pubkey = test.mirror_pubkey("nasa").await;
+    test.relay.wait_for(&pubkey, 0, 1).await;
+    let db = test.state.lock().await.db.clone();
+    let keypair = simpledb::get_channel_keypair(&simpledb::FeedId::twitter("nasa"), db).unwrap();
+    let owner = utils::get_random_keypair();
+    let npub = bech32::encode("npub", owner.x_only_public_key().0.serialize().to_base32(), bech32::Variant::Bech32).unwrap();
+
+    let reply = test.command(channel_claim, &format!("!claim @nasa {}", npub)).await;
+    assert!(reply.content.starts_with("Error: Only operators"), "{}", reply.content);
+    let reply = test.command_as(&test.operator, channel_claim, "!claim @nasa npub1nope").await;
+    assert!(reply.content.starts_with("Error: Invalid public key"), "{}", reply.content);
+    let reply = test.command_as(&test.operator, channel_claim, &format!("!claim @nasa {}", npub)).await;
+    assert!(reply.content.contains("stopped posting"), "{}", reply.content);
+
+    let owner_hex = owner.x_only_public_key().0.to_string();
+    let dms = test.relay.wait_for_to(&owner_hex, 1059, 1).await;
+    let rumor = crate::testing::open_gift_wrap(&owner, &dms[0]);
+    let nsec = content(&rumor).split_whitespace().find(|word| word.starts_with("nsec1")).unwrap().to_string();
+    let (_, data, _) = bech32::decode(&nsec).unwrap();
+    assert_eq!(Vec::<u8>::from_base32(&data).unwrap(), keypair.secret_bytes().to_vec());
+
+    // The bot doesn't post as the feed or take it back
+    test.nitter.post("nasa", "Not mirrored anymore");
+    tokio::time::sleep(std::time::Duration::from_millis(2500)).await;
+    assert!(test.relay.events_by(&pubkey, 1).is_empty());
+    let reply = test.command(channel_add, "!add @nasa").await;
+    assert!(reply.content.contains("handed over"), "{}", reply.content);
+    let reply = test.command(channel_list, "!list").await;
+    assert!(reply.content.contains("following 0 accounts"), "{}", reply.content);
+    let other = utils::get_random_keypair().x_only_public_key().0;
+    let reply = test.command_as(&test.operator, channel_claim, &format!("!claim @nasa {}", other)).await;
+    assert!(reply.content.starts_with("Error: @nasa was already handed over"), "{}", reply.content);
+}
+
+#[tokio::test]
+async fn mirrors_keep_working_when_nitter_instance_fails() {
+    let nitter = crate::testing::MockNitter::start_instances(2).await;
+    let test = TestBot::start_with(&["nasa"], nitter).await;
+    let pubkey = test.mirror_pubkey("nasa").await;
+    test.relay.wait_for(&pubkey, 0, 1).await;
+
+    test.nitter.set_down(0, true);
+    let id = test.nitter.post("nasa", "Still here");
+    let notes = test.relay.wait_for(&pubkey, 1, 1).await;
+    assert_eq!(content(&notes[0]), "Still here");
+    // Links don't depend on the instance the item came from
+    let link = format!("https://x.com/nasa/status/{}", id);
+    assert!(has_tag(&notes[0], &["proxy", &link, "rss"]));
+
+    test.nitter.set_down(0, false);
+    test.nitter.set_down(1, true);
+    test.nitter.post("nasa", "And here");
+    let notes = test.relay.wait_for(&pubkey, 1, 2).await;
+    assert_eq!(content(&notes[1]), "And here");
+}
+
+#[tokio::test]
+async fn channel_add_keeps_one_identity_per_account() {
+    let test = TestBot::start(&["nasa"]).await;
+    let pubkey = test.mirror_pubkey("nasa").await;
+
+    for command in ["!add @nasa", "!add @NASA", "!add nasa"] {
+        let reply = test.command(channel_add, command).await;
+        assert_eq!(mentioned_pubkey(&reply), pubkey, "{}", command);
+    }
+    assert_eq!(test.state.lock().await.db.lock().unwrap().follows_count(), 1);
+}
+
+#[tokio::test]
+async fn channel_add_derives_keys_from_the_master_key() {
+    let test = TestBot::start(&[]).await;
+    test.state.lock().await.config.derive_feed_keys = true;
+    let master = nostr_bot::keypair_from_secret(&test.config.secret);
+    let derived = |keypair: secp256k1::KeyPair| keypair.x_only_public_key().0.to_string();
+
+    let reply = test.command(channel_add, "!add @jack").await;
+    let pubkey = mentioned_pubkey(&reply);
+    assert_eq!(pubkey, derived(utils::derive_keypair(&master, &simpledb::FeedId::twitter("jack"))));
+    assert_eq!(pubkey, test.mirror_pubkey("jack").await);
+
+    // Other feeds and other master keys give unrelated keys
+    assert_ne!(pubkey, derived(utils::derive_keypair(&master, &simpledb::FeedId::twitter("nasa"))));
+    let other_master = utils::get_random_keypair();
+    assert_ne!(pubkey, derived(utils::derive_keypair(&other_master, &simpledb::FeedId::twitter("jack"))));
+}
+
+#[tokio::test]
+async fn channel_add_follows_plain_rss_feeds() {
+    let test = TestBot::start(&[]).await;
+    let feed_url = format!("{}/blog/rss", test.nitter.urls()[0]);
+
+    let reply = test.command(channel_add, &format!("!add {} blog", feed_url)).await;
+    let pubkey = mentioned_pubkey(&reply);
+    let db = test.state.lock().await.db.clone();
+    assert!(db.lock().unwrap().contains_key(&simpledb::FeedId::Rss(feed_url.clone())));
+    assert_eq!(test.nostr_json()["names"]["blog"], pubkey.as_str());
+
+    let profiles = test.relay.wait_for(&pubkey, 0, 1).await;
+    assert!(content(&profiles[0]).contains("RSS feed generated by"));
+
+    let id = test.nitter.post("blog", "New post");
+    let notes = test.relay.wait_for(&pubkey, 1, 1).await;
+    assert_eq!(content(&notes[0]), "New post");
+    // Links of plain feeds aren't rewritten and no page is scraped for videos
+    let link = format!("{}/blog/status/{}#m", test.nitter.urls()[0], id);
+    assert!(has_tag(&notes[0], &["source", &link]));
+    assert!(!test.nitter.requests().iter().any(|path| path.contains("/status/")));
+}
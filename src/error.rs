@@ -0,0 +1,98 @@
+// Errors of fetching feeds, talking to Discord and reading or writing the database. Workers log
+// them and carry on, commands turn them into replies.
+
+use std::time::Duration;
+
+#[derive(Debug)]
+pub enum Error {
+    // Request failed or timed out
+    Network(String),
+    // The server answered with this error status
+    Status(u16, String),
+    // 429 Too Many Requests, with the Retry-After delay if the server sent one
+    RateLimited(Option<Duration>),
+    // Malformed feed, page, link or stored value
+    Parse(String),
+    Discord(String),
+    Storage(String),
+    // A remote signer refused or botched a request
+    Signer(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    // Error of an answer with an error status, `reason` tells what failed. Rate limits keep the
+    // Retry-After delay.
+    pub fn from_status(status: reqwest::StatusCode, headers: &reqwest::header::HeaderMap, reason: String) -> Error {
+        if status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+            let retry_after = headers
+                .get(reqwest::header::RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.trim().parse::<u64>().ok())
+                .map(Duration::from_secs);
+            return Error::RateLimited(retry_after);
+        }
+        Error::Status(status.as_u16(), reason)
+    }
+
+    // How long the server asked us to wait, for rate limits that came with Retry-After
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Error::RateLimited(retry_after) => *retry_after,
+            _ => None,
+        }
+    }
+
+    // Failures of the server or the way to it rather than of the request, another server may do
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Error::Network(_) | Error::RateLimited(_) => true,
+            Error::Status(status, _) => *status >= 500,
+            _ => false,
+        }
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::Network(err) | Error::Status(_, err) => write!(f, "Network error: {}", err),
+            Error::RateLimited(Some(retry_after)) => write!(f, "Rate limited, retry after {} s", retry_after.as_secs()),
+            Error::RateLimited(None) => write!(f, "Rate limited"),
+            Error::Parse(err) => write!(f, "Parse error: {}", err),
+            Error::Discord(err) => write!(f, "Discord error: {}", err),
+            Error::Storage(err) => write!(f, "Storage error: {}", err),
+            Error::Signer(err) => write!(f, "Signer error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<reqwest::Error> for Error {
+    fn from(err: reqwest::Error) -> Error {
+        match err.status() {
+            Some(status) => Error::Status(status.as_u16(), err.to_string()),
+            None => Error::Network(err.to_string()),
+        }
+    }
+}
+
+impl From<rss::Error> for Error {
+    fn from(err: rss::Error) -> Error {
+        Error::Parse(format!("Invalid RSS feed: {}", err))
+    }
+}
+
+impl From<serenity::Error> for Error {
+    fn from(err: serenity::Error) -> Error {
+        Error::Discord(err.to_string())
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Error {
+        Error::Storage(err.to_string())
+    }
+}
@@ -1,31 +1,36 @@
-use log::{debug, info};
+use log::{debug, error, info};
+use crate::alerts::{AlertSender, Component, ConnectionMessage};
+use crate::error::{self, Error};
 use crate::utils;
-use crate::simpledb::SimpleDatabase;
+use crate::nitter::NitterPool;
+use crate::scheduler::{HostLimits, HostPermit};
+use crate::outbox::Outbox;
+use crate::signer::Signer;
+use crate::simpledb::{Database, FeedId};
 use serenity::{
     async_trait,
+    client::bridge::gateway::event::ShardStageUpdateEvent,
+    gateway::ConnectionStage,
     model::{channel::Message, gateway::Ready, id::ChannelId},
     prelude::*,
 };
 use std::sync::Arc;
 use rss::Channel;
 use chrono::{DateTime, Utc};
-use scraper::{Html, Selector};
+use scraper::{ElementRef, Html, Node, Selector};
 use std::collections::{HashSet, HashMap};
 use url::{Url, ParseError as UrlParseError};
 use reqwest::Client;
 use futures::stream::StreamExt;
 
-pub enum ChannelType {
-    Discord(ChannelId),
-    RSS(String),
-}
-
 #[allow(dead_code)]
 pub struct DiscordMessage {
     timestamp: u64,
     message: String,
+    link: String,
 }
 
+#[allow(dead_code)]
 pub struct RSSItem {
     pub timestamp: DateTime<Utc>,
     pub title: String,
@@ -33,29 +38,40 @@ pub struct RSSItem {
     pub link: String,
 }
 
+pub type LinkCache = Arc<std::sync::Mutex<utils::BoundedCache<String>>>;
+
+// Follows redirects of links from known shortener domains, see expand_short_links
+pub struct LinkExpander {
+    domains: Vec<String>,
+    client: Client,
+    cache: LinkCache,
+}
+
 pub struct Handler {
     pub discord_context: Arc<Mutex<Option<Context>>>,
-    pub db_client: Arc<Mutex<SimpleDatabase>>,
-    pub sender: nostr_bot::Sender,
-    pub keypair: secp256k1::KeyPair,
+    pub db_client: Database,
+    pub outbox: Outbox,
+    pub signer: Signer,
+    pub alerts: AlertSender,
 }
 
 #[async_trait]
 impl EventHandler for Handler {
     async fn message(&self, _ctx: Context, msg: Message) {
-        let follows = self.db_client.lock().await.get_follows();
+        let follows = self.db_client.lock().unwrap().get_follows();
 
-        if follows.contains_key(&msg.channel_id.to_string()) {
+        if follows.contains_key(&FeedId::Discord(msg.channel_id.0)) {
             let discord_message = DiscordMessage {
                 timestamp: msg.timestamp.timestamp() as u64,
                 message: msg.content.clone(),
+                link: msg.link(),
             };
 
             let event_non_signed = get_discord_event(&discord_message).await;
-            let signed_event = event_non_signed.sign(&self.keypair); 
-            self.sender.lock().await.send(signed_event).await;
-        } else {
-
+            match self.signer.sign(event_non_signed).await {
+                Ok(signed_event) => self.outbox.send(&signed_event),
+                Err(e) => error!("Failed to sign the message {}: {}", discord_message.link, e),
+            }
         }
     }
     
@@ -63,23 +79,33 @@ impl EventHandler for Handler {
     async fn ready(&self, context: Context, _ready: Ready) {
         let mut discord_context = self.discord_context.lock().await;
         *discord_context = Some(context);
+        let _ = self.alerts.send(ConnectionMessage::success(Component::Discord)).await;
+    }
+
+    // serenity reconnects on its own, this only tells the operators about it
+    async fn shard_stage_update(&self, _ctx: Context, update: ShardStageUpdateEvent) {
+        let message = match update.new {
+            ConnectionStage::Connected => ConnectionMessage::success(Component::Discord),
+            ConnectionStage::Disconnected => {
+                ConnectionMessage::failed(Component::Discord, format!("Shard {} disconnected from the gateway", update.shard_id))
+            }
+            _ => return,
+        };
+        let _ = self.alerts.send(message).await;
     }
 }
 
 pub async fn channel_exists(channel_id: &ChannelId, ctx: Arc<Context>) -> bool {
-    match channel_id.to_channel(&(*ctx)).await {
-        Ok(_) => true,
-        Err(_) => false,
-    }
+    channel_id.to_channel(&(*ctx)).await.is_ok()
 }
 
 #[allow(dead_code)]
-pub async fn get_channel_name(channel_id: &ChannelId, ctx: Arc<Context>) -> Result<String, Box<dyn std::error::Error>> {
+pub async fn get_channel_name(channel_id: &ChannelId, ctx: Arc<Context>) -> error::Result<String> {
     let channel = channel_id.to_channel(&(*ctx)).await?;
 
     match channel {
         serenity::model::channel::Channel::Guild(channel) => Ok(channel.name().to_owned()),
-        _ => Err("The provided channel is not a GuildChannel".into())
+        _ => Err(Error::Discord(format!("Channel {} is not a guild channel", channel_id)))
     }
 }
 
@@ -88,29 +114,23 @@ pub async fn get_new_messages(
     channel_id: ChannelId,
     since: chrono::DateTime<chrono::offset::Utc>,
     until: chrono::DateTime<chrono::offset::Utc>,
-) -> Result<Vec<DiscordMessage>, String> {
-
-    let messages = channel_id.messages(&ctx, |retriever| retriever.limit(10)).await;
-    match messages {
-        Ok(retrieved_messages) => {
-
-            let mut new_messages = vec![];
-            for message in retrieved_messages {
-                let message_timestamp = message.timestamp.timestamp();
-                if message_timestamp >= since.timestamp() && message_timestamp < until.timestamp() {
-                    new_messages.push(DiscordMessage {
-                        timestamp: message_timestamp as u64,
-                        message: message.content,
-                    });
-                }
-            }
-
-            Ok(new_messages)
+) -> error::Result<Vec<DiscordMessage>> {
+
+    let retrieved_messages = channel_id.messages(&ctx, |retriever| retriever.limit(10)).await?;
+
+    let mut new_messages = vec![];
+    for message in retrieved_messages {
+        let message_timestamp = message.timestamp.timestamp();
+        if message_timestamp >= since.timestamp() && message_timestamp < until.timestamp() {
+            new_messages.push(DiscordMessage {
+                timestamp: message_timestamp as u64,
+                link: message.link(),
+                message: message.content,
+            });
         }
-        Err(why) => {
-            Err(format!("Error getting messages: {:?}", why))
-        },
     }
+
+    Ok(new_messages)
 }
 
 
@@ -118,7 +138,7 @@ pub async fn get_discord_event(discord_message: &DiscordMessage) -> nostr_bot::E
 
     nostr_bot::EventNonSigned {
         created_at: utils::unix_timestamp(),
-        tags: vec![],
+        tags: get_tags(&discord_message.message, &discord_message.link, "web"),
         kind: 1,
         content: discord_message.message.clone(),
     }
@@ -128,48 +148,102 @@ pub async fn get_rss_event(item: &RSSItem) -> nostr_bot::EventNonSigned {
 
     nostr_bot::EventNonSigned {
         created_at: utils::unix_timestamp(),
-        tags: vec![],
+        tags: get_tags(&item.description, &item.link, "rss"),
         kind: 1,
-        content: format!(
-            "{}",
-            item.description
-        ),
+        content: item.description.clone(),
     }
 }
 
-pub async fn get_pic_url(feed_url: &String) -> String {
-    let content = match reqwest::get(feed_url).await {
-        Ok(response) => response.bytes().await,
-        Err(err) => {
-            info!("Failed to fetch RSS feed from URL {}: {}", feed_url, err);
-            return "".to_string();
+// Hashtag has to start a word, "&#39;" or "a#b" are not hashtags
+static HASHTAG_REGEX: std::sync::LazyLock<regex::Regex> = std::sync::LazyLock::new(|| regex::Regex::new(r"(?:^|[^\w&#])#(\w+)").unwrap());
+
+// Returns "t" tags for hashtags and "r" tags for links found in the content, plus a "source" tag
+// pointing at the original tweet/message so relays and clients can filter and trace the note.
+// The NIP-48 "proxy" tag marks the note as bridged over `protocol` rather than written on nostr,
+// "rss" for feed items and "web" for Discord messages
+pub fn get_tags(content: &str, source_link: &str, protocol: &str) -> Vec<Vec<String>> {
+    let mut finder = linkify::LinkFinder::new();
+    finder.kinds(&[linkify::LinkKind::Url]);
+
+    let mut links: Vec<String> = vec![];
+    let mut text_without_links = String::new();
+    for span in finder.spans(content) {
+        match span.kind() {
+            Some(_) => {
+                if !links.iter().any(|link| link == span.as_str()) {
+                    links.push(span.as_str().to_string());
+                }
+                text_without_links.push(' ');
+            }
+            None => text_without_links.push_str(span.as_str()),
         }
-    };
+    }
 
-    let content_bytes = match content {
-        Ok(bytes) => bytes,
-        Err(err) => {
-            info!("Failed to fetch content for RSS feed from URL {}: {}", feed_url, err);
-            return "".to_string();
+    let mut hashtags: Vec<String> = vec![];
+    for capture in HASHTAG_REGEX.captures_iter(&text_without_links) {
+        let hashtag = capture[1].to_lowercase();
+        // Skip things like "#1"
+        if hashtag.chars().all(|c| c.is_ascii_digit()) || hashtags.contains(&hashtag) {
+            continue;
         }
-    };
+        hashtags.push(hashtag);
+    }
 
-    let content_str = match String::from_utf8(content_bytes.to_vec()) {
-        Ok(string) => string,
+    let mut tags = vec![];
+    for hashtag in hashtags {
+        tags.push(vec!["t".to_string(), hashtag]);
+    }
+    for link in links {
+        tags.push(vec!["r".to_string(), link]);
+    }
+    if !source_link.is_empty() {
+        tags.push(vec!["source".to_string(), source_link.to_string()]);
+        tags.push(vec!["proxy".to_string(), source_link.to_string(), protocol.to_string()]);
+    }
+
+    tags
+}
+
+#[derive(Default)]
+pub struct Profile {
+    pub picture: String,
+    pub about: String,
+    pub display_name: String,
+    pub banner: String,
+}
+
+// Profile of a Twitter account from its Nitter feed, fields are empty when no instance serves it
+pub async fn get_profile(nitter: &NitterPool, handle: &str) -> Profile {
+    match nitter.get_feed(handle).await {
+        Ok((channel, feed_url)) => profile_from_channel(&channel, &feed_url),
         Err(err) => {
-            info!("Failed to parse content for RSS feed from URL {}: {}", feed_url, err);
-            return "".to_string();
+            info!("Unable to get profile of {}: {}", handle, err);
+            Profile::default()
         }
-    };
+    }
+}
 
-    let channel = match Channel::read_from(content_str.as_bytes()) {
-        Ok(channel) => channel,
+// Profile of a plain RSS feed
+pub async fn get_rss_profile(feeds: &FeedClient, feed_url: &str) -> Profile {
+    match feeds.get_feed(feed_url).await {
+        Ok(channel) => profile_from_channel(&channel, feed_url),
         Err(err) => {
-            info!("Failed to parse RSS feed from URL {}: {}", feed_url, err);
-            return "".to_string();
+            info!("Unable to get profile of {}: {}", feed_url, err);
+            Profile::default()
         }
-    };
+    }
+}
+
+fn profile_from_channel(channel: &Channel, feed_url: &str) -> Profile {
+    Profile {
+        picture: get_pic_url(channel, feed_url),
+        about: get_about(channel, feed_url),
+        display_name: get_display_name(channel, feed_url),
+        banner: get_banner_link(channel),
+    }
+}
 
+fn get_pic_url(channel: &Channel, feed_url: &str) -> String {
     if let Some(image) = channel.image() {
         let pic_url = image.url().to_string();
 
@@ -183,63 +257,25 @@ pub async fn get_pic_url(feed_url: &String) -> String {
     "".to_string()
 }
 
-pub async fn get_banner_link(feed_url: &str) -> String {
-    let content = match reqwest::get(feed_url).await {
-        Ok(response) => match response.bytes().await {
-            Ok(bytes) => bytes,
-            Err(err) => {
-                info!("Failed to fetch content for RSS feed from URL {}: {}", feed_url, err);
-                return "".to_string();
-            }
-        },
-        Err(err) => {
-            info!("Failed to fetch RSS feed from URL {}: {}", feed_url, err);
-            return "".to_string();
-        }
-    };
-
-    let content_str = match String::from_utf8(content.to_vec()) {
-        Ok(string) => string,
-        Err(err) => {
-            info!("Failed to parse content for RSS feed from URL {}: {}", feed_url, err);
-            return "".to_string();
-        }
-    };
-
-    let channel = match Channel::read_from(content_str.as_bytes()) {
-        Ok(channel) => channel,
-        Err(err) => {
-            info!("Failed to parse RSS feed from URL {}: {}", feed_url, err);
-            return "".to_string();
-        }
-    };
-
-    return channel.link().to_string();
+fn get_banner_link(channel: &Channel) -> String {
+    channel.link().to_string()
 }
 
-
-pub async fn get_about(feed_url: &String) -> String {
-    let content = reqwest::get(feed_url).await.unwrap().bytes().await.unwrap();
-    let channel = Channel::read_from(&content[..]).unwrap();
-
-    // Get the channel title
+fn get_about(channel: &Channel, feed_url: &str) -> String {
     let about = channel.description().to_string();
 
     let strippedabout = remove_about_html_tags(&about);
 
     if !about.is_empty() {
         debug!("Found about {} for {}", strippedabout, feed_url);
-        return strippedabout.to_string();
+        strippedabout.to_string()
     } else {
         info!("Unable to find about for {}", feed_url);
         "".to_string()
     }
 }
 
-pub async fn get_display_name(feed_url: &String) -> String {
-    let content = reqwest::get(feed_url).await.unwrap().bytes().await.unwrap();
-    let channel = Channel::read_from(&content[..]).unwrap();
-
+fn get_display_name(channel: &Channel, feed_url: &str) -> String {
     // Get the channel title
     let title = channel.title().to_string();
 
@@ -248,7 +284,7 @@ pub async fn get_display_name(feed_url: &String) -> String {
 
     if !display_name.is_empty() {
         debug!("Found display name {} for {}", display_name, feed_url);
-        return display_name.to_string();
+        display_name.to_string()
     } else {
         info!("Unable to find display name for {}", feed_url);
         "".to_string()
@@ -256,50 +292,23 @@ pub async fn get_display_name(feed_url: &String) -> String {
 }
 
 pub async fn get_new_rss_items(
-    feed_url: &str,
+    nitter: &NitterPool,
+    handle: &str,
     since: &chrono::DateTime<chrono::offset::Utc>,
     until: &chrono::DateTime<chrono::offset::Utc>,
-) -> Result<Vec<RSSItem>, String> {
+    link_frontend: &str,
+) -> error::Result<Vec<RSSItem>> {
+    let (channel, feed_url) = nitter.get_feed(handle).await?;
+    let nitter_host = nitter_host(&feed_url);
 
-    let feed = match reqwest::get(feed_url).await {
-        Ok(response) => response,
-        Err(err) => return Err(format!("Failed to fetch RSS feed: {}", err)),
-    };
-
-    let body = match feed.text().await {
-        Ok(body) => body,
-        Err(err) => return Err(format!("Failed to read RSS feed response: {}", err)),
-    };
-
-    let channel = match Channel::read_from(body.as_bytes()) {
-        Ok(channel) => channel,
-        Err(err) => return Err(format!("Failed to parse RSS feed: {}", err)),
-    };
-
-    let items = channel.into_items();
-
-    let new_items_stream = futures::stream::iter(
-        items.into_iter().filter_map(move |item| {
-            let pub_date = item
-                .pub_date()
-                .and_then(|pub_date| chrono::DateTime::parse_from_rfc2822(pub_date).ok())
-                .map(|datetime| datetime.with_timezone(&chrono::Utc))
-                .unwrap_or_else(|| chrono::DateTime::from_utc(chrono::NaiveDateTime::from_timestamp_opt(0, 0).unwrap_or_else(|| chrono::NaiveDateTime::from_timestamp_opt(0, 0).unwrap_or_else(|| {panic!("Invalid timestamp");})), chrono::Utc));
-
-            if pub_date > *since && pub_date <= *until {
-                Some(item)
-            } else {
-                None
-            }
-        })
-    ).then(|item| async move {
-        let description = item.description().unwrap_or_default();
-        let titletest = "title";
+    let items = items_between(channel.into_items(), since, until);
 
+    let nitter_host = &nitter_host;
+    let new_items_stream = futures::stream::iter(items).then(|item| async move {
         // Fetch the linked page and find the video link (if any)
         let video_link = match item.link() {
             Some(link) => {
-                match find_video_link(link).await {
+                match find_video_link(nitter.feeds(), link).await {
                     Ok(video_link) => video_link.to_owned(),
                     Err(err) => {
                         info!("Error finding video link: {}", err);
@@ -310,38 +319,395 @@ pub async fn get_new_rss_items(
             None => String::new() // handle the case where link is None
         };
 
-        let video_link_found = !video_link.is_empty();
-        // Pass the video_link_found boolean to the remove_html_tags function
-        let stripped_description = remove_html_tags(&description, video_link_found);
-
-        // Append the video link to the description
-        let description_with_video = format!("{}\n\n{}", stripped_description, video_link);
-
-        RSSItem {
-            timestamp: item
-                .pub_date()
-                .and_then(|pub_date| chrono::DateTime::parse_from_str(pub_date, "%a, %d %b %Y %H:%M:%S GMT").ok())
-                .map(|datetime| datetime.with_timezone(&chrono::Utc))
-                .unwrap_or_else(|| chrono::DateTime::from_utc(chrono::NaiveDateTime::from_timestamp_opt(0, 0).unwrap_or_else(|| { chrono::NaiveDateTime::from_timestamp_opt(0, 0).unwrap_or_else(||{ panic!("Invalid timestamp");}) }), chrono::Utc)),
-            title: titletest.to_string(),
-            description: description_with_video,
-            link: item.link().unwrap_or_default().to_string(),
-        }
+        convert_rss_item(&item, &video_link, nitter_host, link_frontend)
     });
 
     let new_items: Vec<_> = new_items_stream.collect().await;
     Ok(new_items)
 }
 
+// New items of a plain (non Nitter) RSS feed, links are kept as they are
+pub async fn get_new_feed_items(
+    feeds: &FeedClient,
+    feed_url: &str,
+    since: &chrono::DateTime<chrono::offset::Utc>,
+    until: &chrono::DateTime<chrono::offset::Utc>,
+) -> error::Result<Vec<RSSItem>> {
+    let channel = feeds.get_feed(feed_url).await?;
+    Ok(items_between(channel.into_items(), since, until)
+        .iter()
+        .map(|item| convert_rss_item(item, "", "", ""))
+        .collect())
+}
+
+pub type Feeds = Arc<FeedClient>;
 
-// Helper function to find the video link on the linked page
-async fn find_video_link(link: &str) -> Result<String, reqwest::Error> {
-    // Create a reqwest client
-    let client = Client::new();
+pub type FeedCache = std::sync::Mutex<HashMap<String, CachedFeed>>;
+
+pub struct CachedFeed {
+    etag: Option<String>,
+    last_modified: Option<String>,
+    body: Vec<u8>,
+}
+
+// HTTP client shared by all feed fetches. Feeds are requested with the ETag / Last-Modified of
+// the previous response, so an unchanged feed costs a 304 and is read from the cache.
+pub struct FeedClient {
+    client: Client,
+    cache: FeedCache,
+    host_limits: Option<HostLimits>,
+}
+
+impl FeedClient {
+    pub fn new(client: Client) -> FeedClient {
+        FeedClient {
+            client,
+            cache: std::sync::Mutex::new(HashMap::new()),
+            host_limits: None,
+        }
+    }
+
+    pub fn from_config(config: &utils::Config) -> FeedClient {
+        FeedClient::new(utils::http_client(config)).with_host_limits(HostLimits::from_config(config))
+    }
+
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    pub fn with_host_limits(mut self, host_limits: HostLimits) -> FeedClient {
+        self.host_limits = Some(host_limits);
+        self
+    }
+
+    async fn host_permit(&self, url: &str) -> Option<HostPermit> {
+        match &self.host_limits {
+            Some(host_limits) => Some(host_limits.acquire(url).await),
+            None => None,
+        }
+    }
+
+    // Body of a page linked from a feed
+    pub async fn get_page(&self, url: &str) -> error::Result<String> {
+        let _permit = self.host_permit(url).await;
+        Ok(self.client.get(url).send().await?.error_for_status()?.text().await?)
+    }
+
+    pub async fn get_feed(&self, feed_url: &str) -> error::Result<Channel> {
+        let mut request = self.client.get(feed_url);
+        if let Some(cached) = self.cache.lock().unwrap().get(feed_url) {
+            if let Some(etag) = &cached.etag {
+                request = request.header(reqwest::header::IF_NONE_MATCH, etag);
+            }
+            if let Some(last_modified) = &cached.last_modified {
+                request = request.header(reqwest::header::IF_MODIFIED_SINCE, last_modified);
+            }
+        }
+
+        let _permit = self.host_permit(feed_url).await;
+        let feed = match request.send().await {
+            Ok(response) if response.status() == reqwest::StatusCode::TOO_MANY_REQUESTS => {
+                return Err(Error::from_status(response.status(), response.headers(), String::new()));
+            }
+            Ok(response) => response.error_for_status()?,
+            Err(err) => return Err(err.into()),
+        };
+
+        if feed.status() == reqwest::StatusCode::NOT_MODIFIED {
+            debug!("Feed {} not modified, using the cached response", feed_url);
+            let cache = self.cache.lock().unwrap();
+            return match cache.get(feed_url) {
+                Some(cached) => parse_feed(&cached.body),
+                None => Err(Error::Network(format!("Got 304 Not Modified for {}, which isn't cached", feed_url))),
+            };
+        }
+
+        let header = |name| {
+            feed.headers()
+                .get(name)
+                .and_then(|value: &reqwest::header::HeaderValue| value.to_str().ok())
+                .map(String::from)
+        };
+        let etag = header(reqwest::header::ETAG);
+        let last_modified = header(reqwest::header::LAST_MODIFIED);
+
+        let body = feed.bytes().await?;
+
+        let channel = parse_feed(&body)?;
+
+        let mut cache = self.cache.lock().unwrap();
+        if etag.is_some() || last_modified.is_some() {
+            cache.insert(
+                feed_url.to_string(),
+                CachedFeed {
+                    etag,
+                    last_modified,
+                    body: body.to_vec(),
+                },
+            );
+        } else {
+            cache.remove(feed_url);
+        }
+
+        Ok(channel)
+    }
+}
+
+fn parse_feed(body: &[u8]) -> error::Result<Channel> {
+    Ok(Channel::read_from(body)?)
+}
+
+// Items published after `since` up to `until`
+fn items_between(
+    items: Vec<rss::Item>,
+    since: &chrono::DateTime<chrono::offset::Utc>,
+    until: &chrono::DateTime<chrono::offset::Utc>,
+) -> Vec<rss::Item> {
+    items.into_iter().filter(|item| {
+        let pub_date = item
+            .pub_date()
+            .and_then(|pub_date| chrono::DateTime::parse_from_rfc2822(pub_date).ok())
+            .map(|datetime| datetime.with_timezone(&chrono::Utc))
+            .unwrap_or_else(|| std::time::UNIX_EPOCH.into());
+
+        pub_date > *since && pub_date <= *until
+    }).collect()
+}
+
+
+// Turns an item of a Nitter RSS feed into RSSItem with plain text description, `video_link` is
+// the video found on the tweet's page (empty if there is none)
+pub fn convert_rss_item(item: &rss::Item, video_link: &str, nitter_host: &str, link_frontend: &str) -> RSSItem {
+    let description = item.description().unwrap_or_default();
+    let titletest = "title";
+
+    let video_link_found = !video_link.is_empty();
+    // Pass the video_link_found boolean to the html_to_text function
+    let stripped_description = html_to_text(description, video_link_found, nitter_host);
+
+    // Append the video link to the description
+    let description_with_video = if video_link_found {
+        format!("{}\n\n{}", stripped_description, video_link)
+    } else {
+        stripped_description
+    };
+    let description_with_video = rewrite_nitter_links(&description_with_video, nitter_host, link_frontend);
+
+    RSSItem {
+        timestamp: item
+            .pub_date()
+            .and_then(|pub_date| chrono::DateTime::parse_from_str(pub_date, "%a, %d %b %Y %H:%M:%S GMT").ok())
+            .map(|datetime| datetime.with_timezone(&chrono::Utc))
+            .unwrap_or_else(|| std::time::UNIX_EPOCH.into()),
+        title: titletest.to_string(),
+        description: description_with_video,
+        link: rewrite_nitter_links(item.link().unwrap_or_default(), nitter_host, link_frontend),
+    }
+}
+
+// Host (with port if there is one) of the Nitter instance serving the feed
+pub fn nitter_host(feed_url: &str) -> String {
+    Url::parse(feed_url)
+        .ok()
+        .and_then(|url| host_with_port(&url))
+        .unwrap_or_default()
+}
+
+fn host_with_port(url: &Url) -> Option<String> {
+    let host = url.host_str()?;
+    match url.port() {
+        Some(port) => Some(format!("{}:{}", host, port)),
+        None => Some(host.to_string()),
+    }
+}
+
+// Rewrites links pointing to the Nitter instance to canonical Twitter links, or to `frontend`
+// host if it's something else than twitter.com/x.com, so the links keep working after
+// the instance changes or dies. Empty `frontend` leaves the links untouched.
+pub fn rewrite_nitter_links(text: &str, nitter_host: &str, frontend: &str) -> String {
+    if frontend.is_empty() || nitter_host.is_empty() {
+        return text.to_string();
+    }
+
+    let mut finder = linkify::LinkFinder::new();
+    finder.kinds(&[linkify::LinkKind::Url]);
+
+    let mut result = String::new();
+    for span in finder.spans(text) {
+        match span.kind() {
+            Some(_) => match rewrite_nitter_link(span.as_str(), nitter_host, frontend) {
+                Some(rewritten) => result.push_str(&rewritten),
+                None => result.push_str(span.as_str()),
+            },
+            None => result.push_str(span.as_str()),
+        }
+    }
+    result
+}
+
+fn rewrite_nitter_link(link: &str, nitter_host: &str, frontend: &str) -> Option<String> {
+    let url = Url::parse(link).ok()?;
+    let host = host_with_port(&url)?;
+    if !host.eq_ignore_ascii_case(nitter_host) {
+        return None;
+    }
+
+    let canonical = frontend == "twitter.com" || frontend == "x.com";
+    let path = url.path();
+
+    if canonical && (path.starts_with("/pic/") || path.starts_with("/video/")) {
+        return canonical_media_link(path);
+    }
+
+    let mut rewritten = format!("https://{}{}", frontend, path);
+    if let Some(query) = url.query() {
+        rewritten.push('?');
+        rewritten.push_str(query);
+    }
+    // Nitter adds "#m" to status links
+    if let Some(fragment) = url.fragment() {
+        if fragment != "m" {
+            rewritten.push('#');
+            rewritten.push_str(fragment);
+        }
+    }
+    Some(rewritten)
+}
+
+// Nitter proxies media as /pic/[orig/]<encoded path> and /video/<signature>/<encoded url>,
+// newer versions base64 encode the path as /pic/enc/<base64> and /video/enc/<signature>/<base64>
+fn canonical_media_link(path: &str) -> Option<String> {
+    let (is_video, rest) = match path.strip_prefix("/pic/") {
+        Some(rest) => (false, rest),
+        None => (true, path.strip_prefix("/video/")?),
+    };
+
+    let (encoded, rest) = match rest.strip_prefix("enc/") {
+        Some(rest) => (true, rest),
+        None => (false, rest),
+    };
+
+    let rest = if is_video {
+        // Skip the signature
+        rest.split_once('/')?.1
+    } else {
+        rest.strip_prefix("orig/").unwrap_or(rest)
+    };
+
+    let decoded = if encoded {
+        use base64::Engine;
+        let bytes = base64::engine::general_purpose::URL_SAFE_NO_PAD
+            .decode(rest.trim_end_matches('='))
+            .ok()?;
+        String::from_utf8(bytes).ok()?
+    } else {
+        percent_encoding::percent_decode_str(rest).decode_utf8().ok()?.to_string()
+    };
+
+    if decoded.starts_with("http://") || decoded.starts_with("https://") {
+        Some(decoded)
+    } else if decoded.starts_with("pbs.twimg.com/") || decoded.starts_with("video.twimg.com/") {
+        Some(format!("https://{}", decoded))
+    } else if is_video {
+        Some(format!("https://video.twimg.com/{}", decoded))
+    } else {
+        Some(format!("https://pbs.twimg.com/{}", decoded))
+    }
+}
+
+// The cache forgets the oldest links when it grows over this many entries
+const MAX_CACHED_LINKS: usize = 10000;
+
+pub fn new_link_cache() -> LinkCache {
+    Arc::new(std::sync::Mutex::new(utils::BoundedCache::new(MAX_CACHED_LINKS)))
+}
+
+impl LinkExpander {
+    // Returns None when there are no shortener domains configured
+    pub fn from_config(config: &utils::Config, cache: LinkCache) -> Option<LinkExpander> {
+        if config.unshorten_domains.is_empty() {
+            return None;
+        }
 
+        let client = utils::http_client_builder(config)
+            .redirect(reqwest::redirect::Policy::limited(10))
+            .timeout(std::time::Duration::from_secs(config.unshorten_timeout_secs))
+            .build()
+            .ok()?;
+
+        Some(LinkExpander {
+            domains: config.unshorten_domains.clone(),
+            client,
+            cache,
+        })
+    }
+
+    fn is_short_link(&self, link: &str) -> bool {
+        match Url::parse(link) {
+            Ok(url) => {
+                let host = url.host_str().unwrap_or_default().to_lowercase();
+                let host = host.strip_prefix("www.").unwrap_or(&host);
+                self.domains.iter().any(|domain| domain == host)
+            }
+            Err(_) => false,
+        }
+    }
+
+    // Returns the final URL after following redirects, the link itself if that fails. Failures
+    // aren't cached, the next item tries again.
+    async fn expand(&self, link: &str) -> String {
+        let cached = self.cache.lock().unwrap().get(link).cloned();
+        if let Some(expanded) = cached {
+            return expanded;
+        }
+
+        // Some shorteners don't support HEAD, try GET then
+        let response = match self.client.head(link).send().await {
+            Ok(response) if response.status().is_success() => Ok(response),
+            _ => self.client.get(link).send().await,
+        };
+
+        match response {
+            Ok(response) => {
+                debug!("Expanded {} to {}", link, response.url());
+                let expanded = response.url().to_string();
+                self.cache.lock().unwrap().insert(link.to_string(), expanded.clone());
+                expanded
+            }
+            Err(err) => {
+                info!("Unable to expand {}: {}", link, err);
+                link.to_string()
+            }
+        }
+    }
+}
+
+// Replaces links from shortener domains (t.co, bit.ly...) with the links they redirect to
+pub async fn expand_short_links(text: &str, expander: &LinkExpander) -> String {
+    let mut finder = linkify::LinkFinder::new();
+    finder.kinds(&[linkify::LinkKind::Url]);
+
+    // Spans can't be held across await
+    let spans = finder
+        .spans(text)
+        .map(|span| (span.kind().is_some(), span.as_str().to_string()))
+        .collect::<Vec<_>>();
+
+    let mut result = String::new();
+    for (is_link, span) in spans {
+        if is_link && expander.is_short_link(&span) {
+            result.push_str(&expander.expand(&span).await);
+        } else {
+            result.push_str(&span);
+        }
+    }
+    result
+}
+
+// Helper function to find the video link on the linked page
+async fn find_video_link(feeds: &FeedClient, link: &str) -> error::Result<String> {
     // Send a GET request to the link and fetch the HTML content
-    let response = client.get(link).send().await?;
-    let body = response.text().await?;
+    let body = feeds.get_page(link).await?;
 
     // Parse the HTML content using scraper
     let fragment = Html::parse_document(&body);
@@ -360,86 +726,173 @@ async fn find_video_link(link: &str) -> Result<String, reqwest::Error> {
     Ok(String::new())
 }
 
-// Function to remove HTML tags using a regular expression
-fn remove_html_tags(description: &str, video_link_found: bool) -> String {
+// Renders HTML description from Nitter as plain text. Entities are decoded, <br> and <p> keep
+// their line breaks and links and media are inlined where they appeared.
+// When a video was found, images are left out since they are just its thumbnails.
+fn html_to_text(description: &str, video_link_found: bool, nitter_host: &str) -> String {
     let fragment = Html::parse_fragment(description);
-    let img_selector = Selector::parse("img").unwrap();
-    let a_selector = Selector::parse("a").unwrap();
-    let video_selector = Selector::parse("source").unwrap();
-
-    let mut text_parts = Vec::new();
-    let mut links = HashMap::new();
-    let mut excluded_links = HashSet::new();
-
-    for element in fragment.select(&img_selector) {
-        if let Some(link) = element.value().attr("src") {
-            let normalized_link = normalize_link(link);
-            if !links.contains_key(&normalized_link) {
-                links.insert(normalized_link, link.to_string());
+
+    let mut renderer = TextRenderer {
+        description,
+        video_link_found,
+        nitter_host,
+        text: String::new(),
+        media: HashSet::new(),
+    };
+    renderer.render_children(fragment.root_element());
+    renderer.finish()
+}
+
+struct TextRenderer<'a> {
+    description: &'a str,
+    video_link_found: bool,
+    nitter_host: &'a str,
+    text: String,
+    media: HashSet<String>,
+}
+
+impl<'a> TextRenderer<'a> {
+    fn render_children(&mut self, element: ElementRef) {
+        for child in element.children() {
+            match child.value() {
+                Node::Text(text) => self.push_text(text),
+                Node::Element(_) => {
+                    if let Some(child) = ElementRef::wrap(child) {
+                        self.render_element(child);
+                    }
+                }
+                _ => {}
             }
         }
     }
 
-    for element in fragment.select(&a_selector) {
-        if let Some(link) = element.value().attr("href") {
-            let normalized_link = normalize_link(link);
-            // Check if the link is the same as the text of the <a> tag
-            let text = element.inner_html();
-            if normalize_link(&text) == normalized_link {
-                excluded_links.insert(normalized_link.clone());
+    fn render_element(&mut self, element: ElementRef) {
+        match element.value().name() {
+            "br" => self.text.push('\n'),
+            "p" | "div" | "blockquote" | "li" => {
+                self.text.push_str("\n\n");
+                self.render_children(element);
+                self.text.push_str("\n\n");
+            }
+            "hr" => self.text.push_str("\n\n"),
+            "a" => self.render_link(element),
+            "img" => {
+                if !self.video_link_found {
+                    if let Some(src) = element.value().attr("src") {
+                        self.push_media(src);
+                    }
+                }
             }
-            if contains_profile_link(&normalized_link, description) || contains_search_link(&normalized_link) {
-                excluded_links.insert(normalized_link.clone());
-            } else if !links.contains_key(&normalized_link) {
-                links.insert(normalized_link.clone(), normalize_link(link));
+            "video" | "source" => {
+                if let Some(src) = element.value().attr("src") {
+                    self.push_media(src);
+                }
+                self.render_children(element);
             }
+            "script" | "style" => {}
+            _ => self.render_children(element),
         }
     }
 
-    for element in fragment.select(&video_selector) {
-        if let Some(link) = element.value().attr("src") {
-            let normalized_link = normalize_link(link);
-            if !links.contains_key(&normalized_link) {
-                links.insert(normalized_link.clone(), normalize_link(link));
+    fn render_link(&mut self, element: ElementRef) {
+        let text = collapse_whitespace(&element.text().collect::<String>());
+        let text = text.trim();
+        let href = element.value().attr("href").unwrap_or_default();
+
+        // Mentions and hashtags stay as they are, without the link to Nitter
+        if href.is_empty() || text.starts_with('@') || text.starts_with('#') || contains_search_link(href) {
+            self.text.push_str(text);
+            return;
+        }
+
+        let link = match normalize_link(href) {
+            Ok(link) => link,
+            Err(err) => {
+                debug!("Keeping link as it is: {}", err);
+                href.to_string()
             }
+        };
+        if text.is_empty() || is_link_text(text, &link) {
+            self.text.push_str(&link);
+        } else if contains_profile_link(&link, self.description, self.nitter_host) {
+            self.text.push_str(text);
+        } else {
+            self.text.push_str(&format!("{} ({})", text, link));
         }
     }
 
-    // Remove HTML tags from the text parts
-    let re = regex::Regex::new(r"<[^>]*>").unwrap();
-    let text = re.replace_all(description, "").to_string();
-    text_parts.push(text);
+    fn push_text(&mut self, text: &str) {
+        self.text.push_str(&collapse_whitespace(text));
+    }
 
-    // Combine the text and unique media parts
-    let mut result = text_parts.join(" ");
-    for (normalized_link, original_link) in &links {
-        if !excluded_links.contains(normalized_link) {
-            // Check if a video link was found and the current link is an image link
-            if !(video_link_found && original_link.starts_with("https://")) {
-                result.push_str(" ");
-                result.push_str(original_link);
+    fn push_media(&mut self, src: &str) {
+        if self.media.insert(normalize_link(src).unwrap_or_else(|_| src.to_string())) {
+            self.text.push('\n');
+            self.text.push_str(src);
+            self.text.push('\n');
+        }
+    }
+
+    // Trims the lines and keeps at most one empty line between paragraphs
+    fn finish(self) -> String {
+        let mut lines: Vec<&str> = vec![];
+        for line in self.text.lines().map(|line| line.trim()) {
+            if line.is_empty() && lines.last().is_none_or(|last| last.is_empty()) {
+                continue;
             }
+            lines.push(line);
+        }
+        while lines.last().is_some_and(|last| last.is_empty()) {
+            lines.pop();
         }
+        lines.join("\n")
     }
+}
 
+// Whitespace in HTML text is not significant, line breaks come from the tags
+fn collapse_whitespace(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut last_was_whitespace = false;
+    for c in text.chars() {
+        if c.is_whitespace() {
+            if !last_was_whitespace {
+                result.push(' ');
+            }
+            last_was_whitespace = true;
+        } else {
+            result.push(c);
+            last_was_whitespace = false;
+        }
+    }
     result
 }
 
+// Nitter shows links without the scheme and shortens the long ones
+fn is_link_text(text: &str, link: &str) -> bool {
+    let strip = |s: &str| {
+        let s = s.trim();
+        let s = s.strip_prefix("https://").or_else(|| s.strip_prefix("http://")).unwrap_or(s);
+        let s = s.strip_prefix("www.").unwrap_or(s);
+        s.trim_end_matches('…').trim_end_matches("...").trim_end_matches('/').to_lowercase()
+    };
+    let text = strip(text);
+    !text.is_empty() && strip(link).starts_with(&text)
+}
 
-fn normalize_link(link: &str) -> String {
+fn normalize_link(link: &str) -> error::Result<String> {
     let url = Url::parse(link);
     match url {
         Ok(mut url) => {
-            url.set_scheme("https").unwrap();
-            url.to_string()
+            url.set_scheme("https")
+                .map_err(|_| Error::Parse(format!("Link {} can't use https", link)))?;
+            Ok(url.to_string())
         },
-        Err(UrlParseError::RelativeUrlWithoutBase) => format!("https://{}", link),
-        Err(e) => panic!("URL parsing error: {}", e),
+        Err(UrlParseError::RelativeUrlWithoutBase) => Ok(format!("https://{}", link)),
+        Err(e) => Err(Error::Parse(format!("Invalid link {}: {}", link, e))),
     }
 }
 
-fn contains_profile_link(link: &str, description: &str) -> bool {
-    let config = utils::parse_config();
+fn contains_profile_link(link: &str, description: &str, nitter_host: &str) -> bool {
 
     // Check for @mentions in hyperlinks
     let hyperlink_username_regex = regex::Regex::new(r"<a[^>]*>@(\w+)</a>").unwrap();
@@ -462,9 +915,9 @@ fn contains_profile_link(link: &str, description: &str) -> bool {
     all_referenced_usernames.iter().any(|username| {
         let lower_username = username.to_lowercase();
         let username_link1 = format!("/{}", lower_username);
-        let username_link2 = format!("https://{}/{}", config.nitter_instance, lower_username);
+        let username_link2 = format!("https://{}/{}", nitter_host, lower_username);
 
-        if link == &username_link2 {
+        if link == username_link2 {
             return false;
         }
   
@@ -490,4 +943,6 @@ fn remove_about_html_tags(description: &str) -> String {
     let text_without_at_symbols = re_at_symbols.replace_all(&text_without_newlines, "").to_string().trim().to_string();
 
     text_without_at_symbols
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,240 @@
+// Golden-file tests for turning Nitter RSS items into notes.
+//
+// Every directory in tests/fixtures/feeds holds a case:
+// - feed.xml      Nitter RSS feed
+// - videos.json   (optional) item link -> video link, stands in for find_video_link
+// - expected.json notes (content and tags) the items should be turned into
+//
+// Run `UPDATE_GOLDEN=1 cargo test` to regenerate expected.json files after an intended change
+// and review the diff.
+
+use super::*;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+const LINK_FRONTEND: &str = "x.com";
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct Note {
+    content: String,
+    tags: Vec<Vec<String>>,
+}
+
+fn fixtures_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/feeds")
+}
+
+async fn convert_feed(case_dir: &Path) -> Vec<Note> {
+    let feed = std::fs::read_to_string(case_dir.join("feed.xml")).unwrap();
+    let channel = Channel::read_from(feed.as_bytes()).unwrap();
+
+    let videos_path = case_dir.join("videos.json");
+    let videos: HashMap<String, String> = if videos_path.exists() {
+        serde_json::from_str(&std::fs::read_to_string(videos_path).unwrap()).unwrap()
+    } else {
+        HashMap::new()
+    };
+
+    let nitter_host = nitter_host(channel.link());
+
+    let mut notes = vec![];
+    for item in channel.items() {
+        let video_link = videos
+            .get(item.link().unwrap_or_default())
+            .cloned()
+            .unwrap_or_default();
+        let rss_item = convert_rss_item(item, &video_link, &nitter_host, LINK_FRONTEND);
+        let event = get_rss_event(&rss_item).await;
+        notes.push(Note {
+            content: event.content,
+            tags: event.tags,
+        });
+    }
+    notes
+}
+
+#[tokio::test]
+async fn golden_feeds() {
+    let update = std::env::var("UPDATE_GOLDEN").is_ok();
+
+    let mut case_dirs = std::fs::read_dir(fixtures_dir())
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.is_dir())
+        .collect::<Vec<_>>();
+    case_dirs.sort();
+    assert!(!case_dirs.is_empty(), "No fixtures found in {:?}", fixtures_dir());
+
+    let mut failures = vec![];
+    for case_dir in case_dirs {
+        let notes = convert_feed(&case_dir).await;
+        let expected_path = case_dir.join("expected.json");
+
+        if update {
+            let json = serde_json::to_string_pretty(&notes).unwrap();
+            std::fs::write(&expected_path, json + "\n").unwrap();
+            continue;
+        }
+
+        let expected: Vec<Note> = serde_json::from_str(
+            &std::fs::read_to_string(&expected_path)
+                .unwrap_or_else(|_| panic!("Missing {:?}, run with UPDATE_GOLDEN=1", expected_path)),
+        )
+        .unwrap();
+
+        if notes != expected {
+            failures.push(format!(
+                "{:?}:\nexpected {:#?}\ngot {:#?}",
+                case_dir.file_name().unwrap(),
+                expected,
+                notes
+            ));
+        }
+    }
+
+    assert!(failures.is_empty(), "{}", failures.join("\n\n"));
+}
+
+#[test]
+fn html_to_text_decodes_entities_and_keeps_line_breaks() {
+    let text = html_to_text("<p>a &amp; b<br>c &lt;3</p><p>d</p>", false, "nitter.net");
+    assert_eq!(text, "a & b\nc <3\n\nd");
+}
+
+#[test]
+fn html_to_text_skips_thumbnails_of_videos() {
+    let description = r#"<p>clip</p><img src="https://nitter.net/pic/media%2Fthumb.jpg" />"#;
+    assert_eq!(html_to_text(description, true, "nitter.net"), "clip");
+    assert_eq!(
+        html_to_text(description, false, "nitter.net"),
+        "clip\n\nhttps://nitter.net/pic/media%2Fthumb.jpg"
+    );
+}
+
+#[test]
+fn normalize_link_uses_https() {
+    assert_eq!(normalize_link("http://example.com/a").unwrap(), "https://example.com/a");
+    assert_eq!(normalize_link("example.com/a").unwrap(), "https://example.com/a");
+    assert!(normalize_link("http://[::1").is_err());
+    assert!(normalize_link("mailto:nasa@example.com").is_err());
+}
+
+#[test]
+fn profile_links_of_mentioned_users() {
+    let description = r#"<a href="https://nitter.net/jack">@jack</a> said"#;
+    assert!(contains_profile_link("https://nitter.net/jack/status/20#m", description, "nitter.net"));
+    assert!(!contains_profile_link("https://nitter.net/jack", description, "nitter.net"));
+    assert!(!contains_profile_link("https://nitter.net/saylor/status/1#m", description, "nitter.net"));
+}
+
+#[test]
+fn rewrite_keeps_links_without_frontend() {
+    let text = "https://nitter.net/jack/status/20#m";
+    assert_eq!(rewrite_nitter_links(text, "nitter.net", ""), text);
+    assert_eq!(
+        rewrite_nitter_links(text, "nitter.net", "nitter.example"),
+        "https://nitter.example/jack/status/20"
+    );
+}
+
+#[test]
+fn tags_skip_numbers_and_entities() {
+    let tags = get_tags("#1 &#39; a#b #Nostr #nostr", "", "rss");
+    assert_eq!(tags, vec![vec!["t".to_string(), "nostr".to_string()]]);
+}
+
+#[tokio::test]
+async fn feed_client_reuses_unchanged_feeds() {
+    let mock = crate::testing::MockNitter::start().await;
+    let feeds = FeedClient::new(reqwest::Client::new());
+    let url = crate::nitter::feed_url(&mock.urls()[0], "nasa");
+    mock.post("nasa", "first");
+
+    assert_eq!(feeds.get_feed(&url).await.unwrap().items().len(), 1);
+    assert_eq!(feeds.get_feed(&url).await.unwrap().items().len(), 1);
+    assert_eq!(mock.not_modified(), 1);
+
+    mock.post("nasa", "second");
+    assert_eq!(feeds.get_feed(&url).await.unwrap().items().len(), 2);
+    assert_eq!(mock.not_modified(), 1);
+}
+
+#[tokio::test]
+async fn feed_client_reports_rate_limits() {
+    let mock = crate::testing::MockNitter::start().await;
+    let feeds = FeedClient::new(reqwest::Client::new());
+    let url = crate::nitter::feed_url(&mock.urls()[0], "nasa");
+    mock.set_rate_limited(0, 3);
+
+    match feeds.get_feed(&url).await {
+        Err(Error::RateLimited(retry_after)) => assert_eq!(retry_after, Some(std::time::Duration::from_secs(3))),
+        other => panic!("Expected a rate limit, got {:?}", other.map(|channel| channel.items().len())),
+    }
+}
+
+#[tokio::test]
+async fn tor_mode_fetches_feeds_through_the_proxy() {
+    let relay = crate::testing::MockRelay::start().await;
+    let mock = crate::testing::MockNitter::start().await;
+    // Stands in for the Tor SOCKS port, it only records that something connected
+    let proxy = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mut config = crate::testing::TestBot::config(&relay, &mock, &utils::get_random_keypair(), &utils::get_random_keypair(), &std::env::temp_dir());
+    config.use_tor = true;
+    config.tor_socks_address = proxy.local_addr().unwrap().to_string();
+
+    let connected = tokio::spawn(async move { proxy.accept().await.is_ok() });
+    let feeds = FeedClient::from_config(&config);
+    assert!(feeds.get_feed(&crate::nitter::feed_url(&mock.urls()[0], "nasa")).await.is_err());
+    assert!(connected.await.unwrap());
+    assert!(mock.requests().is_empty());
+}
+
+fn link_expander(mock: &crate::testing::MockShortener, timeout: std::time::Duration) -> LinkExpander {
+    LinkExpander {
+        domains: vec![url::Url::parse(&mock.url("/")).unwrap().host_str().unwrap().to_string()],
+        client: reqwest::Client::builder().timeout(timeout).build().unwrap(),
+        cache: new_link_cache(),
+    }
+}
+
+#[tokio::test]
+async fn short_links_follow_redirect_chains() {
+    let mock = crate::testing::MockShortener::start().await;
+    mock.redirect("/a", "/b");
+    mock.redirect("/b", "/article");
+    let expander = link_expander(&mock, std::time::Duration::from_secs(5));
+
+    let text = format!("Read {} now", mock.url("/a"));
+    assert_eq!(expand_short_links(&text, &expander).await, format!("Read {} now", mock.url("/article")));
+    assert_eq!(mock.requests(), vec!["/a", "/b", "/article"]);
+
+    // Expanded links come from the cache
+    assert_eq!(expand_short_links(&text, &expander).await, format!("Read {} now", mock.url("/article")));
+    assert_eq!(mock.requests().len(), 3);
+}
+
+#[tokio::test]
+async fn short_links_that_time_out_stay() {
+    let mock = crate::testing::MockShortener::start().await;
+    let expander = link_expander(&mock, std::time::Duration::from_millis(200));
+
+    let text = format!("Read {}", mock.url("/slow/a"));
+    assert_eq!(expand_short_links(&text, &expander).await, text);
+
+    // Failures aren't cached
+    let requests = mock.requests().len();
+    assert_eq!(expand_short_links(&text, &expander).await, text);
+    assert!(mock.requests().len() > requests);
+}
+
+#[tokio::test]
+async fn other_links_are_left_alone() {
+    let mock = crate::testing::MockShortener::start().await;
+    mock.redirect("/a", "/article");
+    let expander = link_expander(&mock, std::time::Duration::from_secs(5));
+
+    // Same server under another name than the shortener domain
+    let text = format!("Read {}", mock.url("/a").replace("127.0.0.1", "localhost"));
+    assert_eq!(expand_short_links(&text, &expander).await, text);
+    assert!(mock.requests().is_empty());
+}
@@ -1,16 +1,29 @@
 mod simpledb;
+mod alerts;
 mod dostr;
+mod dm;
+mod error;
 mod fetch;
 mod utils;
 mod nip5server;
+mod media;
+mod nip49;
+mod nitter;
+mod outbox;
+mod relays;
+mod schedule;
+mod scheduler;
+mod signer;
+#[cfg(test)]
+mod testing;
 
 use env_logger::Builder;
 use log::LevelFilter;
-use log::debug;
+use log::{debug, warn};
 use nostr_bot::FunctorType;
 use dostr::State;
 use fetch::Handler;
-use serenity::Client;
+use serenity::client::ClientBuilder;
 use tokio::sync::Mutex;
 use std::sync::Arc;
 use std::env;
@@ -31,10 +44,6 @@ async fn main() {
 
     let discord_context: Arc<Mutex<Option<Context>>> = Arc::new(Mutex::new(None));
 
-    let current_dir = env::current_dir().unwrap();
-    let db_file_path = current_dir.join("data/channels");
-    let db_client = Arc::new(Mutex::new(SimpleDatabase::from_file(db_file_path.to_string_lossy().to_string())));
-
     let args = std::env::args().collect::<Vec<String>>();
     if args.len() != 2 {
         println!("Usage: {} --clearnet|--tor", args[0]);
@@ -42,42 +51,89 @@ async fn main() {
     }
 
     // Instead of reading a configuration file, create the config directly from environment variables
-    let config = utils::parse_config();
+    let mut config = utils::parse_config();
+    config.use_tor = match args[1].as_str() {
+        "--clearnet" => false,
+        "--tor" => true,
+        _ => panic!("Incorrect network settings"),
+    };
     debug!("{:?}", config);
 
+    // The Discord handler and the Nostr bot share the database, the feed keys are decrypted once
+    let current_dir = env::current_dir().unwrap();
+    let db_file_path = current_dir.join("data/channels");
+    let db = match SimpleDatabase::from_file_encrypted(db_file_path.to_string_lossy().to_string(), simpledb::KeyEncryption::from_config(&config)) {
+        Ok(db) => Arc::new(std::sync::Mutex::new(db)),
+        Err(e) => {
+            log::error!("Unable to open {}: {}", db_file_path.display(), e);
+            std::process::exit(1);
+        }
+    };
+
     // Discord bot setup and start.
 
     let discord_token = &config.apik;
 
-    let mut discord_client = Client::builder(&discord_token)
+    // Discord API requests share the proxied client. The gateway websocket of serenity can't use
+    // a proxy, with --tor only the firewall of startup_tor.sh keeps it from leaking.
+    if config.use_tor {
+        warn!("The Discord gateway connection doesn't go through Tor");
+    }
+    let discord_token = if discord_token.starts_with("Bot ") {
+        discord_token.to_string()
+    } else {
+        format!("Bot {}", discord_token)
+    };
+    // Feed workers, the Discord handler and the health checks report to the alert listener
+    let (tx, rx) = tokio::sync::mpsc::channel::<alerts::ConnectionMessage>(64);
+
+    // Signs the Discord forwards only, nostr-bot signs command replies with `keypair` and alerts
+    // and !claim DMs need it to encrypt
+    let bot_signer = signer::Signer::from_config(&config).expect("The BUNKER_URL environment variable is not a valid bunker URI.");
+    let keypair = nostr_bot::keypair_from_secret(&config.secret);
+    let sender = nostr_bot::new_sender();
+
+    let feeds = Arc::new(fetch::FeedClient::from_config(&config));
+    let nitter = nitter::NitterPool::from_config(&config, feeds.clone());
+    let pool = relays::Relays::from_config(&config, current_dir.join("data/relays"));
+    pool.add_key(&keypair);
+    // Forwards signed by BUNKER_URL authenticate through it
+    if let signer::Signer::Remote(_) = bot_signer {
+        match bot_signer.public_key().await {
+            Ok(pubkey) => pool.add_signer(&pubkey, bot_signer.clone()),
+            Err(e) => warn!("Unable to get the key of the bunker at BUNKER_URL: {}", e),
+        }
+    }
+    let outbox = outbox::EventOutbox::from_config(&config, pool.clone(), current_dir.join("data/outbox"));
+
+    let discord_http = serenity::http::Http::new(Arc::new(utils::http_client(&config)), &discord_token);
+
+    let mut discord_client = ClientBuilder::new_with_http(discord_http)
         .event_handler(Handler {
             discord_context: Arc::clone(&discord_context),
-            db_client: Arc::clone(&db_client),
-            sender: nostr_bot::new_sender(),
-            keypair: nostr_bot::keypair_from_secret(&config.secret),
+            db_client: Arc::clone(&db),
+            outbox: outbox.clone(),
+            signer: bot_signer.clone(),
+            alerts: tx.clone(),
         })
         .await
         .expect("Err creating Discord client");
 
     let discord_future = discord_client.start();
 
-    let keypair = nostr_bot::keypair_from_secret(&config.secret);
-    let sender = nostr_bot::new_sender();
-
-    let (tx, rx) = tokio::sync::mpsc::channel::<dostr::ConnectionMessage>(64);
-
-    let current_dir = env::current_dir().unwrap();
-    let db_file_path = current_dir.join("data/channels");
-    
     let state = nostr_bot::wrap_state(dostr::DostrState {
         config: config.clone(),
-        sender: sender.clone(),
-        db: std::sync::Arc::new(std::sync::Mutex::new(simpledb::SimpleDatabase::from_file(
-            db_file_path.to_string_lossy().to_string(),
-        ))),
+        db,
         error_sender: tx.clone(),
         started_timestamp: nostr_bot::unix_timestamp(),
         discord_context: Arc::clone(&discord_context),
+        media_cache: media::new_cache(),
+        link_cache: fetch::new_link_cache(),
+        nitter: nitter.clone(),
+        feeds,
+        scheduler: scheduler::FetchScheduler::from_config(&config),
+        outbox: outbox.clone(),
+        relays: pool.clone(),
     });
 
     let start_existing = {
@@ -87,15 +143,16 @@ async fn main() {
         }
     };
 
-    let error_listener = {
-        let state = state.clone();
-        let sender = state.lock().await.sender.clone();
+    let alert_listener = {
+        let config = config.clone();
         async move {
-            dostr::error_listener(rx, sender, keypair).await;
+            alerts::alert_listener(rx, outbox, keypair, config).await;
         }
     };
 
-    let relays = config.relays.iter().map(|r| r.as_str()).collect::<Vec<_>>();
+    // Commands are read from the default relays at startup, !relay changes apply after a restart
+    let defaults = pool.defaults();
+    let relays = defaults.iter().map(|r| r.as_str()).collect::<Vec<_>>();
 
     let mut bot = nostr_bot::Bot::<State>::new(keypair, relays, state)
         .name(&config.name)
@@ -104,7 +161,7 @@ async fn main() {
         .intro_message(&config.hello_message)
         .command(
             nostr_bot::Command::new("!add", nostr_bot::wrap!(dostr::channel_add))
-                .description("Add new Twitter acount to be followed by the bot. For example, !add @nasa")
+                .description("Add new Twitter acount to be followed by the bot. For example, !add @nasa. RSS feeds can be added by URL with an optional name, !add https://example.com/feed.xml example")
         )
         .command(
             nostr_bot::Command::new("!random", nostr_bot::wrap!(dostr::channel_random))
@@ -114,9 +171,29 @@ async fn main() {
             nostr_bot::Command::new("!list", nostr_bot::wrap!(dostr::channel_list))
                 .description("Returns list of all Twitter accounts that the bot follows."),
         )
+        .command(
+            nostr_bot::Command::new("!interval", nostr_bot::wrap!(dostr::channel_interval))
+                .description("Shows how often a followed account is checked, operators can set it. For example, !interval @nasa 600 or !interval @nasa auto."),
+        )
+        .command(
+            nostr_bot::Command::new("!feedrelays", nostr_bot::wrap!(dostr::channel_feed_relays))
+                .description("Shows the relays a followed account publishes to, operators can set them. For example, !feedrelays @nasa wss://relay.example wss://nos.lol or !feedrelays @nasa default."),
+        )
+        .command(
+            nostr_bot::Command::new("!feedsigner", nostr_bot::wrap!(dostr::channel_feed_signer))
+                .description("Shows the NIP-46 bunker signing for a followed account, operators can set it. For example, !feedsigner @nasa bunker://<key>?relay=wss://relay.example or !feedsigner @nasa local."),
+        )
+        .command(
+            nostr_bot::Command::new("!claim", nostr_bot::wrap!(dostr::channel_claim))
+                .description("Operators only. Sends the key of a followed account to its owner as an encrypted DM and stops posting as it. For example, !claim @nasa npub1..."),
+        )
+        .command(
+            nostr_bot::Command::new("!relay", nostr_bot::wrap!(dostr::channel_relay))
+                .description("Operators only. Adds or removes a default relay. For example, !relay add wss://nos.lol or !relay remove wss://nos.lol."),
+        )
         .command(
             nostr_bot::Command::new("!relays", nostr_bot::wrap_extra!(dostr::channel_relays))
-                .description("Show connected relay."),
+                .description("Shows the relays, whether they accept events and how many."),
         )
         .command(
             nostr_bot::Command::new("!uptime", nostr_bot::wrap!(dostr::uptime))
@@ -125,12 +202,18 @@ async fn main() {
         .help()
         .sender(sender)
         .spawn(Box::pin(start_existing))
-        .spawn(Box::pin(error_listener));
+        .spawn(Box::pin(alert_listener));
 
-    match args[1].as_str() {
-        "--clearnet" => {}
-        "--tor" => bot = bot.use_socks5("0.0.0.0:9050"),
-        _ => panic!("Incorrect network settings"),
+    if config.nitter_health_check_secs > 0 {
+        bot = bot.spawn(Box::pin(nitter::health_checker(nitter, config.nitter_health_check_secs, tx.clone())));
+    }
+
+    if config.relay_check_secs > 0 {
+        bot = bot.spawn(Box::pin(alerts::relay_checker(config.clone(), pool, tx)));
+    }
+
+    if config.use_tor {
+        bot = bot.use_socks5(&config.tor_socks_address);
     }
 
     // Run both the Nostr bot and the Discord bot concurrently
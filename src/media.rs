@@ -0,0 +1,602 @@
+// Media of mirrored notes. Linked files can be rehosted to a Blossom or NIP-96 server, and media
+// that keeps its link is described with NIP-92 imeta tags probed from the original host.
+
+use log::{debug, info, warn};
+use crate::error::{self, Error};
+use crate::signer::Signer;
+use crate::utils;
+use secp256k1::hashes::{sha256, Hash};
+use serde::Deserialize;
+use std::collections::HashMap;
+
+pub type MediaCache = std::sync::Arc<std::sync::Mutex<utils::BoundedCache<Media>>>;
+
+// Files bigger than this are left on the original host
+const MAX_MEDIA_BYTES: usize = 100 * 1024 * 1024;
+
+// The cache forgets the oldest media when it grows over this many entries
+const MAX_CACHED_MEDIA: usize = 10000;
+
+// Number of blurhash components in each direction
+const BLURHASH_COMPONENTS: (u32, u32) = (4, 3);
+
+const MEDIA_EXTENSIONS: [&str; 8] = ["jpg", "jpeg", "png", "gif", "webp", "mp4", "mov", "webm"];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MediaServerKind {
+    Blossom,
+    Nip96,
+}
+
+pub struct MediaServer {
+    url: String,
+    kind: MediaServerKind,
+    client: reqwest::Client,
+    nip96_api_url: tokio::sync::Mutex<Option<String>>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProbeMode {
+    // No imeta tags for media that wasn't rehosted
+    Off,
+    // Only mime type and size from response headers
+    Headers,
+    // Download images to get hash, dimensions and blurhash
+    Full,
+}
+
+// Media file linked from a note, as stored on the media server when rehosted
+#[derive(Clone)]
+pub struct Media {
+    pub url: String,
+    pub mime: String,
+    pub sha256: Option<String>,
+    pub original_sha256: Option<String>,
+    pub size: Option<usize>,
+    pub dim: Option<(u32, u32)>,
+    pub blurhash: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct BlobDescriptor {
+    url: String,
+    sha256: Option<String>,
+    #[serde(rename = "type")]
+    mime: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Nip96Info {
+    api_url: String,
+}
+
+#[derive(Deserialize)]
+struct Nip96Response {
+    status: String,
+    message: Option<String>,
+    nip94_event: Option<Nip96Event>,
+}
+
+#[derive(Deserialize)]
+struct Nip96Event {
+    tags: Vec<Vec<String>>,
+}
+
+impl Media {
+    // NIP-92 "imeta" tag with NIP-94 fields describing the file
+    pub fn imeta_tag(&self) -> Vec<String> {
+        let mut tag = vec![
+            "imeta".to_string(),
+            format!("url {}", self.url),
+            format!("m {}", self.mime),
+        ];
+        if let Some(sha256) = &self.sha256 {
+            tag.push(format!("x {}", sha256));
+        }
+        if let Some(original_sha256) = &self.original_sha256 {
+            if Some(original_sha256) != self.sha256.as_ref() {
+                tag.push(format!("ox {}", original_sha256));
+            }
+        }
+        if let Some(size) = self.size {
+            tag.push(format!("size {}", size));
+        }
+        if let Some((width, height)) = self.dim {
+            tag.push(format!("dim {}x{}", width, height));
+        }
+        if let Some(blurhash) = &self.blurhash {
+            tag.push(format!("blurhash {}", blurhash));
+        }
+        tag
+    }
+}
+
+pub fn new_cache() -> MediaCache {
+    std::sync::Arc::new(std::sync::Mutex::new(utils::BoundedCache::new(MAX_CACHED_MEDIA)))
+}
+
+impl ProbeMode {
+    pub fn from_config(config: &utils::Config) -> ProbeMode {
+        match config.media_probe.as_str() {
+            "off" => ProbeMode::Off,
+            "headers" => ProbeMode::Headers,
+            _ => ProbeMode::Full,
+        }
+    }
+}
+
+impl MediaServer {
+    // Returns None when media rehosting is not configured
+    pub fn from_config(config: &utils::Config, client: reqwest::Client) -> Option<MediaServer> {
+        if config.media_server.is_empty() {
+            return None;
+        }
+
+        let kind = match config.media_server_type.as_str() {
+            "nip96" => MediaServerKind::Nip96,
+            _ => MediaServerKind::Blossom,
+        };
+
+        Some(MediaServer {
+            url: config.media_server.trim_end_matches('/').to_string(),
+            kind,
+            client,
+            nip96_api_url: tokio::sync::Mutex::new(None),
+        })
+    }
+
+    // Downloads the file from `url` and uploads it to the media server, authenticated by `signer`
+    pub async fn rehost(&self, url: &str, signer: &Signer) -> error::Result<Media> {
+        let (bytes, mime) = download(&self.client, url).await?;
+        let sha256 = sha256::Hash::hash(&bytes).to_string();
+        debug!("Downloaded {} ({} bytes, {}, sha256 {})", url, bytes.len(), mime, sha256);
+
+        let (dim, blurhash) = describe_image(&bytes, &mime);
+
+        let media = match self.kind {
+            MediaServerKind::Blossom => self.upload_blossom(bytes, mime, sha256, signer).await?,
+            MediaServerKind::Nip96 => self.upload_nip96(bytes, url, mime, sha256, signer).await?,
+        };
+
+        Ok(Media {
+            dim: media.dim.or(dim),
+            blurhash: media.blurhash.or(blurhash),
+            ..media
+        })
+    }
+
+    async fn upload_blossom(
+        &self,
+        bytes: Vec<u8>,
+        mime: String,
+        sha256: String,
+        signer: &Signer,
+    ) -> error::Result<Media> {
+        let size = bytes.len();
+        let auth = nostr_bot::EventNonSigned {
+            created_at: utils::unix_timestamp(),
+            kind: 24242,
+            tags: vec![
+                vec!["t".to_string(), "upload".to_string()],
+                vec!["x".to_string(), sha256.clone()],
+                vec!["expiration".to_string(), (utils::unix_timestamp() + 300).to_string()],
+            ],
+            content: "Upload media".to_string(),
+        };
+        let auth = signer.sign(auth).await?;
+
+        let upload_url = format!("{}/upload", self.url);
+        let response = self
+            .client
+            .put(&upload_url)
+            .header(reqwest::header::AUTHORIZATION, authorization_header(&auth))
+            .header(reqwest::header::CONTENT_TYPE, mime.clone())
+            .body(bytes)
+            .send()
+            .await
+            .map_err(|e| Error::Network(format!("Failed to upload to {}: {}", upload_url, e)))?;
+
+        if !response.status().is_success() {
+            let reason = response
+                .headers()
+                .get("X-Reason")
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or_default()
+                .to_string();
+            let reason = format!("Upload to {} failed: status {} {}", upload_url, response.status(), reason);
+            return Err(Error::from_status(response.status(), response.headers(), reason));
+        }
+
+        let descriptor: BlobDescriptor = response
+            .json()
+            .await
+            .map_err(|e| Error::Parse(format!("Unable to parse response from {}: {}", upload_url, e)))?;
+
+        Ok(Media {
+            url: descriptor.url,
+            mime: descriptor.mime.unwrap_or(mime),
+            sha256: Some(descriptor.sha256.unwrap_or_else(|| sha256.clone())),
+            original_sha256: Some(sha256),
+            size: Some(size),
+            dim: None,
+            blurhash: None,
+        })
+    }
+
+    async fn upload_nip96(
+        &self,
+        bytes: Vec<u8>,
+        original_url: &str,
+        mime: String,
+        sha256: String,
+        signer: &Signer,
+    ) -> error::Result<Media> {
+        let size = bytes.len();
+        let api_url = self.nip96_api_url().await?;
+
+        // NIP-98 HTTP auth
+        let auth = nostr_bot::EventNonSigned {
+            created_at: utils::unix_timestamp(),
+            kind: 27235,
+            tags: vec![
+                vec!["u".to_string(), api_url.clone()],
+                vec!["method".to_string(), "POST".to_string()],
+            ],
+            content: String::new(),
+        };
+        let auth = signer.sign(auth).await?;
+
+        let file_name = original_url
+            .rsplit('/')
+            .next()
+            .map(|name| name.split(['?', '#']).next().unwrap_or_default())
+            .filter(|name| !name.is_empty())
+            .unwrap_or("media")
+            .to_string();
+        let part = reqwest::multipart::Part::bytes(bytes)
+            .file_name(file_name)
+            .mime_str(&mime)
+            .map_err(|e| Error::Parse(format!("Invalid mime type {}: {}", mime, e)))?;
+        let form = reqwest::multipart::Form::new()
+            .text("content_type", mime.clone())
+            .text("size", size.to_string())
+            .part("file", part);
+
+        let response = self
+            .client
+            .post(&api_url)
+            .header(reqwest::header::AUTHORIZATION, authorization_header(&auth))
+            .multipart(form)
+            .send()
+            .await
+            .map_err(|e| Error::Network(format!("Failed to upload to {}: {}", api_url, e)))?;
+
+        let (status, headers) = (response.status(), response.headers().clone());
+        let body = response
+            .bytes()
+            .await
+            .map_err(|e| Error::Network(format!("Failed to upload to {}: {}", api_url, e)))?;
+        let response = serde_json::from_slice::<Nip96Response>(&body);
+
+        if !status.is_success() {
+            let message = response.ok().and_then(|response| response.message).unwrap_or_default();
+            let reason = format!("Upload to {} failed: status {} {}", api_url, status, message);
+            return Err(Error::from_status(status, &headers, reason));
+        }
+        let response = response.map_err(|e| Error::Parse(format!("Unable to parse response from {}: {}", api_url, e)))?;
+        if response.status != "success" {
+            return Err(Error::Parse(format!("Upload to {} failed: {}", api_url, response.message.unwrap_or_default())));
+        }
+
+        let tags = response.nip94_event.map(|event| event.tags).unwrap_or_default();
+        let tag_value = |name: &str| {
+            tags.iter()
+                .find(|tag| tag.len() > 1 && tag[0] == name)
+                .map(|tag| tag[1].clone())
+        };
+
+        let url = tag_value("url").ok_or_else(|| Error::Parse(format!("{} didn't return url of the upload", api_url)))?;
+
+        let dim = tag_value("dim").and_then(|dim| parse_dim(&dim));
+
+        Ok(Media {
+            url,
+            mime: tag_value("m").unwrap_or(mime),
+            sha256: Some(tag_value("x").unwrap_or_else(|| sha256.clone())),
+            original_sha256: Some(tag_value("ox").unwrap_or(sha256)),
+            size: Some(size),
+            dim,
+            blurhash: tag_value("blurhash"),
+        })
+    }
+
+    async fn nip96_api_url(&self) -> error::Result<String> {
+        let mut api_url = self.nip96_api_url.lock().await;
+        if let Some(api_url) = api_url.as_ref() {
+            return Ok(api_url.clone());
+        }
+
+        let info_url = format!("{}/.well-known/nostr/nip96.json", self.url);
+        let response = self
+            .client
+            .get(&info_url)
+            .send()
+            .await
+            .map_err(|e| Error::Network(format!("Failed to fetch {}: {}", info_url, e)))?;
+        if !response.status().is_success() {
+            let reason = format!("Failed to fetch {}: status {}", info_url, response.status());
+            return Err(Error::from_status(response.status(), response.headers(), reason));
+        }
+        let info: Nip96Info = response
+            .json()
+            .await
+            .map_err(|e| Error::Parse(format!("Unable to parse {}: {}", info_url, e)))?;
+
+        *api_url = Some(info.api_url.clone());
+        Ok(info.api_url)
+    }
+}
+
+// Rehosts all media linked in the event content, replaces the links and adds "imeta" tags
+pub async fn rehost_media(
+    event: &mut nostr_bot::EventNonSigned,
+    server: &MediaServer,
+    signer: &Signer,
+) {
+    let mut rehosted = HashMap::new();
+    for link in media_links(&event.content) {
+        match server.rehost(&link, signer).await {
+            Ok(media) => {
+                info!("Rehosted {} as {}", link, media.url);
+                for tag in event.tags.iter_mut() {
+                    if tag.len() > 1 && tag[0] == "r" && tag[1] == link {
+                        tag[1] = media.url.clone();
+                    }
+                }
+                event.tags.push(media.imeta_tag());
+                rehosted.insert(link, media.url);
+            }
+            Err(e) => {
+                warn!("Unable to rehost {}, keeping the original link: {}", link, e);
+            }
+        }
+    }
+    event.content = replace_links(&event.content, &rehosted);
+}
+
+// Replaces links that are exactly a key of `replacements`, not longer links starting the same
+fn replace_links(content: &str, replacements: &HashMap<String, String>) -> String {
+    let mut finder = linkify::LinkFinder::new();
+    finder.kinds(&[linkify::LinkKind::Url]);
+
+    let mut replaced = String::with_capacity(content.len());
+    let mut end = 0;
+    for link in finder.links(content) {
+        if let Some(replacement) = replacements.get(link.as_str()) {
+            replaced.push_str(&content[end..link.start()]);
+            replaced.push_str(replacement);
+            end = link.end();
+        }
+    }
+    replaced.push_str(&content[end..]);
+    replaced
+}
+
+// Adds NIP-92 "imeta" tags for media linked in the event content that doesn't have one yet
+pub async fn add_imeta_tags(event: &mut nostr_bot::EventNonSigned, cache: &MediaCache, mode: ProbeMode, client: &reqwest::Client) {
+    if mode == ProbeMode::Off {
+        return;
+    }
+
+    for link in media_links(&event.content) {
+        let has_imeta = event.tags.iter().any(|tag| {
+            !tag.is_empty() && tag[0] == "imeta" && tag.iter().any(|field| *field == format!("url {}", link))
+        });
+        if has_imeta {
+            continue;
+        }
+
+        let cached = cache.lock().unwrap().get(&link).cloned();
+        let media = match cached {
+            Some(media) => media,
+            None => match probe(client, &link, mode).await {
+                Ok(media) => {
+                    cache.lock().unwrap().insert(link.clone(), media.clone());
+                    media
+                }
+                Err(e) => {
+                    info!("Unable to probe {}: {}", link, e);
+                    continue;
+                }
+            },
+        };
+
+        event.tags.push(media.imeta_tag());
+    }
+}
+
+// Gets media info from response headers, in full mode images are downloaded to compute
+// hash, dimensions and blurhash
+async fn probe(client: &reqwest::Client, url: &str, mode: ProbeMode) -> error::Result<Media> {
+    let response = client
+        .head(url)
+        .send()
+        .await
+        .map_err(|e| Error::Network(format!("Failed to fetch headers of {}: {}", url, e)))?;
+
+    if !response.status().is_success() {
+        let reason = format!("Failed to fetch headers of {}: status {}", url, response.status());
+        return Err(Error::from_status(response.status(), response.headers(), reason));
+    }
+
+    let mime = content_type(&response).unwrap_or_else(|| guess_mime(url).to_string());
+    let size = response
+        .headers()
+        .get(reqwest::header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<usize>().ok());
+
+    let mut media = Media {
+        url: url.to_string(),
+        mime,
+        sha256: None,
+        original_sha256: None,
+        size,
+        dim: None,
+        blurhash: None,
+    };
+
+    if mode == ProbeMode::Full && media.mime.starts_with("image/") {
+        let (bytes, _) = download(client, url).await?;
+        let (dim, blurhash) = describe_image(&bytes, &media.mime);
+        media.sha256 = Some(sha256::Hash::hash(&bytes).to_string());
+        media.size = Some(bytes.len());
+        media.dim = dim;
+        media.blurhash = blurhash;
+    }
+
+    Ok(media)
+}
+
+async fn download(client: &reqwest::Client, url: &str) -> error::Result<(Vec<u8>, String)> {
+    download_at_most(client, url, MAX_MEDIA_BYTES).await
+}
+
+// Gives up as soon as the file turns out to be bigger than `max_bytes`, before or while reading it
+async fn download_at_most(client: &reqwest::Client, url: &str, max_bytes: usize) -> error::Result<(Vec<u8>, String)> {
+    let mut response = client
+        .get(url)
+        .send()
+        .await
+        .map_err(|e| Error::Network(format!("Failed to download {}: {}", url, e)))?;
+
+    if !response.status().is_success() {
+        let reason = format!("Failed to download {}: status {}", url, response.status());
+        return Err(Error::from_status(response.status(), response.headers(), reason));
+    }
+
+    if let Some(size) = response.content_length().filter(|size| *size > max_bytes as u64) {
+        return Err(Error::Parse(format!("{} is too big ({} bytes)", url, size)));
+    }
+
+    let mime = content_type(&response).unwrap_or_else(|| guess_mime(url).to_string());
+
+    let mut bytes = vec![];
+    while let Some(chunk) = response
+        .chunk()
+        .await
+        .map_err(|e| Error::Network(format!("Failed to download {}: {}", url, e)))?
+    {
+        if bytes.len() + chunk.len() > max_bytes {
+            return Err(Error::Parse(format!("{} is too big (over {} bytes)", url, max_bytes)));
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+
+    Ok((bytes, mime))
+}
+
+fn content_type(response: &reqwest::Response) -> Option<String> {
+    response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.split(';').next().unwrap_or_default().trim().to_string())
+        .filter(|value| !value.is_empty() && value != "application/octet-stream")
+}
+
+// Returns dimensions and blurhash of an image, None for videos and undecodable images
+fn describe_image(bytes: &[u8], mime: &str) -> (Option<(u32, u32)>, Option<String>) {
+    if !mime.starts_with("image/") {
+        return (None, None);
+    }
+
+    let image = match image::load_from_memory(bytes) {
+        Ok(image) => image,
+        Err(e) => {
+            debug!("Unable to decode image: {}", e);
+            return (None, None);
+        }
+    };
+
+    let dim = (image.width(), image.height());
+
+    // Blurhash only needs a rough idea of the colors, no need to go through all the pixels
+    let thumbnail = image.thumbnail(64, 64).to_rgba8();
+    let blurhash = blurhash::encode(
+        BLURHASH_COMPONENTS.0,
+        BLURHASH_COMPONENTS.1,
+        thumbnail.width(),
+        thumbnail.height(),
+        thumbnail.as_raw(),
+    )
+    .ok();
+
+    (Some(dim), blurhash)
+}
+
+fn parse_dim(dim: &str) -> Option<(u32, u32)> {
+    let (width, height) = dim.split_once('x')?;
+    Some((width.parse().ok()?, height.parse().ok()?))
+}
+
+fn media_links(content: &str) -> Vec<String> {
+    let mut finder = linkify::LinkFinder::new();
+    finder.kinds(&[linkify::LinkKind::Url]);
+
+    let mut links: Vec<String> = vec![];
+    for link in finder.links(content) {
+        let link = link.as_str().to_string();
+        if is_media_link(&link) && !links.contains(&link) {
+            links.push(link);
+        }
+    }
+    links
+}
+
+// Images and videos proxied by Nitter or links ending with a media file extension
+pub fn is_media_link(link: &str) -> bool {
+    let url = match url::Url::parse(link) {
+        Ok(url) => url,
+        Err(_) => return false,
+    };
+
+    let path = url.path().to_lowercase();
+    if path.starts_with("/pic/") || path.starts_with("/video/") {
+        return true;
+    }
+
+    let decoded_path = path.replace("%2e", ".");
+    MEDIA_EXTENSIONS
+        .iter()
+        .any(|extension| decoded_path.ends_with(&format!(".{}", extension)))
+}
+
+fn guess_mime(link: &str) -> &'static str {
+    let link = link.to_lowercase();
+    let path = link.split(['?', '#']).next().unwrap_or_default();
+    if path.ends_with(".png") {
+        "image/png"
+    } else if path.ends_with(".gif") {
+        "image/gif"
+    } else if path.ends_with(".webp") {
+        "image/webp"
+    } else if path.ends_with(".mp4") || path.contains("/video/") {
+        "video/mp4"
+    } else if path.ends_with(".mov") {
+        "video/quicktime"
+    } else if path.ends_with(".webm") {
+        "video/webm"
+    } else {
+        "image/jpeg"
+    }
+}
+
+fn authorization_header(event: &nostr_bot::Event) -> String {
+    use base64::Engine;
+    let json = serde_json::to_string(event).unwrap();
+    format!("Nostr {}", base64::engine::general_purpose::STANDARD.encode(json))
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,157 @@
+use super::*;
+use crate::testing::MockMediaServer;
+
+// 2x1 PNG
+fn png() -> Vec<u8> {
+    let image = image::RgbImage::from_fn(2, 1, |x, _| image::Rgb([255 * x as u8, 0, 128]));
+    let mut bytes = std::io::Cursor::new(vec![]);
+    image.write_to(&mut bytes, image::ImageFormat::Png).unwrap();
+    bytes.into_inner()
+}
+
+fn media_server(mock: &MockMediaServer, kind: MediaServerKind) -> MediaServer {
+    MediaServer {
+        url: mock.url().to_string(),
+        kind,
+        client: reqwest::Client::new(),
+        nip96_api_url: tokio::sync::Mutex::new(None),
+    }
+}
+
+// Note linking `link` and a longer link starting with it, with an "r" tag for `link`
+fn note(link: &str) -> nostr_bot::EventNonSigned {
+    nostr_bot::EventNonSigned {
+        created_at: utils::unix_timestamp(),
+        kind: 1,
+        tags: vec![vec!["r".to_string(), link.to_string()]],
+        content: format!("Look {} and {}.txt", link, link),
+    }
+}
+
+fn imeta_field<'a>(tag: &'a [String], name: &str) -> Option<&'a str> {
+    tag.iter().find_map(|field| field.strip_prefix(&format!("{} ", name)))
+}
+
+#[tokio::test]
+async fn uploads_replace_the_links() {
+    for kind in [MediaServerKind::Blossom, MediaServerKind::Nip96] {
+        let mock = MockMediaServer::start(kind).await;
+        let link = mock.host("cat.png", &png(), "image/png");
+        let keypair = utils::get_random_keypair();
+
+        let mut event = note(&link);
+        rehost_media(&mut event, &media_server(&mock, kind), &Signer::Local(keypair)).await;
+
+        let uploads = mock.uploads();
+        assert_eq!(uploads.len(), 1, "{:?}", kind);
+        let upload = &uploads[0];
+        assert_eq!(upload.auth["pubkey"], keypair.x_only_public_key().0.to_string());
+        assert_eq!(upload.mime, "image/png");
+        let url = format!("{}/files/{}", mock.url(), upload.sha256);
+        // The longer link sharing the prefix stays as it is
+        assert_eq!(event.content, format!("Look {} and {}.txt", url, link));
+        assert_eq!(event.tags[0], vec!["r".to_string(), url.clone()]);
+
+        let imeta = &event.tags[1];
+        assert_eq!(imeta[0], "imeta");
+        assert_eq!(imeta_field(imeta, "url"), Some(url.as_str()));
+        assert_eq!(imeta_field(imeta, "m"), Some("image/png"));
+        assert_eq!(imeta_field(imeta, "x"), Some(upload.sha256.as_str()));
+        // Same file on the server, so no "ox"
+        assert_eq!(imeta_field(imeta, "ox"), None);
+        assert_eq!(imeta_field(imeta, "size"), Some(upload.size.to_string().as_str()));
+        assert_eq!(imeta_field(imeta, "dim"), Some("2x1"));
+        assert!(imeta_field(imeta, "blurhash").is_some());
+    }
+}
+
+#[tokio::test]
+async fn failed_uploads_keep_the_original_links() {
+    for kind in [MediaServerKind::Blossom, MediaServerKind::Nip96] {
+        let mock = MockMediaServer::start(kind).await;
+        mock.set_failing(true);
+        let link = mock.host("cat.png", &png(), "image/png");
+        let missing = format!("{}/files/missing.jpg", mock.url());
+
+        let mut event = note(&link);
+        event.content.push_str(&format!(" {}", missing));
+        let original = (event.content.clone(), event.tags.clone());
+        rehost_media(&mut event, &media_server(&mock, kind), &Signer::Local(utils::get_random_keypair())).await;
+
+        assert!(mock.uploads().is_empty());
+        assert_eq!((event.content, event.tags), original);
+    }
+}
+
+#[tokio::test]
+async fn downloads_stop_at_the_size_limit() {
+    let mock = MockMediaServer::start(MediaServerKind::Blossom).await;
+    let link = mock.host("big.png", &[0; 1000], "image/png");
+    let client = reqwest::Client::new();
+
+    assert_eq!(download_at_most(&client, &link, 1000).await.unwrap().0.len(), 1000);
+    let error = download_at_most(&client, &link, 999).await.unwrap_err();
+    assert!(matches!(&error, Error::Parse(reason) if reason.contains("too big")), "{}", error);
+}
+
+#[test]
+fn only_exact_links_are_replaced() {
+    let replacements = HashMap::from([("https://a.example/x.jpg".to_string(), "https://b.example/y.jpg".to_string())]);
+    let content = "https://a.example/x.jpg https://a.example/x.jpg.html https://a.example/x.jpg?s=1 (https://a.example/x.jpg)";
+    assert_eq!(
+        replace_links(content, &replacements),
+        "https://b.example/y.jpg https://a.example/x.jpg.html https://a.example/x.jpg?s=1 (https://b.example/y.jpg)"
+    );
+}
+
+#[tokio::test]
+async fn headers_probes_only_ask_for_type_and_size() {
+    let mock = MockMediaServer::start(MediaServerKind::Blossom).await;
+    let link = mock.host("cat.png", &png(), "image/png");
+
+    let media = probe(&reqwest::Client::new(), &link, ProbeMode::Headers).await.unwrap();
+    assert_eq!((media.url.as_str(), media.mime.as_str(), media.size), (link.as_str(), "image/png", Some(png().len())));
+    assert_eq!((media.sha256, media.dim, media.blurhash), (None, None, None));
+
+    let missing = format!("{}/files/missing.png", mock.url());
+    assert!(matches!(probe(&reqwest::Client::new(), &missing, ProbeMode::Headers).await, Err(Error::Status(404, _))));
+}
+
+#[tokio::test]
+async fn full_probes_describe_images() {
+    let mock = MockMediaServer::start(MediaServerKind::Blossom).await;
+    let link = mock.host("cat.png", &png(), "image/png");
+    let video = mock.host("clip.mp4", &[0; 10], "video/mp4");
+
+    let media = probe(&reqwest::Client::new(), &link, ProbeMode::Full).await.unwrap();
+    assert_eq!(media.sha256, Some(sha256::Hash::hash(&png()).to_string()));
+    assert_eq!(media.size, Some(png().len()));
+    assert_eq!(media.dim, Some((2, 1)));
+    assert!(media.blurhash.is_some());
+
+    // Videos aren't downloaded
+    let media = probe(&reqwest::Client::new(), &video, ProbeMode::Full).await.unwrap();
+    assert_eq!((media.mime.as_str(), media.size, media.sha256), ("video/mp4", Some(10), None));
+}
+
+#[tokio::test]
+async fn imeta_tags_come_from_a_bounded_cache() {
+    let mock = MockMediaServer::start(MediaServerKind::Blossom).await;
+    let links = ["a", "b", "c"].map(|name| mock.host(&format!("{}.png", name), &png(), "image/png"));
+    let cache = std::sync::Arc::new(std::sync::Mutex::new(utils::BoundedCache::new(2)));
+
+    let mut event = note(&links[0]);
+    event.content = links.join(" ");
+    event.tags.clear();
+    add_imeta_tags(&mut event, &cache, ProbeMode::Headers, &reqwest::Client::new()).await;
+    assert_eq!(event.tags.len(), 3);
+    for (tag, link) in event.tags.iter().zip(&links) {
+        assert_eq!(imeta_field(tag, "url"), Some(link.as_str()));
+        assert_eq!(imeta_field(tag, "m"), Some("image/png"));
+    }
+
+    // The oldest entry made room for the newest
+    let cache = cache.lock().unwrap();
+    assert!(cache.get(&links[0]).is_none());
+    assert!(cache.get(&links[1]).is_some() && cache.get(&links[2]).is_some());
+}
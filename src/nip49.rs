@@ -0,0 +1,65 @@
+// Password encrypted secret keys (NIP-49). The key is encrypted with XChaCha20-Poly1305 under a
+// scrypt hash of the NFKC normalized password and bech32 encoded as ncryptsec1...
+
+use crate::error::{self, Error};
+use bech32::{FromBase32, ToBase32};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use rand::RngCore;
+use secp256k1::SecretKey;
+use unicode_normalization::UnicodeNormalization;
+
+const VERSION: u8 = 0x02;
+
+// The associated data byte, whether the key was ever kept unencrypted
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KeySecurity {
+    // Stored or handled in plaintext before
+    Insecure = 0x00,
+    Secure = 0x01,
+}
+
+fn symmetric_key(password: &str, salt: &[u8], log_n: u8) -> error::Result<[u8; 32]> {
+    let password = password.nfkc().collect::<String>();
+    let params = scrypt::Params::new(log_n, 8, 1, 32).map_err(|e| Error::Parse(format!("Invalid scrypt parameters: {}", e)))?;
+    let mut key = [0u8; 32];
+    scrypt::scrypt(password.as_bytes(), salt, &params, &mut key).map_err(|e| Error::Parse(format!("scrypt failed: {}", e)))?;
+    Ok(key)
+}
+
+// ncryptsec of `secret`, scrypt takes 2^`log_n` rounds (16 is the usual minimum)
+pub fn encrypt(secret: &SecretKey, password: &str, log_n: u8, security: KeySecurity) -> error::Result<String> {
+    let mut salt = [0u8; 16];
+    let mut nonce = [0u8; 24];
+    rand::thread_rng().fill_bytes(&mut salt);
+    rand::thread_rng().fill_bytes(&mut nonce);
+
+    let key = symmetric_key(password, &salt, log_n)?;
+    let security = security as u8;
+    let ciphertext = XChaCha20Poly1305::new(&key.into())
+        .encrypt(XNonce::from_slice(&nonce), Payload { msg: &secret.secret_bytes(), aad: &[security] })
+        .map_err(|_| Error::Parse("Unable to encrypt the key".to_string()))?;
+
+    let data = [&[VERSION, log_n][..], &salt, &nonce, &[security], &ciphertext].concat();
+    bech32::encode("ncryptsec", data.to_base32(), bech32::Variant::Bech32)
+        .map_err(|e| Error::Parse(format!("Unable to encode the key: {}", e)))
+}
+
+pub fn decrypt(ncryptsec: &str, password: &str) -> error::Result<SecretKey> {
+    let invalid = |reason: &str| Error::Parse(format!("Invalid ncryptsec: {}", reason));
+    let (hrp, data, _) = bech32::decode(ncryptsec).map_err(|e| invalid(&e.to_string()))?;
+    let data = Vec::<u8>::from_base32(&data).map_err(|e| invalid(&e.to_string()))?;
+    if hrp != "ncryptsec" || data.len() != 91 || data[0] != VERSION {
+        return Err(invalid("unknown format"));
+    }
+
+    let (log_n, salt, nonce, security, ciphertext) = (data[1], &data[2..18], &data[18..42], &data[42..43], &data[43..]);
+    let key = symmetric_key(password, salt, log_n)?;
+    let secret = XChaCha20Poly1305::new(&key.into())
+        .decrypt(XNonce::from_slice(nonce), Payload { msg: ciphertext, aad: security })
+        .map_err(|_| invalid("wrong password"))?;
+    SecretKey::from_slice(&secret).map_err(|e| invalid(&e.to_string()))
+}
+
+#[cfg(test)]
+mod tests;
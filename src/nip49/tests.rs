@@ -0,0 +1,20 @@
+use super::*;
+
+#[test]
+fn decrypts_the_reference_vector() {
+    let ncryptsec = "ncryptsec1qgg9947rlpvqu76pj5ecreduf9jxhselq2nae2kghhvd5g7dgjtcxfqtd67p9m0w57lspw8gsq6yphnm8623nsl8xn9j4jdzz84zm3frztj3z7s35vpzmqf6ksu8r89qk5z2zxfmu5gv8th8wclt0h4p";
+    let secret = decrypt(ncryptsec, "nostr").unwrap();
+    assert_eq!(secret.display_secret().to_string(), "3501454135014541350145413501453fefb02227e449e57cf4d3a3ce05378683");
+    assert!(decrypt(ncryptsec, "nostr!").is_err());
+}
+
+#[test]
+fn round_trip_with_normalized_passwords() {
+    let secret = crate::utils::get_random_keypair().secret_key();
+    // The decomposed and the composed form of "ÅΩẛ̣" are the same password
+    let ncryptsec = encrypt(&secret, "\u{212B}\u{2126}\u{1E9B}\u{0323}", 4, KeySecurity::Secure).unwrap();
+    assert!(ncryptsec.starts_with("ncryptsec1"));
+    assert_eq!(decrypt(&ncryptsec, "\u{00C5}\u{03A9}\u{1E69}").unwrap(), secret);
+    assert!(decrypt(&ncryptsec, "other").is_err());
+    assert!(decrypt("nsec1vl029mgpspedva04g90vltkh6fvh240zqtv9k0t9af8935ke9laqsnlfe5", "other").is_err());
+}
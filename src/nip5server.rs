@@ -13,28 +13,33 @@ pub struct Entry {
     names: HashMap<String, String>,
 }
 
-async fn load_data() -> Result<Entry, Box<dyn std::error::Error + Send + Sync>> {
-    let current_dir = env::current_dir()?;
-    let data = fs::read_to_string(current_dir.join("web/.well-known/nostr.json")).await?;
+async fn load_data(path: &std::path::Path) -> Result<Entry, Box<dyn std::error::Error + Send + Sync>> {
+    let data = fs::read_to_string(path).await?;
     let entries: Entry = serde_json::from_str(&data)?;
     Ok(entries)
 }
 
 pub async fn start_server() {
+    let config = utils::parse_config();
+    let nostr_json_path = config.nostr_json_path();
+
     let well_known = warp::path(".well-known")
         .and(warp::path("nostr.json"))
         .and(warp::query::<HashMap<String, String>>().or_else(|_| async { Ok::<_, warp::Rejection>((HashMap::new(),)) }))
-        .and_then(|mut query: HashMap<String, String>| async move {
-            let data = load_data().await.unwrap();
-            let name = query.remove("name").unwrap_or_default();
-            let mut response = HashMap::new();
-            let names = data.names.get(&name).cloned().unwrap_or_else(|| "Not found".to_string());
-            response.insert("names", json!({name: names}));
-            Ok::<_, warp::Rejection>(warp::reply::with_header(
-                warp::reply::json(&response),
-                "Access-Control-Allow-Origin",
-                "*",
-            ))
+        .and_then(move |mut query: HashMap<String, String>| {
+            let nostr_json_path = nostr_json_path.clone();
+            async move {
+                let data = load_data(&nostr_json_path).await.unwrap();
+                let name = query.remove("name").unwrap_or_default();
+                let mut response = HashMap::new();
+                let names = data.names.get(&name).cloned().unwrap_or_else(|| "Not found".to_string());
+                response.insert("names", json!({name: names}));
+                Ok::<_, warp::Rejection>(warp::reply::with_header(
+                    warp::reply::json(&response),
+                    "Access-Control-Allow-Origin",
+                    "*",
+                ))
+            }
         });
 
     let current_dir = env::current_dir().expect("Failed to get current directory");
@@ -43,7 +48,6 @@ pub async fn start_server() {
 
     let routes = well_known.or(static_files);
 
-    let config = utils::parse_config();
     let port = config.web_port;
 
     warp::serve(routes.recover(handle_rejection)).run(([0, 0, 0, 0], port)).await;
@@ -56,10 +60,10 @@ async fn handle_rejection(err: warp::Rejection) -> Result<impl warp::Reply, Infa
     if err.is_not_found() {
         code = StatusCode::NOT_FOUND;
         message = "Not Found";
-    } else if let Some(_) = err.find::<warp::filters::body::BodyDeserializeError>() {
+    } else if err.find::<warp::filters::body::BodyDeserializeError>().is_some() {
         code = StatusCode::BAD_REQUEST;
         message = "Invalid Body";
-    } else if let Some(_) = err.find::<warp::reject::MethodNotAllowed>() {
+    } else if err.find::<warp::reject::MethodNotAllowed>().is_some() {
         code = StatusCode::METHOD_NOT_ALLOWED;
         message = "Method Not Allowed";
     } else {
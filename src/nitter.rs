@@ -0,0 +1,143 @@
+// Pool of Nitter instances from NITTER_INSTANCE. Every feed fetch goes to a healthy instance and
+// moves on to the next one when it fails, instances that fail to answer, answer with 5xx or
+// rate limit are skipped until a health check or another fetch finds them working again.
+
+use crate::alerts::{AlertSender, Component, ConnectionMessage};
+use crate::error::{self, Error};
+use crate::fetch::Feeds;
+use crate::utils;
+use log::{debug, info, warn};
+use rss::Channel;
+
+pub type Nitter = std::sync::Arc<NitterPool>;
+
+pub struct NitterPool {
+    instances: Vec<String>,
+    healthy: std::sync::Mutex<Vec<bool>>,
+    health_handle: String,
+    feeds: Feeds,
+}
+
+pub fn feed_url(instance: &str, handle: &str) -> String {
+    format!("{}/{}/rss", instance, handle)
+}
+
+impl NitterPool {
+    pub fn new(instances: Vec<String>, health_handle: &str, feeds: Feeds) -> NitterPool {
+        NitterPool {
+            healthy: std::sync::Mutex::new(vec![true; instances.len()]),
+            instances,
+            health_handle: health_handle.to_string(),
+            feeds,
+        }
+    }
+
+    pub fn from_config(config: &utils::Config, feeds: Feeds) -> Nitter {
+        std::sync::Arc::new(NitterPool::new(config.nitter_instances.clone(), &config.nitter_health_handle, feeds))
+    }
+
+    pub fn feeds(&self) -> &Feeds {
+        &self.feeds
+    }
+
+    pub fn healthy_instances(&self) -> Vec<String> {
+        let healthy = self.healthy.lock().unwrap();
+        self.instances
+            .iter()
+            .zip(healthy.iter())
+            .filter(|(_, healthy)| **healthy)
+            .map(|(instance, _)| instance.clone())
+            .collect()
+    }
+
+    // Order in which instances are tried for `handle`, healthy ones first. Each handle starts at
+    // a different instance so the feeds are spread over the pool.
+    fn candidates(&self, handle: &str) -> Vec<usize> {
+        let count = self.instances.len();
+        let start = handle.bytes().map(|b| b as usize).sum::<usize>() % count.max(1);
+        let healthy = self.healthy.lock().unwrap().clone();
+
+        let mut order = (0..count).map(|i| (start + i) % count).collect::<Vec<_>>();
+        order.sort_by_key(|&i| !healthy[i]);
+        order
+    }
+
+    fn set_healthy(&self, index: usize, is_healthy: bool) {
+        let mut healthy = self.healthy.lock().unwrap();
+        if healthy[index] != is_healthy {
+            if is_healthy {
+                info!("Nitter instance {} is working again", self.instances[index]);
+            } else {
+                warn!("Nitter instance {} is failing, using other instances", self.instances[index]);
+            }
+            healthy[index] = is_healthy;
+        }
+    }
+
+    // Fetches the RSS feed of `handle` from the first instance that serves it, returns the feed
+    // and the URL it came from. When every instance is rate limiting, the error has the longest
+    // Retry-After of them.
+    pub async fn get_feed(&self, handle: &str) -> error::Result<(Channel, String)> {
+        let mut errors = vec![];
+        let mut rate_limited = true;
+        let mut retry_after = None;
+        for index in self.candidates(handle) {
+            let url = feed_url(&self.instances[index], handle);
+            match self.fetch(&url).await {
+                Ok(channel) => {
+                    self.set_healthy(index, true);
+                    return Ok((channel, url));
+                }
+                Err(err) => {
+                    debug!("Failed to get feed {}: {}", url, err);
+                    // A 404 for an unknown handle says nothing about the instance
+                    if err.is_transient() {
+                        self.set_healthy(index, false);
+                    }
+                    match &err {
+                        Error::RateLimited(delay) => retry_after = retry_after.max(*delay),
+                        _ => rate_limited = false,
+                    }
+                    errors.push(format!("{}: {}", self.instances[index], err));
+                }
+            }
+        }
+
+        if rate_limited && !errors.is_empty() {
+            return Err(Error::RateLimited(retry_after));
+        }
+        Err(Error::Network(format!("No Nitter instance could serve the feed of {} ({})", handle, errors.join(", "))))
+    }
+
+    async fn fetch(&self, url: &str) -> error::Result<Channel> {
+        self.feeds.get_feed(url).await
+    }
+
+    pub async fn check_health(&self) {
+        for index in 0..self.instances.len() {
+            let url = feed_url(&self.instances[index], &self.health_handle);
+            let result = self.fetch(&url).await;
+            if let Err(err) = &result {
+                debug!("Health check of {} failed: {}", url, err);
+            }
+            self.set_healthy(index, result.is_ok());
+        }
+    }
+}
+
+pub async fn health_checker(nitter: Nitter, interval_secs: u64, alerts: AlertSender) {
+    loop {
+        tokio::time::sleep(std::time::Duration::from_secs(interval_secs)).await;
+        nitter.check_health().await;
+        let message = if nitter.healthy_instances().is_empty() {
+            warn!("None of the Nitter instances is working");
+            ConnectionMessage::failed(Component::Nitter, "None of the instances is working".to_string())
+        } else {
+            ConnectionMessage::success(Component::Nitter)
+        };
+        let _ = alerts.send(message).await;
+    }
+}
+
+#[cfg(test)]
+mod tests;
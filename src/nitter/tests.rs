@@ -0,0 +1,75 @@
+use super::*;
+use crate::fetch::FeedClient;
+use crate::testing::MockNitter;
+
+fn feeds() -> Feeds {
+    std::sync::Arc::new(FeedClient::new(reqwest::Client::new()))
+}
+
+#[tokio::test]
+async fn get_feed_fails_over_to_working_instance() {
+    let mock = MockNitter::start_instances(2).await;
+    let pool = NitterPool::new(mock.urls(), "jack", feeds());
+    mock.post("nasa", "hello");
+
+    mock.set_down(0, true);
+    // Feeds of both handles would start at the first instance or the second one
+    for handle in ["nasa", "elon", "nasa", "elon"] {
+        let (channel, url) = pool.get_feed(handle).await.unwrap();
+        assert_eq!(url, feed_url(&mock.urls()[1], handle));
+        assert_eq!(channel.items().len(), usize::from(handle == "nasa"));
+    }
+    assert_eq!(pool.healthy_instances(), vec![mock.urls()[1].clone()]);
+
+    // The failing instance is tried at most once, then it's skipped until it is healthy again
+    assert!(mock.requests_to(0).len() <= 1);
+
+    mock.set_down(0, false);
+    pool.check_health().await;
+    assert_eq!(pool.healthy_instances(), mock.urls());
+}
+
+#[tokio::test]
+async fn get_feed_fails_when_no_instance_works() {
+    let mock = MockNitter::start_instances(2).await;
+    let pool = NitterPool::new(mock.urls(), "jack", feeds());
+    mock.set_down(0, true);
+    mock.set_down(1, true);
+
+    let err = pool.get_feed("nasa").await.unwrap_err().to_string();
+    assert!(err.contains("No Nitter instance"), "{}", err);
+    assert!(pool.healthy_instances().is_empty());
+
+    // Unhealthy instances are still tried as the last resort
+    mock.set_down(1, false);
+    assert!(pool.get_feed("nasa").await.is_ok());
+}
+
+#[tokio::test]
+async fn handles_are_spread_over_instances() {
+    let mock = MockNitter::start_instances(2).await;
+    let pool = NitterPool::new(mock.urls(), "jack", feeds());
+
+    let mut used = std::collections::HashSet::new();
+    for handle in ["nasa", "jack", "saylor", "elon"] {
+        used.insert(pool.get_feed(handle).await.unwrap().1.replace(&format!("/{}/rss", handle), ""));
+    }
+    assert_eq!(used.len(), 2);
+}
+
+#[tokio::test]
+async fn unknown_handles_leave_instances_healthy() {
+    let mock = MockNitter::start_instances(2).await;
+    let pool = NitterPool::new(mock.urls(), "jack", feeds());
+    mock.set_missing("nobody");
+
+    let err = pool.get_feed("nobody").await.unwrap_err();
+    assert!(matches!(err, Error::Network(_)), "{}", err);
+    assert_eq!(pool.healthy_instances(), mock.urls());
+
+    // Rate limits and 5xx still take instances out
+    mock.set_rate_limited(0, 3);
+    mock.set_down(1, true);
+    assert!(pool.get_feed("nasa").await.is_err());
+    assert!(pool.healthy_instances().is_empty());
+}
@@ -0,0 +1,310 @@
+// Events the bot publishes. Every event stays in the outbox until each relay accepted it, failed
+// deliveries are retried with backoff and the outbox is written to data/outbox so unsent events
+// survive restarts. Changes are written in batches, at most every SAVE_INTERVAL.
+
+use crate::relays::{Ack, RelayPool};
+use crate::utils;
+use log::{debug, error, warn};
+use serde_json::{json, Value};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+pub type Outbox = Arc<EventOutbox>;
+
+// Retries back off up to this delay
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(3600);
+
+const SAVE_INTERVAL: Duration = Duration::from_secs(1);
+
+struct Delivery {
+    relay: String,
+    attempts: u32,
+    next_try: Instant,
+    in_flight: bool,
+}
+
+struct Queued {
+    id: String,
+    queued_at: u64,
+    event: Value,
+    deliveries: Vec<Delivery>,
+}
+
+impl Queued {
+    fn entry(&self) -> Value {
+        let relays = self.deliveries.iter().map(|delivery| &delivery.relay).collect::<Vec<_>>();
+        json!({"queued_at": self.queued_at, "relays": relays, "event": self.event})
+    }
+
+    fn parse(line: &str) -> Option<Queued> {
+        let value: Value = serde_json::from_str(line).ok()?;
+        let event = value["event"].clone();
+        let deliveries = value["relays"]
+            .as_array()?
+            .iter()
+            .filter_map(|relay| relay.as_str())
+            .map(|relay| Delivery {
+                relay: relay.to_string(),
+                attempts: 0,
+                next_try: Instant::now(),
+                in_flight: false,
+            })
+            .collect();
+        Some(Queued {
+            id: event["id"].as_str()?.to_string(),
+            queued_at: value["queued_at"].as_u64()?,
+            event,
+            deliveries,
+        })
+    }
+}
+
+pub struct EventOutbox {
+    pool: RelayPool,
+    retry_delay: Duration,
+    // Deliveries still failing after this long are given up, None keeps trying
+    max_age: Option<Duration>,
+    file: PathBuf,
+    save_interval: Duration,
+    queue: std::sync::Mutex<Vec<Queued>>,
+    // Set when the queue changed since it was last written
+    changed: AtomicBool,
+    changes: Notify,
+    wakeup: Notify,
+}
+
+impl EventOutbox {
+    // Loads the events left in `file` and spawns the tasks publishing them and saving the changes
+    pub fn start(pool: RelayPool, retry_delay: Duration, max_age: Option<Duration>, file: PathBuf, save_interval: Duration) -> Outbox {
+        let mut queue = vec![];
+        if let Ok(content) = std::fs::read_to_string(&file) {
+            for line in content.lines().filter(|line| !line.is_empty()) {
+                match Queued::parse(line) {
+                    Some(queued) => queue.push(queued),
+                    None => warn!("Unable to parse outbox line >{}<, skipping", line),
+                }
+            }
+        }
+        if !queue.is_empty() {
+            warn!("Resending {} events left in {}", queue.len(), file.display());
+        }
+
+        let outbox = Arc::new(EventOutbox {
+            pool,
+            retry_delay,
+            max_age,
+            file,
+            save_interval,
+            queue: std::sync::Mutex::new(queue),
+            changed: AtomicBool::new(false),
+            changes: Notify::new(),
+            wakeup: Notify::new(),
+        });
+        tokio::spawn(outbox.clone().run());
+        tokio::spawn(outbox.clone().save_changes());
+        outbox
+    }
+
+    pub fn from_config(config: &utils::Config, pool: RelayPool, file: PathBuf) -> Outbox {
+        let max_age = match config.outbox_max_age_secs {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        };
+        EventOutbox::start(
+            pool,
+            Duration::from_secs(config.outbox_retry_secs),
+            max_age,
+            file,
+            SAVE_INTERVAL,
+        )
+    }
+
+    // Queues `event` for every default relay
+    pub fn send(&self, event: &nostr_bot::Event) {
+        self.send_to(event, &self.pool.defaults());
+    }
+
+    pub fn send_to(&self, event: &nostr_bot::Event, relays: &[String]) {
+        let event: Value = match serde_json::from_str::<Value>(&event.format()) {
+            Ok(message) => message[1].clone(),
+            Err(e) => {
+                error!("Unable to queue event {}: {}", event.id, e);
+                return;
+            }
+        };
+
+        let mut queue = self.queue.lock().unwrap();
+        queue.push(Queued {
+            id: event["id"].as_str().unwrap_or_default().to_string(),
+            queued_at: utils::unix_timestamp(),
+            event,
+            deliveries: relays
+                .iter()
+                .map(|relay| Delivery {
+                    relay: relay.clone(),
+                    attempts: 0,
+                    next_try: Instant::now(),
+                    in_flight: false,
+                })
+                .collect(),
+        });
+        drop(queue);
+        self.changed();
+        self.wakeup.notify_one();
+    }
+
+    // Events not accepted by every relay yet
+    pub fn pending(&self) -> usize {
+        self.queue.lock().unwrap().len()
+    }
+
+    // Has the queue written with the next batch
+    fn changed(&self) {
+        self.changed.store(true, Ordering::SeqCst);
+        self.changes.notify_one();
+    }
+
+    // Writes the queue after changes, one batch per save interval. The entries are taken under
+    // the queue lock, serialized and written on a blocking thread.
+    async fn save_changes(self: Arc<Self>) {
+        loop {
+            self.changes.notified().await;
+            tokio::time::sleep(self.save_interval).await;
+            if !self.changed.swap(false, Ordering::SeqCst) {
+                continue;
+            }
+            let entries = self.queue.lock().unwrap().iter().map(Queued::entry).collect::<Vec<_>>();
+            let file = self.file.clone();
+            match tokio::task::spawn_blocking(move || save(&file, &entries)).await {
+                Ok(Ok(())) => {}
+                // Tried again with the next batch
+                Ok(Err(e)) => {
+                    error!("Failed to write the outbox {}: {}", self.file.display(), e);
+                    self.changed();
+                }
+                Err(e) => error!("Failed to write the outbox {}: {}", self.file.display(), e),
+            }
+        }
+    }
+
+    async fn run(self: Arc<Self>) {
+        loop {
+            match self.dispatch() {
+                Some(next_try) => {
+                    tokio::select! {
+                        _ = tokio::time::sleep_until(next_try) => {}
+                        _ = self.wakeup.notified() => {}
+                    }
+                }
+                None => self.wakeup.notified().await,
+            }
+        }
+    }
+
+    // Starts the deliveries that are due, returns when the next one is
+    fn dispatch(self: &Arc<Self>) -> Option<Instant> {
+        let mut queue = self.queue.lock().unwrap();
+        let now = Instant::now();
+        let mut changed = false;
+
+        if let Some(max_age) = self.max_age {
+            let oldest = utils::unix_timestamp().saturating_sub(max_age.as_secs());
+            let before = queue.len();
+            queue.retain(|queued| {
+                let keep = queued.queued_at >= oldest;
+                if !keep {
+                    let relays = queued.deliveries.iter().map(|delivery| delivery.relay.as_str()).collect::<Vec<_>>();
+                    warn!("Giving up on event {}, not accepted by {}", queued.id, relays.join(", "));
+                }
+                keep
+            });
+            changed = queue.len() != before;
+        }
+
+        let mut next_try: Option<Instant> = None;
+        if let Some(max_age) = self.max_age {
+            // Wake up to give up on the oldest event even if none of its deliveries is due
+            if let Some(oldest) = queue.iter().map(|queued| queued.queued_at).min() {
+                let expires_in = (oldest + max_age.as_secs() + 1).saturating_sub(utils::unix_timestamp());
+                next_try = Some(now + Duration::from_secs(expires_in));
+            }
+        }
+        for queued in queue.iter_mut() {
+            for delivery in queued.deliveries.iter_mut().filter(|delivery| !delivery.in_flight) {
+                if delivery.next_try <= now {
+                    delivery.in_flight = true;
+                    let message = json!(["EVENT", queued.event]).to_string();
+                    tokio::spawn(self.clone().deliver(queued.id.clone(), delivery.relay.clone(), message));
+                } else {
+                    next_try = Some(next_try.map_or(delivery.next_try, |next_try| next_try.min(delivery.next_try)));
+                }
+            }
+        }
+        drop(queue);
+        if changed {
+            self.changed();
+        }
+        next_try
+    }
+
+    async fn deliver(self: Arc<Self>, id: String, relay: String, message: String) {
+        let ack = self.pool.publish(&relay, &id, message).await;
+
+        let mut queue = self.queue.lock().unwrap();
+        let index = match queue.iter().position(|queued| queued.id == id) {
+            Some(index) => index,
+            // Given up meanwhile
+            None => return,
+        };
+        let queued = &mut queue[index];
+        let delivery = match queued.deliveries.iter().position(|delivery| delivery.relay == relay) {
+            Some(delivery) => delivery,
+            None => return,
+        };
+
+        // Relays done with the event are saved so a restart doesn't send it to them again
+        let mut done = true;
+        match &ack {
+            Ack::Accepted => {
+                queued.deliveries.remove(delivery);
+            }
+            ack if ack.is_permanent() => {
+                warn!("{} rejected event {}: {:?}", relay, id, ack);
+                queued.deliveries.remove(delivery);
+            }
+            ack => {
+                let delivery = &mut queued.deliveries[delivery];
+                let delay = self.retry_delay.saturating_mul(1 << delivery.attempts.min(16)).min(MAX_RETRY_DELAY.max(self.retry_delay));
+                delivery.attempts += 1;
+                delivery.next_try = Instant::now() + delay;
+                delivery.in_flight = false;
+                debug!("Publishing event {} to {} failed ({:?}), retrying in {} s", id, relay, ack, delay.as_secs());
+                done = false;
+            }
+        }
+
+        if queued.deliveries.is_empty() {
+            queue.remove(index);
+        }
+        drop(queue);
+        if done {
+            self.changed();
+        }
+        self.wakeup.notify_one();
+    }
+}
+
+// Writes a temporary file and renames it over the outbox, a crash midway leaves the previous one
+fn save(file: &std::path::Path, entries: &[Value]) -> std::io::Result<()> {
+    let content = entries.iter().map(|entry| entry.to_string() + "\n").collect::<String>();
+    let temporary = file.with_extension("tmp");
+    std::fs::write(&temporary, content)?;
+    std::fs::rename(&temporary, file)
+}
+
+#[cfg(test)]
+mod tests;
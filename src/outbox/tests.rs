@@ -0,0 +1,141 @@
+use super::*;
+use crate::relays::Relays;
+use crate::testing::{MockRelay, TIMEOUT};
+
+const FAST_SAVES: Duration = Duration::from_millis(50);
+
+fn note(keypair: &secp256k1::KeyPair, content: &str) -> nostr_bot::Event {
+    nostr_bot::Event::new(keypair, utils::unix_timestamp(), 1, vec![], content.to_string())
+}
+
+fn outbox_file(keypair: &secp256k1::KeyPair) -> PathBuf {
+    std::env::temp_dir().join(format!("dostr-outbox-{}", keypair.x_only_public_key().0))
+}
+
+async fn wait_until_sent(outbox: &EventOutbox) {
+    let started = std::time::Instant::now();
+    while outbox.pending() > 0 {
+        assert!(started.elapsed() < TIMEOUT, "Timed out waiting for the outbox to empty");
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+}
+
+// Content of the outbox file once `saved` holds for it
+async fn wait_until_saved(file: &std::path::Path, saved: impl Fn(&str) -> bool) -> String {
+    let started = std::time::Instant::now();
+    loop {
+        let content = std::fs::read_to_string(file).unwrap_or_default();
+        if saved(&content) {
+            return content;
+        }
+        assert!(started.elapsed() < TIMEOUT, "Timed out waiting for the outbox to be saved, got {}", content);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+}
+
+#[tokio::test]
+async fn retries_until_the_relay_accepts() {
+    let relay = MockRelay::start().await;
+    let keypair = utils::get_random_keypair();
+    let pubkey = keypair.x_only_public_key().0.to_string();
+    let file = outbox_file(&keypair);
+    let outbox = EventOutbox::start(Relays::new(None, vec![relay.url().to_string()], None), Duration::from_millis(100), None, file.clone(), FAST_SAVES);
+
+    relay.set_rejecting(Some("rate-limited: slow down"));
+    let event = note(&keypair, "Liftoff!");
+    outbox.send(&event);
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert!(relay.events().is_empty());
+    assert_eq!(outbox.pending(), 1);
+    assert!(std::fs::read_to_string(&file).unwrap().contains(&event.id));
+
+    relay.set_rejecting(None);
+    let notes = relay.wait_for(&pubkey, 1, 1).await;
+    assert_eq!(notes[0]["content"], "Liftoff!");
+    wait_until_sent(&outbox).await;
+    wait_until_saved(&file, str::is_empty).await;
+    // Saved through a temporary file renamed over the outbox
+    assert!(!file.with_extension("tmp").exists());
+    std::fs::remove_file(file).unwrap();
+}
+
+#[tokio::test]
+async fn permanent_rejections_are_not_retried() {
+    let relay = MockRelay::start().await;
+    let keypair = utils::get_random_keypair();
+    let file = outbox_file(&keypair);
+    let outbox = EventOutbox::start(Relays::new(None, vec![relay.url().to_string()], None), Duration::from_millis(100), None, file.clone(), FAST_SAVES);
+
+    relay.set_rejecting(Some("blocked: not on the allow list"));
+    outbox.send(&note(&keypair, "Blocked"));
+    wait_until_sent(&outbox).await;
+
+    relay.set_rejecting(None);
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert!(relay.events().is_empty());
+    std::fs::remove_file(file).unwrap();
+}
+
+#[tokio::test]
+async fn unsent_events_survive_restarts() {
+    // A relay that isn't up yet
+    let address = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+    let url = format!("ws://{}", address);
+    let keypair = utils::get_random_keypair();
+    let file = outbox_file(&keypair);
+
+    let before_restart = EventOutbox::start(Relays::new(None, vec![url.clone()], None), Duration::from_secs(60), None, file.clone(), FAST_SAVES);
+    before_restart.send(&note(&keypair, "Sent while the relay was down"));
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(before_restart.pending(), 1);
+
+    let relay = MockRelay::start_on(&address.to_string()).await;
+    let after_restart = EventOutbox::start(Relays::new(None, vec![url], None), Duration::from_secs(60), None, file.clone(), FAST_SAVES);
+    assert_eq!(after_restart.pending(), 1);
+    let notes = relay.wait_for(&keypair.x_only_public_key().0.to_string(), 1, 1).await;
+    assert_eq!(notes[0]["content"], "Sent while the relay was down");
+    wait_until_sent(&after_restart).await;
+    std::fs::remove_file(file).unwrap();
+}
+
+#[tokio::test]
+async fn old_events_are_given_up() {
+    let address = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+    let keypair = utils::get_random_keypair();
+    let file = outbox_file(&keypair);
+    let outbox = EventOutbox::start(
+        Relays::new(None, vec![format!("ws://{}", address)], None),
+        Duration::from_millis(100),
+        Some(Duration::from_secs(1)),
+        file.clone(),
+        FAST_SAVES,
+    );
+
+    outbox.send(&note(&keypair, "Nobody will see this"));
+    assert_eq!(outbox.pending(), 1);
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    assert_eq!(outbox.pending(), 0);
+    std::fs::remove_file(file).unwrap();
+}
+
+#[tokio::test]
+async fn relays_that_took_an_event_are_saved() {
+    let relay = MockRelay::start().await;
+    let down = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+    let down = format!("ws://{}", down);
+    let keypair = utils::get_random_keypair();
+    let file = outbox_file(&keypair);
+    let relays = vec![relay.url().to_string(), down.clone()];
+    let outbox = EventOutbox::start(Relays::new(None, relays.clone(), None), Duration::from_secs(60), None, file.clone(), FAST_SAVES);
+
+    outbox.send(&note(&keypair, "Half delivered"));
+    relay.wait_for(&keypair.x_only_public_key().0.to_string(), 1, 1).await;
+    wait_until_saved(&file, |content| content.contains(&down) && !content.contains(relay.url())).await;
+
+    // After a restart only the relay that was down gets it
+    let after_restart = EventOutbox::start(Relays::new(None, relays, None), Duration::from_secs(60), None, file.clone(), FAST_SAVES);
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert_eq!(relay.events().len(), 1);
+    assert_eq!(after_restart.pending(), 1);
+    std::fs::remove_file(file).unwrap();
+}
@@ -0,0 +1,570 @@
+// Connections the bot publishes through. nostr-bot sends events without reading the answers of
+// the relays, the pool keeps a connection of its own to every relay and hands back the NIP-01 OK
+// message of each event it publishes. Relays that require NIP-42 AUTH get an AUTH event signed by
+// the signer of each event, its key or its bunker, so every mirror authenticates as itself. The
+// pool also keeps the default relays, ADD_RELAY or the list changed with !relay and saved to
+// data/relays, and what happened to every relay so far.
+
+use crate::error::{self, Error};
+use crate::signer::Signer;
+use crate::utils;
+use futures_util::{SinkExt, StreamExt};
+use log::debug;
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tungstenite::Message;
+
+pub type RelayPool = Arc<Relays>;
+
+pub type WebSocket = tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>;
+
+// How long to wait for the OK of an event
+const ACK_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Ack {
+    Accepted,
+    // OK false, with the reason the relay gave (e.g. "rate-limited: slow down")
+    Rejected(String),
+    // No connection, connection lost or no answer in time
+    Failed(String),
+}
+
+impl Ack {
+    // Rejections that trying again won't change
+    pub fn is_permanent(&self) -> bool {
+        match self {
+            Ack::Rejected(reason) => ["invalid:", "blocked:", "pow:", "restricted:", "mute:"]
+                .iter()
+                .any(|prefix| reason.starts_with(prefix)),
+            _ => false,
+        }
+    }
+
+    // What the relay or the connection said, empty for accepted events
+    pub fn reason(&self) -> &str {
+        match self {
+            Ack::Accepted => "",
+            Ack::Rejected(reason) | Ack::Failed(reason) => reason,
+        }
+    }
+}
+
+struct Publish {
+    id: String,
+    // Author of the event, the key to authenticate with
+    pubkey: String,
+    message: String,
+    ack: oneshot::Sender<Ack>,
+}
+
+// What happened to a relay since the bot started, for !relays
+#[derive(Clone, Debug, Default)]
+pub struct RelayStatus {
+    pub connected: bool,
+    pub accepted: u64,
+    pub rejected: u64,
+    pub failed: u64,
+    pub last_ok: Option<u64>,
+    // Timestamp and reason of the last rejection or failure
+    pub last_error: Option<(u64, String)>,
+    // The relay rejected events with auth-required:
+    pub auth_required: bool,
+    // Public keys the relay accepted an AUTH of
+    pub authenticated: HashSet<String>,
+    pub auth_error: Option<String>,
+}
+
+type Statuses = Arc<Mutex<HashMap<String, RelayStatus>>>;
+
+// Signers of the main bot and the mirrors by public key, to sign AUTH events with
+type Keys = Arc<Mutex<HashMap<String, Signer>>>;
+
+// AUTH events signed for a connection, by the public key they authenticate
+type Signed = mpsc::UnboundedSender<(String, error::Result<nostr_bot::Event>)>;
+
+fn ago(timestamp: u64) -> String {
+    compound_duration::format_dhms(utils::unix_timestamp().saturating_sub(timestamp)) + " ago"
+}
+
+impl std::fmt::Display for RelayStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.accepted + self.rejected + self.failed == 0 && !self.connected {
+            return write!(f, "nothing sent yet");
+        }
+        let connection = if self.connected { "connected" } else { "disconnected" };
+        write!(f, "{}, {} accepted, {} rejected, {} failed", connection, self.accepted, self.rejected, self.failed)?;
+        if let Some(last_ok) = self.last_ok {
+            write!(f, ", last OK {}", ago(last_ok))?;
+        }
+        if let Some((at, reason)) = &self.last_error {
+            write!(f, ", last error {}: {}", ago(*at), reason)?;
+        }
+        if self.auth_required {
+            write!(f, ", requires AUTH ({} keys authenticated)", self.authenticated.len())?;
+        }
+        if let Some(reason) = &self.auth_error {
+            write!(f, ", last AUTH error: {}", reason)?;
+        }
+        Ok(())
+    }
+}
+
+pub struct Relays {
+    connections: Mutex<HashMap<String, mpsc::Sender<Publish>>>,
+    statuses: Statuses,
+    keys: Keys,
+    // Where events go unless a feed has relays of its own
+    defaults: Mutex<Vec<String>>,
+    // Saved defaults, None keeps changes in memory
+    file: Option<PathBuf>,
+    // Tor SOCKS proxy with --tor
+    proxy: Option<String>,
+}
+
+// Websocket connection to `relay`, through the SOCKS proxy at `proxy` if given
+pub async fn connect(relay: &str, proxy: Option<&str>) -> error::Result<WebSocket> {
+    let connect = async {
+        match proxy {
+            Some(proxy) => {
+                let url = url::Url::parse(relay).map_err(|e| Error::Parse(format!("Invalid relay URL {}: {}", relay, e)))?;
+                let host = url.host_str().ok_or_else(|| Error::Parse(format!("Relay URL {} has no host", relay)))?;
+                let port = url.port_or_known_default().unwrap_or(443);
+                let stream = tokio_socks::tcp::Socks5Stream::connect(proxy, (host, port))
+                    .await
+                    .map_err(|e| Error::Network(e.to_string()))?;
+                tokio_tungstenite::client_async_tls(relay, stream.into_inner()).await
+            }
+            None => tokio_tungstenite::connect_async(relay).await,
+        }
+        .map(|(ws, _)| ws)
+        .map_err(|e| Error::Network(e.to_string()))
+    };
+
+    tokio::time::timeout(Duration::from_secs(30), connect)
+        .await
+        .map_err(|_| Error::Network("Timed out connecting".to_string()))?
+}
+
+// Event id and answer of an OK message
+fn parse_ok(text: &str) -> Option<(String, Ack)> {
+    let message: Value = serde_json::from_str(text).ok()?;
+    if message[0] != "OK" {
+        return None;
+    }
+    let id = message[1].as_str()?.to_string();
+    let ack = if message[2].as_bool()? {
+        Ack::Accepted
+    } else {
+        Ack::Rejected(message[3].as_str().unwrap_or_default().to_string())
+    };
+    Some((id, ack))
+}
+
+enum Auth {
+    // The signer is signing the AUTH event, events of the key wait for it
+    Signing(Vec<Publish>),
+    // Waiting for the OK of the AUTH event with this id, events of the key wait with it
+    Pending(String, Vec<Publish>),
+    Done,
+    Failed,
+}
+
+type Sink = futures_util::stream::SplitSink<WebSocket, Message>;
+
+// One websocket connection of run_connection
+struct Connection {
+    relay: String,
+    sink: Sink,
+    statuses: Statuses,
+    keys: Keys,
+    signed: Signed,
+    // Events sent and waiting for their OK, by id
+    waiting: HashMap<String, Publish>,
+    challenge: Option<String>,
+    // Once a relay asked for AUTH, further keys authenticate before their first event
+    auth_required: bool,
+    auths: HashMap<String, Auth>,
+}
+
+impl Connection {
+    fn status(&self) -> std::sync::MutexGuard<'_, HashMap<String, RelayStatus>> {
+        self.statuses.lock().unwrap()
+    }
+
+    // Sends `publish` or holds it back until its key authenticated, false if the connection broke
+    async fn submit(&mut self, publish: Publish) -> bool {
+        match self.auths.get_mut(&publish.pubkey) {
+            Some(Auth::Signing(held)) | Some(Auth::Pending(_, held)) => {
+                held.push(publish);
+                return true;
+            }
+            None if self.auth_required => return self.authenticate(publish).await,
+            _ => {}
+        }
+
+        if let Err(e) = self.sink.send(Message::Text(publish.message.clone())).await {
+            let _ = publish.ack.send(Ack::Failed(e.to_string()));
+            return false;
+        }
+        // Forget events whose publisher stopped waiting
+        self.waiting.retain(|_, waiting| !waiting.ack.is_closed());
+        self.waiting.insert(publish.id.clone(), publish);
+        true
+    }
+
+    // Has the AUTH for the key of `publish` signed, the event goes out again once the relay answered
+    async fn authenticate(&mut self, publish: Publish) -> bool {
+        let signer = self.keys.lock().unwrap().get(&publish.pubkey).cloned();
+        let (signer, challenge) = match (signer, self.challenge.clone()) {
+            (Some(signer), Some(challenge)) => (signer, challenge),
+            // Without a signer or challenge there is nothing to authenticate with
+            _ => {
+                self.auths.insert(publish.pubkey.clone(), Auth::Failed);
+                return Box::pin(self.submit(publish)).await;
+            }
+        };
+
+        let auth = nostr_bot::EventNonSigned {
+            created_at: utils::unix_timestamp(),
+            kind: 22242,
+            tags: vec![
+                vec!["relay".to_string(), self.relay.clone()],
+                vec!["challenge".to_string(), challenge],
+            ],
+            content: String::new(),
+        };
+        // Bunkers may take a while to sign, the connection goes on meanwhile
+        let (pubkey, signed) = (publish.pubkey.clone(), self.signed.clone());
+        tokio::spawn(async move {
+            let _ = signed.send((pubkey, signer.sign(auth).await));
+        });
+        self.auths.insert(publish.pubkey.clone(), Auth::Signing(vec![publish]));
+        true
+    }
+
+    // Sends the AUTH signed for `pubkey`, false if the connection broke
+    async fn send_auth(&mut self, pubkey: String, event: error::Result<nostr_bot::Event>) -> bool {
+        let held = match self.auths.remove(&pubkey) {
+            Some(Auth::Signing(held)) => held,
+            other => {
+                self.auths.extend(other.map(|auth| (pubkey, auth)));
+                return true;
+            }
+        };
+        let auth = event.and_then(|event| {
+            let message = serde_json::from_str::<Value>(&event.format()).map_err(|e| Error::Parse(e.to_string()))?;
+            Ok((event.id, json!(["AUTH", message[1]]).to_string()))
+        });
+        let (id, auth) = match auth {
+            Ok(auth) => auth,
+            Err(e) => {
+                debug!("Unable to sign the AUTH for {} as {}: {}", self.relay, pubkey, e);
+                let relay = self.relay.clone();
+                self.status().entry(relay).or_default().auth_error = Some(e.to_string());
+                self.auths.insert(pubkey, Auth::Failed);
+                return self.submit_all(held).await;
+            }
+        };
+
+        debug!("Authenticating to {} as {}", self.relay, pubkey);
+        if self.sink.send(Message::Text(auth)).await.is_err() {
+            return false;
+        }
+        self.auths.insert(pubkey, Auth::Pending(id, held));
+        true
+    }
+
+    async fn submit_all(&mut self, publishes: Vec<Publish>) -> bool {
+        for publish in publishes {
+            if !self.submit(publish).await {
+                return false;
+            }
+        }
+        true
+    }
+
+    // Handles a message of the relay, false if the connection broke
+    async fn receive(&mut self, text: &str) -> bool {
+        let message: Value = match serde_json::from_str(text) {
+            Ok(message) => message,
+            Err(_) => return true,
+        };
+        if message[0] == "AUTH" {
+            self.challenge = message[1].as_str().map(String::from);
+            return true;
+        }
+        let (id, ack) = match parse_ok(text) {
+            Some(ok) => ok,
+            None => return true,
+        };
+
+        let authenticated = self.auths.iter().find_map(|(pubkey, auth)| match auth {
+            Auth::Pending(auth_id, _) if *auth_id == id => Some(pubkey.clone()),
+            _ => None,
+        });
+        if let Some(pubkey) = authenticated {
+            let accepted = ack == Ack::Accepted;
+            let held = match self.auths.insert(pubkey.clone(), if accepted { Auth::Done } else { Auth::Failed }) {
+                Some(Auth::Pending(_, held)) => held,
+                _ => vec![],
+            };
+            {
+                let relay = self.relay.clone();
+                let mut statuses = self.status();
+                let status = statuses.entry(relay).or_default();
+                if accepted {
+                    status.authenticated.insert(pubkey);
+                } else {
+                    debug!("AUTH as {} failed: {:?}", pubkey, ack);
+                    status.auth_error = Some(ack.reason().to_string());
+                }
+            }
+            // Events of a key the relay didn't take the AUTH of go out anyway and get its answer
+            return self.submit_all(held).await;
+        }
+
+        let publish = match self.waiting.remove(&id) {
+            Some(publish) => publish,
+            None => return true,
+        };
+        match &ack {
+            Ack::Rejected(reason) if reason.starts_with("auth-required:") && !self.auths.contains_key(&publish.pubkey) => {
+                self.auth_required = true;
+                let relay = self.relay.clone();
+                self.status().entry(relay).or_default().auth_required = true;
+                self.authenticate(publish).await
+            }
+            _ => {
+                let _ = publish.ack.send(ack);
+                true
+            }
+        }
+    }
+}
+
+// Connects once there is something to publish and stays connected until the relay goes away
+async fn run_connection(relay: String, proxy: Option<String>, statuses: Statuses, keys: Keys, mut requests: mpsc::Receiver<Publish>) {
+    let set_connected = |connected: bool| {
+        statuses.lock().unwrap().entry(relay.clone()).or_default().connected = connected;
+    };
+    while let Some(first) = requests.recv().await {
+        let ws = match connect(&relay, proxy.as_deref()).await {
+            Ok(ws) => ws,
+            Err(e) => {
+                set_connected(false);
+                debug!("Unable to connect to {}: {}", relay, e);
+                let _ = first.ack.send(Ack::Failed(e.to_string()));
+                // Whatever was queued meanwhile fails the same way instead of connecting again
+                while let Ok(publish) = requests.try_recv() {
+                    let _ = publish.ack.send(Ack::Failed(e.to_string()));
+                }
+                continue;
+            }
+        };
+        set_connected(true);
+        let (sink, mut stream) = ws.split();
+        let (signed, mut signed_auths) = mpsc::unbounded_channel();
+        let mut connection = Connection {
+            relay: relay.clone(),
+            sink,
+            statuses: statuses.clone(),
+            keys: keys.clone(),
+            signed,
+            waiting: HashMap::new(),
+            challenge: None,
+            auth_required: false,
+            auths: HashMap::new(),
+        };
+        let mut next = Some(first);
+
+        loop {
+            if let Some(publish) = next.take() {
+                if !connection.submit(publish).await {
+                    break;
+                }
+            }
+
+            tokio::select! {
+                publish = requests.recv() => match publish {
+                    Some(publish) => next = Some(publish),
+                    None => return,
+                },
+                Some((pubkey, event)) = signed_auths.recv() => {
+                    if !connection.send_auth(pubkey, event).await {
+                        break;
+                    }
+                }
+                message = stream.next() => match message {
+                    Some(Ok(Message::Text(text))) => {
+                        if !connection.receive(&text).await {
+                            break;
+                        }
+                    }
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => {
+                        debug!("Lost the connection to {}", relay);
+                        break;
+                    }
+                    Some(Ok(_)) => {}
+                },
+            }
+        }
+        set_connected(false);
+        // Events still waiting for their OK or AUTH fail when `connection` is dropped
+    }
+}
+
+impl Relays {
+    pub fn new(proxy: Option<String>, defaults: Vec<String>, file: Option<PathBuf>) -> RelayPool {
+        Arc::new(Relays {
+            connections: Mutex::new(HashMap::new()),
+            statuses: Arc::new(Mutex::new(HashMap::new())),
+            keys: Arc::new(Mutex::new(HashMap::new())),
+            defaults: Mutex::new(defaults),
+            file,
+            proxy,
+        })
+    }
+
+    // The relays saved in `file` replace ADD_RELAY once !relay changed them
+    pub fn from_config(config: &utils::Config, file: PathBuf) -> RelayPool {
+        let saved = std::fs::read_to_string(&file)
+            .map(|content| content.lines().map(str::trim).filter(|line| !line.is_empty()).map(String::from).collect::<Vec<_>>())
+            .unwrap_or_default();
+        let defaults = if saved.is_empty() { config.relays.clone() } else { saved };
+        Relays::new(config.use_tor.then(|| config.tor_socks_address.clone()), defaults, Some(file))
+    }
+
+    // Lets events signed by `keypair` authenticate to relays that require AUTH
+    pub fn add_key(&self, keypair: &secp256k1::KeyPair) {
+        self.add_signer(&keypair.x_only_public_key().0, Signer::Local(*keypair));
+    }
+
+    // Same for events of `pubkey` signed by `signer`, e.g. a bunker
+    pub fn add_signer(&self, pubkey: &secp256k1::XOnlyPublicKey, signer: Signer) {
+        self.keys.lock().unwrap().insert(pubkey.to_string(), signer);
+    }
+
+    pub fn defaults(&self) -> Vec<String> {
+        self.defaults.lock().unwrap().clone()
+    }
+
+    // Adds `relay` to the defaults, false if it is one already
+    pub fn add(&self, relay: &str) -> error::Result<bool> {
+        let mut defaults = self.defaults.lock().unwrap();
+        if defaults.iter().any(|default| default == relay) {
+            return Ok(false);
+        }
+        let mut changed = defaults.clone();
+        changed.push(relay.to_string());
+        self.save(&changed)?;
+        *defaults = changed;
+        Ok(true)
+    }
+
+    // Removes `relay` from the defaults, false if it isn't one
+    pub fn remove(&self, relay: &str) -> error::Result<bool> {
+        let mut defaults = self.defaults.lock().unwrap();
+        if !defaults.iter().any(|default| default == relay) {
+            return Ok(false);
+        }
+        let changed = defaults.iter().filter(|default| *default != relay).cloned().collect::<Vec<_>>();
+        self.save(&changed)?;
+        *defaults = changed;
+        Ok(true)
+    }
+
+    fn save(&self, defaults: &[String]) -> error::Result<()> {
+        match &self.file {
+            Some(file) => std::fs::write(file, defaults.join("\n") + "\n")
+                .map_err(|e| Error::Storage(format!("Failed to write {}: {}", file.display(), e))),
+            None => Ok(()),
+        }
+    }
+
+    // Status of every relay the bot published to or has as a default
+    pub fn statuses(&self) -> Vec<(String, RelayStatus)> {
+        let mut statuses = self.statuses.lock().unwrap().clone();
+        for relay in self.defaults() {
+            statuses.entry(relay).or_default();
+        }
+        let mut statuses = statuses.into_iter().collect::<Vec<_>>();
+        statuses.sort_by(|a, b| a.0.cmp(&b.0));
+        statuses
+    }
+
+    fn record(&self, relay: &str, ack: &Ack) {
+        let mut statuses = self.statuses.lock().unwrap();
+        let status = statuses.entry(relay.to_string()).or_default();
+        let now = utils::unix_timestamp();
+        match ack {
+            Ack::Accepted => {
+                status.accepted += 1;
+                status.last_ok = Some(now);
+            }
+            Ack::Rejected(reason) => {
+                status.rejected += 1;
+                status.last_error = Some((now, reason.clone()));
+            }
+            Ack::Failed(reason) => {
+                status.failed += 1;
+                status.last_error = Some((now, reason.clone()));
+            }
+        }
+    }
+
+    fn connection(&self, relay: &str) -> mpsc::Sender<Publish> {
+        let mut connections = self.connections.lock().unwrap();
+        connections
+            .entry(relay.to_string())
+            .or_insert_with(|| {
+                let (requests, receiver) = mpsc::channel(64);
+                tokio::spawn(run_connection(
+                    relay.to_string(),
+                    self.proxy.clone(),
+                    self.statuses.clone(),
+                    self.keys.clone(),
+                    receiver,
+                ));
+                requests
+            })
+            .clone()
+    }
+
+    // Sends `message`, an EVENT message of the event with `id`, to `relay` and waits for its OK
+    pub async fn publish(&self, relay: &str, id: &str, message: String) -> Ack {
+        let ack = self.send(relay, id, message).await;
+        self.record(relay, &ack);
+        ack
+    }
+
+    async fn send(&self, relay: &str, id: &str, message: String) -> Ack {
+        let (ack, acked) = oneshot::channel();
+        let pubkey = serde_json::from_str::<Value>(&message)
+            .ok()
+            .and_then(|message| message[1]["pubkey"].as_str().map(String::from))
+            .unwrap_or_default();
+        let publish = Publish {
+            id: id.to_string(),
+            pubkey,
+            message,
+            ack,
+        };
+        if self.connection(relay).send(publish).await.is_err() {
+            return Ack::Failed("The connection task stopped".to_string());
+        }
+
+        match tokio::time::timeout(ACK_TIMEOUT, acked).await {
+            Ok(Ok(ack)) => ack,
+            Ok(Err(_)) => Ack::Failed("Lost the connection".to_string()),
+            Err(_) => Ack::Failed("No answer from the relay".to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,100 @@
+use super::*;
+use crate::signer::Bunker;
+use crate::testing::{MockBunker, MockNitter, MockRelay, TestBot};
+
+// Id and EVENT message of a note
+fn note(keypair: &secp256k1::KeyPair, content: &str) -> (String, String) {
+    let event = nostr_bot::Event::new(keypair, utils::unix_timestamp(), 1, vec![], content.to_string());
+    (event.id.clone(), event.format())
+}
+
+#[tokio::test]
+async fn changed_relays_replace_add_relay_after_restart() {
+    let (relay, nitter) = (MockRelay::start().await, MockNitter::start().await);
+    let keypair = utils::get_random_keypair();
+    let dir = std::env::temp_dir();
+    let config = TestBot::config(&relay, &nitter, &keypair, &keypair, &dir);
+    let file = dir.join(format!("dostr-relays-{}", keypair.x_only_public_key().0));
+
+    let pool = Relays::from_config(&config, file.clone());
+    assert_eq!(pool.defaults(), config.relays);
+    assert!(pool.add("wss://nos.lol").unwrap());
+    assert!(!pool.add("wss://nos.lol").unwrap());
+    assert!(pool.remove(relay.url()).unwrap());
+    assert!(!pool.remove(relay.url()).unwrap());
+
+    let restarted = Relays::from_config(&config, file.clone());
+    assert_eq!(restarted.defaults(), vec!["wss://nos.lol".to_string()]);
+    std::fs::remove_file(file).unwrap();
+}
+
+#[tokio::test]
+async fn publish_keeps_count_of_answers() {
+    let relay = MockRelay::start().await;
+    let pool = Relays::new(None, vec![relay.url().to_string()], None);
+    let keypair = utils::get_random_keypair();
+
+    let (id, message) = note(&keypair, "Accepted");
+    assert_eq!(pool.publish(relay.url(), &id, message).await, Ack::Accepted);
+    relay.set_rejecting(Some("rate-limited: slow down"));
+    let (id, message) = note(&keypair, "Rejected");
+    assert_eq!(pool.publish(relay.url(), &id, message).await, Ack::Rejected("rate-limited: slow down".to_string()));
+
+    let statuses = pool.statuses();
+    let (url, status) = &statuses[0];
+    assert_eq!(url, relay.url());
+    assert!(status.connected);
+    assert_eq!((status.accepted, status.rejected, status.failed), (1, 1, 0));
+    assert!(status.last_ok.is_some());
+    assert_eq!(status.last_error.as_ref().unwrap().1, "rate-limited: slow down");
+    assert!(status.to_string().starts_with("connected, 1 accepted, 1 rejected, 0 failed, last OK"), "{}", status);
+
+    let unreachable = "ws://127.0.0.1:1";
+    let (id, message) = note(&keypair, "Lost");
+    assert!(matches!(pool.publish(unreachable, &id, message).await, Ack::Failed(_)));
+    assert_eq!(pool.statuses().len(), 2);
+}
+
+#[tokio::test]
+async fn every_key_authenticates_as_itself() {
+    let relay = MockRelay::start().await;
+    relay.set_requiring_auth(true);
+    let pool = Relays::new(None, vec![relay.url().to_string()], None);
+    let (first, second, unknown) = (utils::get_random_keypair(), utils::get_random_keypair(), utils::get_random_keypair());
+    pool.add_key(&first);
+    pool.add_key(&second);
+
+    // The first event is rejected with auth-required:, authenticates and goes out again
+    let (id, message) = note(&first, "First");
+    assert_eq!(pool.publish(relay.url(), &id, message).await, Ack::Accepted);
+    // Further keys authenticate before their first event
+    let (id, message) = note(&second, "Second");
+    assert_eq!(pool.publish(relay.url(), &id, message).await, Ack::Accepted);
+    let (id, message) = note(&unknown, "Unknown");
+    assert!(matches!(pool.publish(relay.url(), &id, message).await, Ack::Rejected(reason) if reason.starts_with("auth-required:")));
+
+    let pubkeys = [&first, &second].map(|keypair| keypair.x_only_public_key().0.to_string());
+    assert_eq!(relay.authenticated(), pubkeys);
+    assert_eq!(relay.events().len(), 2);
+    let status = &pool.statuses()[0].1;
+    assert!(status.auth_required);
+    assert!(status.to_string().contains("requires AUTH (2 keys authenticated)"), "{}", status);
+}
+
+#[tokio::test]
+async fn bunkers_sign_the_auth_of_their_keys() {
+    let relay = MockRelay::start().await;
+    relay.set_requiring_auth(true);
+    let pool = Relays::new(None, vec![relay.url().to_string()], None);
+    let user = utils::get_random_keypair();
+    let bunker = MockBunker::start(user, Some("s3cret")).await;
+    let signer = Signer::Remote(Arc::new(Bunker::parse(&bunker.uri(Some("s3cret")), utils::get_random_keypair(), None).unwrap()));
+    pool.add_signer(&user.x_only_public_key().0, signer);
+
+    let (id, message) = note(&user, "Remote");
+    assert_eq!(pool.publish(relay.url(), &id, message).await, Ack::Accepted);
+    assert_eq!(relay.authenticated(), vec![user.x_only_public_key().0.to_string()]);
+    let signed = bunker.signed();
+    assert_eq!(signed.len(), 1);
+    assert_eq!(signed[0]["kind"], 22242);
+}
@@ -0,0 +1,85 @@
+// Polling schedule of a single feed. Feeds that post often are polled at REFRESH_INTERVAL_SECS,
+// quiet ones slow down to MAX_REFRESH_INTERVAL_SECS and failing ones back off exponentially.
+// Every delay gets some jitter so the workers don't hit Nitter all at once.
+
+use rand::Rng;
+use std::time::Duration;
+
+// Delays are randomly stretched or shortened by up to this fraction
+const JITTER: f64 = 0.1;
+
+pub struct PollSchedule {
+    base: Duration,
+    max: Duration,
+    current: Duration,
+    fixed: Option<Duration>,
+    failures: u32,
+    retry_after: Option<Duration>,
+}
+
+impl PollSchedule {
+    pub fn new(base_secs: u64, max_secs: u64) -> PollSchedule {
+        let base = Duration::from_secs(base_secs);
+        PollSchedule {
+            base,
+            max: Duration::from_secs(max_secs).max(base),
+            current: base,
+            fixed: None,
+            failures: 0,
+            retry_after: None,
+        }
+    }
+
+    pub fn from_config(config: &crate::utils::Config) -> PollSchedule {
+        PollSchedule::new(config.refresh_interval_secs, config.max_refresh_interval_secs)
+    }
+
+    // Per-feed override from the database, None goes back to the adaptive interval
+    pub fn set_fixed(&mut self, interval_secs: Option<u64>) {
+        self.fixed = interval_secs.map(Duration::from_secs);
+    }
+
+    // Interval before the next poll, without jitter
+    pub fn interval(&self) -> Duration {
+        let interval = self.fixed.unwrap_or(self.current);
+        if self.failures == 0 {
+            return interval;
+        }
+
+        let backoff = interval
+            .saturating_mul(2u32.saturating_pow(self.failures))
+            .min(self.max.max(interval));
+        backoff.max(self.retry_after.unwrap_or_default())
+    }
+
+    pub fn next_delay(&self) -> Duration {
+        jittered(self.interval())
+    }
+
+    // Workers start at a random point of their first interval instead of all at once
+    pub fn initial_delay(&self) -> Duration {
+        self.interval().mul_f64(rand::thread_rng().gen_range(0.0..=1.0))
+    }
+
+    pub fn on_success(&mut self, new_items: usize) {
+        self.failures = 0;
+        self.retry_after = None;
+        self.current = if new_items > 0 {
+            (self.current / 2).max(self.base)
+        } else {
+            (self.current * 5 / 4).min(self.max)
+        };
+    }
+
+    pub fn on_error(&mut self, retry_after: Option<Duration>) {
+        self.failures = self.failures.saturating_add(1);
+        self.retry_after = retry_after;
+    }
+}
+
+fn jittered(delay: Duration) -> Duration {
+    delay.mul_f64(1.0 + rand::thread_rng().gen_range(-JITTER..=JITTER))
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,79 @@
+use super::*;
+
+fn secs(secs: u64) -> Duration {
+    Duration::from_secs(secs)
+}
+
+#[test]
+fn quiet_feeds_slow_down_and_active_ones_speed_up() {
+    let mut schedule = PollSchedule::new(60, 300);
+    assert_eq!(schedule.interval(), secs(60));
+
+    schedule.on_success(0);
+    assert_eq!(schedule.interval(), secs(75));
+    for _ in 0..20 {
+        schedule.on_success(0);
+    }
+    assert_eq!(schedule.interval(), secs(300));
+
+    schedule.on_success(3);
+    assert_eq!(schedule.interval(), secs(150));
+    for _ in 0..5 {
+        schedule.on_success(1);
+    }
+    assert_eq!(schedule.interval(), secs(60));
+}
+
+#[test]
+fn errors_back_off_until_the_next_success() {
+    let mut schedule = PollSchedule::new(60, 600);
+
+    schedule.on_error(None);
+    assert_eq!(schedule.interval(), secs(120));
+    schedule.on_error(None);
+    assert_eq!(schedule.interval(), secs(240));
+    for _ in 0..40 {
+        schedule.on_error(None);
+    }
+    assert_eq!(schedule.interval(), secs(600));
+
+    schedule.on_success(1);
+    assert_eq!(schedule.interval(), secs(60));
+}
+
+#[test]
+fn rate_limits_wait_at_least_retry_after() {
+    let mut schedule = PollSchedule::new(60, 600);
+
+    schedule.on_error(Some(secs(900)));
+    assert_eq!(schedule.interval(), secs(900));
+    schedule.on_error(Some(secs(10)));
+    assert_eq!(schedule.interval(), secs(240));
+}
+
+#[test]
+fn fixed_intervals_override_the_adaptive_one() {
+    let mut schedule = PollSchedule::new(60, 600);
+    schedule.set_fixed(Some(30));
+    for _ in 0..5 {
+        schedule.on_success(0);
+    }
+    assert_eq!(schedule.interval(), secs(30));
+
+    schedule.on_error(None);
+    assert_eq!(schedule.interval(), secs(60));
+
+    schedule.set_fixed(None);
+    schedule.on_success(0);
+    assert!(schedule.interval() > secs(60));
+}
+
+#[test]
+fn delays_are_jittered_within_bounds() {
+    let schedule = PollSchedule::new(100, 100);
+    for _ in 0..100 {
+        let delay = schedule.next_delay();
+        assert!(delay >= secs(90) && delay <= secs(110), "{:?}", delay);
+        assert!(schedule.initial_delay() <= secs(100));
+    }
+}
@@ -0,0 +1,181 @@
+// Central queue of feed polls. Workers ask for a turn instead of sleeping on their own, turns are
+// handed out when they are due, at most MAX_CONCURRENT_FETCHES at a time and the most overdue
+// first. HostLimits caps the requests to a single host on top of that.
+
+use log::debug;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{oneshot, Notify, OwnedSemaphorePermit, Semaphore};
+use tokio::time::Instant;
+
+pub type Scheduler = Arc<FetchScheduler>;
+
+struct Queued {
+    due: Instant,
+    seq: u64,
+    grant: oneshot::Sender<OwnedSemaphorePermit>,
+}
+
+impl PartialEq for Queued {
+    fn eq(&self, other: &Self) -> bool {
+        (self.due, self.seq) == (other.due, other.seq)
+    }
+}
+
+impl Eq for Queued {}
+
+impl PartialOrd for Queued {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Queued {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        (self.due, self.seq).cmp(&(other.due, other.seq))
+    }
+}
+
+pub struct FetchScheduler {
+    queue: std::sync::Mutex<BinaryHeap<Reverse<Queued>>>,
+    seq: std::sync::atomic::AtomicU64,
+    wakeup: Notify,
+    slots: Arc<Semaphore>,
+}
+
+// Permission to poll, the slot is given back when the turn is dropped
+pub struct Turn {
+    _slot: OwnedSemaphorePermit,
+}
+
+impl FetchScheduler {
+    // Creates the scheduler and spawns the task handing out turns
+    pub fn start(max_concurrent: usize) -> Scheduler {
+        let scheduler = Arc::new(FetchScheduler {
+            queue: std::sync::Mutex::new(BinaryHeap::new()),
+            seq: std::sync::atomic::AtomicU64::new(0),
+            wakeup: Notify::new(),
+            slots: Arc::new(Semaphore::new(max_concurrent.max(1))),
+        });
+        tokio::spawn(scheduler.clone().dispatch());
+        scheduler
+    }
+
+    pub fn from_config(config: &crate::utils::Config) -> Scheduler {
+        FetchScheduler::start(config.max_concurrent_fetches)
+    }
+
+    // Waits until `delay` has passed and a slot is free
+    pub async fn turn(&self, delay: Duration) -> Turn {
+        let (grant, granted) = oneshot::channel();
+        let seq = self.seq.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        self.queue.lock().unwrap().push(Reverse(Queued {
+            due: Instant::now() + delay,
+            seq,
+            grant,
+        }));
+        self.wakeup.notify_one();
+
+        Turn {
+            _slot: granted.await.expect("The fetch scheduler stopped"),
+        }
+    }
+
+    async fn dispatch(self: Arc<Self>) {
+        loop {
+            let next_due = self.queue.lock().unwrap().peek().map(|Reverse(queued)| queued.due);
+            match next_due {
+                None => self.wakeup.notified().await,
+                Some(due) if due > Instant::now() => {
+                    tokio::select! {
+                        _ = tokio::time::sleep_until(due) => {}
+                        _ = self.wakeup.notified() => {}
+                    }
+                }
+                Some(_) => {
+                    let slot = self.slots.clone().acquire_owned().await.unwrap();
+                    // Whatever waited the longest goes first, new turns may have come in while
+                    // waiting for the slot
+                    let queued = self.queue.lock().unwrap().pop();
+                    if let Some(Reverse(queued)) = queued {
+                        let overdue = Instant::now().saturating_duration_since(queued.due);
+                        if overdue > Duration::from_secs(1) {
+                            debug!("Starting a fetch {} s late", overdue.as_secs());
+                        }
+                        // A worker that went away gives the slot back right away
+                        let _ = queued.grant.send(slot);
+                    }
+                }
+            }
+        }
+    }
+}
+
+struct HostState {
+    slots: Arc<Semaphore>,
+    next_request: Instant,
+}
+
+// Per-host limits: at most `max_concurrent` requests in flight and requests started at least
+// `interval` apart
+pub struct HostLimits {
+    max_concurrent: usize,
+    interval: Duration,
+    hosts: std::sync::Mutex<HashMap<String, HostState>>,
+}
+
+// Held for the duration of a request
+pub struct HostPermit {
+    _slot: OwnedSemaphorePermit,
+}
+
+impl HostLimits {
+    pub fn new(max_concurrent: usize, interval: Duration) -> HostLimits {
+        HostLimits {
+            max_concurrent: max_concurrent.max(1),
+            interval,
+            hosts: std::sync::Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn from_config(config: &crate::utils::Config) -> HostLimits {
+        HostLimits::new(
+            config.max_fetches_per_host,
+            Duration::from_millis(config.host_request_interval_ms),
+        )
+    }
+
+    pub async fn acquire(&self, url: &str) -> HostPermit {
+        let host = url::Url::parse(url)
+            .ok()
+            .and_then(|url| url.host_str().map(|host| format!("{}:{}", host, url.port_or_known_default().unwrap_or_default())))
+            .unwrap_or_default();
+
+        let slots = {
+            let mut hosts = self.hosts.lock().unwrap();
+            let state = hosts.entry(host.clone()).or_insert_with(|| HostState {
+                slots: Arc::new(Semaphore::new(self.max_concurrent)),
+                next_request: Instant::now(),
+            });
+            state.slots.clone()
+        };
+        let slot = slots.acquire_owned().await.unwrap();
+
+        // Reserve the next start time of the host, then wait for it
+        let start = {
+            let mut hosts = self.hosts.lock().unwrap();
+            let state = hosts.get_mut(&host).unwrap();
+            let start = state.next_request.max(Instant::now());
+            state.next_request = start + self.interval;
+            start
+        };
+        tokio::time::sleep_until(start).await;
+
+        HostPermit { _slot: slot }
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,65 @@
+use super::*;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+#[tokio::test]
+async fn turns_respect_the_global_limit() {
+    let scheduler = FetchScheduler::start(2);
+    let running = Arc::new(AtomicUsize::new(0));
+    let most = Arc::new(AtomicUsize::new(0));
+
+    let polls = (0..6).map(|_| {
+        let (scheduler, running, most) = (scheduler.clone(), running.clone(), most.clone());
+        tokio::spawn(async move {
+            let _turn = scheduler.turn(Duration::ZERO).await;
+            let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+            most.fetch_max(now, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            running.fetch_sub(1, Ordering::SeqCst);
+        })
+    });
+    futures::future::join_all(polls).await;
+
+    assert_eq!(most.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn overdue_turns_go_first() {
+    let scheduler = FetchScheduler::start(1);
+    let busy = scheduler.turn(Duration::ZERO).await;
+    let order = Arc::new(std::sync::Mutex::new(vec![]));
+
+    let poll = |name: &'static str, delay_ms: u64| {
+        let (scheduler, order) = (scheduler.clone(), order.clone());
+        tokio::spawn(async move {
+            let _turn = scheduler.turn(Duration::from_millis(delay_ms)).await;
+            order.lock().unwrap().push(name);
+        })
+    };
+    let later = poll("later", 50);
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    let sooner = poll("sooner", 0);
+
+    // Both are due by the time the slot is free, the one that waited longer wins
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    drop(busy);
+    later.await.unwrap();
+    sooner.await.unwrap();
+
+    assert_eq!(*order.lock().unwrap(), vec!["sooner", "later"]);
+}
+
+#[tokio::test]
+async fn requests_to_a_host_are_spaced() {
+    let limits = HostLimits::new(2, Duration::from_millis(100));
+    let start = Instant::now();
+
+    for _ in 0..3 {
+        let _permit = limits.acquire("https://nitter.example/nasa/rss").await;
+    }
+    assert!(start.elapsed() >= Duration::from_millis(200));
+
+    // Other hosts have their own schedule
+    let other = Instant::now();
+    let _permit = limits.acquire("https://example.com/feed.xml").await;
+    assert!(other.elapsed() < Duration::from_millis(100));
+}
@@ -0,0 +1,235 @@
+// Signing of the events the bot publishes. Keys are held in process by default, a feed or the bot
+// itself can have a NIP-46 remote signer ("bunker") sign its events instead. The bot talks to a
+// bunker with kind 24133 events, NIP-44 encrypted between its client key and the bunker's key,
+// over the relay in the bunker:// URI.
+
+use crate::dm;
+use crate::error::{self, Error};
+use crate::relays::{self, WebSocket};
+use crate::simpledb::FeedOptions;
+use crate::utils;
+use futures_util::{SinkExt, StreamExt};
+use log::{debug, error};
+use rand::RngCore;
+use secp256k1::{KeyPair, XOnlyPublicKey};
+use serde_json::{json, Value};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tungstenite::Message;
+
+// A bunker may wait for its user to approve a request, this is how long the bot waits along
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Clone)]
+pub enum Signer {
+    // Key from data/channels or SECRET
+    Local(KeyPair),
+    Remote(Arc<Bunker>),
+}
+
+impl Signer {
+    pub async fn sign(&self, event: nostr_bot::EventNonSigned) -> error::Result<nostr_bot::Event> {
+        match self {
+            Signer::Local(keypair) => Ok(event.sign(keypair)),
+            Signer::Remote(bunker) => bunker.sign_event(event).await,
+        }
+    }
+
+    // Key the events are signed with, a bunker's may differ from the feed's
+    pub async fn public_key(&self) -> error::Result<XOnlyPublicKey> {
+        match self {
+            Signer::Local(keypair) => Ok(keypair.x_only_public_key().0),
+            Signer::Remote(bunker) => bunker.public_key().await,
+        }
+    }
+
+    // Signer of the bot's own events, BUNKER_URL or SECRET
+    pub fn from_config(config: &utils::Config) -> error::Result<Signer> {
+        let keypair = nostr_bot::keypair_from_secret(&config.secret);
+        if config.bunker_url.is_empty() {
+            return Ok(Signer::Local(keypair));
+        }
+        Bunker::from_config(&config.bunker_url, config).map(|bunker| Signer::Remote(Arc::new(bunker)))
+    }
+
+    // Signer of a feed with `keypair`, its bunker if it has one
+    pub fn for_feed(keypair: &KeyPair, options: &FeedOptions, config: &utils::Config) -> error::Result<Signer> {
+        match &options.bunker {
+            Some(uri) => Bunker::from_config(uri, config).map(|bunker| Signer::Remote(Arc::new(bunker))),
+            None => Ok(Signer::Local(*keypair)),
+        }
+    }
+}
+
+struct Session {
+    ws: WebSocket,
+    user: XOnlyPublicKey,
+}
+
+pub struct Bunker {
+    // Key the bunker answers with, not necessarily the one it signs with
+    remote: XOnlyPublicKey,
+    relay: String,
+    secret: Option<String>,
+    client: KeyPair,
+    proxy: Option<String>,
+    // Connected on the first request and again after the connection broke
+    session: tokio::sync::Mutex<Option<Session>>,
+}
+
+impl Bunker {
+    // bunker://<remote pubkey>?relay=wss://...&secret=..., the first relay is used
+    pub fn parse(uri: &str, client: KeyPair, proxy: Option<String>) -> error::Result<Bunker> {
+        let invalid = |reason: &str| Error::Parse(format!("Invalid bunker URI: {}", reason));
+        let url = url::Url::parse(uri).map_err(|e| invalid(&e.to_string()))?;
+        if url.scheme() != "bunker" {
+            return Err(invalid("not a bunker:// URI"));
+        }
+        let remote = url.host_str().and_then(|remote| XOnlyPublicKey::from_str(remote).ok()).ok_or_else(|| invalid("no remote signer key"))?;
+        let relay = url
+            .query_pairs()
+            .find(|(name, _)| name == "relay")
+            .map(|(_, relay)| relay.to_string())
+            .filter(|relay| utils::is_relay_url(relay))
+            .ok_or_else(|| invalid("no relay"))?;
+        let secret = url.query_pairs().find(|(name, _)| name == "secret").map(|(_, secret)| secret.to_string());
+        Ok(Bunker {
+            remote,
+            relay,
+            secret,
+            client,
+            proxy,
+            session: tokio::sync::Mutex::new(None),
+        })
+    }
+
+    pub fn from_config(uri: &str, config: &utils::Config) -> error::Result<Bunker> {
+        let client = utils::bunker_client_keypair(&nostr_bot::keypair_from_secret(&config.secret));
+        let proxy = config.use_tor.then(|| config.tor_socks_address.clone());
+        Bunker::parse(uri, client, proxy)
+    }
+
+    pub fn relay(&self) -> &str {
+        &self.relay
+    }
+
+    // Key the bunker signs with
+    pub async fn public_key(&self) -> error::Result<XOnlyPublicKey> {
+        let mut session = self.session.lock().await;
+        self.connected(&mut session).await.map(|session| session.user)
+    }
+
+    pub async fn sign_event(&self, event: nostr_bot::EventNonSigned) -> error::Result<nostr_bot::Event> {
+        let mut session = self.session.lock().await;
+        let connected = self.connected(&mut session).await?;
+        let user = connected.user;
+        let unsigned = json!({"kind": event.kind, "content": event.content, "tags": event.tags, "created_at": event.created_at});
+        let result = self.request(&mut connected.ws, "sign_event", vec![unsigned.to_string()]).await;
+        let result = self.keep_if_alive(&mut session, result)?;
+
+        let mut signed: nostr_bot::Event = serde_json::from_str(&result).map_err(|e| Error::Signer(format!("Invalid signed event: {}", e)))?;
+        // nostr-bot keeps the content JSON escaped
+        let escaped = serde_json::to_string(&signed.content).map_err(|e| Error::Signer(e.to_string()))?;
+        signed.content = escaped[1..escaped.len() - 1].to_string();
+        if signed.pubkey != user.to_string() || signed.kind != event.kind || signed.created_at != event.created_at || !signed.has_valid_sig() {
+            return Err(Error::Signer(format!("The bunker returned an invalid signature for event {}", signed.id)));
+        }
+        Ok(signed)
+    }
+
+    // Connection errors drop the session so the next request connects again
+    fn keep_if_alive<T>(&self, session: &mut Option<Session>, result: error::Result<T>) -> error::Result<T> {
+        if let Err(Error::Network(e)) = &result {
+            debug!("Connection to the bunker at {} broke: {}", self.relay, e);
+            *session = None;
+        }
+        result
+    }
+
+    async fn connected<'a>(&self, session: &'a mut Option<Session>) -> error::Result<&'a mut Session> {
+        if session.is_none() {
+            *session = Some(self.connect().await?);
+        }
+        Ok(session.as_mut().unwrap())
+    }
+
+    async fn connect(&self) -> error::Result<Session> {
+        let mut ws = relays::connect(&self.relay, self.proxy.as_deref()).await?;
+        let client = self.client.x_only_public_key().0.to_string();
+        let filter = json!({"kinds": [24133], "#p": [client], "since": utils::unix_timestamp().saturating_sub(10)});
+        send(&mut ws, json!(["REQ", "nip46", filter]).to_string()).await?;
+
+        let params = vec![self.remote.to_string(), self.secret.clone().unwrap_or_default()];
+        self.request(&mut ws, "connect", params).await?;
+        let user = self.request(&mut ws, "get_public_key", vec![]).await?;
+        let user = XOnlyPublicKey::from_str(&user).map_err(|e| Error::Signer(format!("Invalid public key {}: {}", user, e)))?;
+        debug!("Connected to the bunker at {}, signing as {}", self.relay, user);
+        Ok(Session { ws, user })
+    }
+
+    // Sends a request and waits for the bunker's result
+    async fn request(&self, ws: &mut WebSocket, method: &str, params: Vec<String>) -> error::Result<String> {
+        let mut id = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut id);
+        let id = id.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+
+        let conversation_key = dm::conversation_key(&self.client.secret_key(), &self.remote);
+        let content = dm::nip44_encrypt(&conversation_key, &json!({"id": id, "method": method, "params": params}).to_string())?;
+        let tags = vec![vec!["p".to_string(), self.remote.to_string()]];
+        let event = nostr_bot::Event::new(&self.client, utils::unix_timestamp(), 24133, tags, content);
+        send(ws, event.format()).await?;
+
+        let response = async {
+            loop {
+                let text = match ws.next().await {
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(Message::Close(_))) | None => return Err(Error::Network("The relay closed the connection".to_string())),
+                    Some(Ok(_)) => continue,
+                    Some(Err(e)) => return Err(Error::Network(e.to_string())),
+                };
+                let message: Value = match serde_json::from_str(&text) {
+                    Ok(message) => message,
+                    Err(_) => continue,
+                };
+                if message[0] == "OK" && message[1] == event.id.as_str() && message[2] == false {
+                    return Err(Error::Network(format!("The relay refused the request: {}", message[3].as_str().unwrap_or_default())));
+                }
+                let reply = &message[2];
+                if message[0] != "EVENT" || reply["kind"] != 24133 || reply["pubkey"] != self.remote.to_string().as_str() {
+                    continue;
+                }
+                let response = match reply["content"].as_str().map(|content| dm::nip44_decrypt(&conversation_key, content)) {
+                    Some(Ok(response)) => response,
+                    _ => continue,
+                };
+                let response = match serde_json::from_str::<Value>(&response) {
+                    Ok(response) if response["id"] == id.as_str() => response,
+                    _ => continue,
+                };
+                return Ok(response);
+            }
+        };
+        let response = tokio::time::timeout(REQUEST_TIMEOUT, response)
+            .await
+            .map_err(|_| Error::Network(format!("The bunker didn't answer {} in time", method)))??;
+
+        let result = response["result"].as_str().unwrap_or_default();
+        let reason = response["error"].as_str().unwrap_or_default();
+        if result == "auth_url" {
+            error!("The bunker at {} asks to approve the bot at {}", self.relay, reason);
+            return Err(Error::Signer(format!("The bunker asks to approve the bot at {}", reason)));
+        }
+        if !reason.is_empty() {
+            return Err(Error::Signer(format!("The bunker refused {}: {}", method, reason)));
+        }
+        Ok(result.to_string())
+    }
+}
+
+async fn send(ws: &mut WebSocket, message: String) -> error::Result<()> {
+    ws.send(Message::Text(message)).await.map_err(|e| Error::Network(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests;
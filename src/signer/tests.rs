@@ -0,0 +1,65 @@
+use super::*;
+use crate::testing::{verify_event, MockBunker};
+
+fn note(content: &str) -> nostr_bot::EventNonSigned {
+    nostr_bot::EventNonSigned {
+        created_at: utils::unix_timestamp(),
+        kind: 1,
+        tags: vec![vec!["t".to_string(), "test".to_string()]],
+        content: content.to_string(),
+    }
+}
+
+fn as_json(event: &nostr_bot::Event) -> Value {
+    serde_json::from_str::<Value>(&event.format()).unwrap()[1].clone()
+}
+
+#[tokio::test]
+async fn signs_through_a_bunker() {
+    let user = utils::get_random_keypair();
+    let mock = MockBunker::start(user, Some("s3cret")).await;
+    let signer = Signer::Remote(Arc::new(Bunker::parse(&mock.uri(Some("s3cret")), utils::get_random_keypair(), None).unwrap()));
+
+    let signed = signer.sign(note("He said \"hi\"\n\tand left \\o/")).await.unwrap();
+    assert_eq!(signed.pubkey, user.x_only_public_key().0.to_string());
+    assert!(signed.has_valid_sig());
+    let signed = as_json(&signed);
+    assert!(verify_event(&signed));
+    assert_eq!(signed["content"], "He said \"hi\"\n\tand left \\o/");
+    assert_eq!(mock.signed(), vec![signed]);
+
+    // Same event as with the key in process
+    let local = Signer::Local(user).sign(note("Same")).await.unwrap();
+    let remote = signer.sign(note("Same")).await.unwrap();
+    assert_eq!(local.pubkey, remote.pubkey);
+    assert!(verify_event(&as_json(&local)));
+}
+
+#[tokio::test]
+async fn bunkers_refuse_unknown_clients() {
+    let mock = MockBunker::start(utils::get_random_keypair(), Some("s3cret")).await;
+    let client = utils::get_random_keypair();
+
+    let bunker = Bunker::parse(&mock.uri(Some("wrong")), client, None).unwrap();
+    assert!(matches!(bunker.sign_event(note("Nope")).await, Err(Error::Signer(_))));
+    assert!(mock.signed().is_empty());
+
+    // Authorized clients get by without the secret
+    mock.authorize(&client.x_only_public_key().0);
+    let bunker = Bunker::parse(&mock.uri(None), client, None).unwrap();
+    assert!(bunker.sign_event(note("Yes")).await.is_ok());
+    assert_eq!(mock.signed().len(), 1);
+}
+
+#[test]
+fn parses_bunker_uris() {
+    let client = utils::get_random_keypair();
+    let remote = "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+    let bunker = Bunker::parse(&format!("bunker://{}?relay=wss%3A%2F%2Frelay.example&secret=abc", remote), client, None).unwrap();
+    assert_eq!(bunker.relay(), "wss://relay.example");
+    assert_eq!(bunker.secret.as_deref(), Some("abc"));
+
+    assert!(Bunker::parse(&format!("nostrconnect://{}?relay=wss://relay.example", remote), client, None).is_err());
+    assert!(Bunker::parse(&format!("bunker://{}", remote), client, None).is_err());
+    assert!(Bunker::parse("bunker://nasa?relay=wss://relay.example", client, None).is_err());
+}
@@ -1,101 +1,454 @@
-use log::{debug, warn};
+use crate::error::{self, Error};
+use crate::nip49::{self, KeySecurity};
+use crate::utils;
+use log::{debug, error, info, warn};
 use std::io::Write;
+use std::str::FromStr;
 
 pub type Database = std::sync::Arc<std::sync::Mutex<SimpleDatabase>>;
 
+// Identity of a followed feed, stored as e.g. twitter:nasa, discord:1111088216607567974 or
+// rss:https://example.com/feed.xml
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum FeedId {
+    Twitter(String),
+    Discord(u64),
+    Rss(String),
+}
+
+impl FeedId {
+    pub fn twitter(handle: &str) -> FeedId {
+        FeedId::Twitter(handle.trim_start_matches('@').to_lowercase())
+    }
+
+    pub fn parse(id: &str) -> Option<FeedId> {
+        let (kind, value) = id.split_once(':')?;
+        match kind {
+            "twitter" if !value.is_empty() => Some(FeedId::twitter(value)),
+            "discord" => value.parse().ok().map(FeedId::Discord),
+            "rss" if !value.is_empty() => Some(FeedId::Rss(value.to_string())),
+            _ => None,
+        }
+    }
+
+    // Keys used before typed identifiers: Discord channel IDs, Nitter feed URLs
+    // (https://<NITTER_INSTANCE>/<handle>/rss) and plain Twitter handles
+    fn from_legacy(key: &str) -> Option<FeedId> {
+        if let Ok(channel_id) = key.parse::<u64>() {
+            return Some(FeedId::Discord(channel_id));
+        }
+
+        if let Ok(url) = url::Url::parse(key) {
+            let segments = url.path_segments().map(|s| s.collect::<Vec<_>>()).unwrap_or_default();
+            return match segments.as_slice() {
+                [handle, "rss"] if !handle.is_empty() => Some(FeedId::twitter(handle)),
+                _ => Some(FeedId::Rss(key.to_string())),
+            };
+        }
+
+        if !key.is_empty() && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Some(FeedId::twitter(key));
+        }
+
+        None
+    }
+}
+
+impl std::fmt::Display for FeedId {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            FeedId::Twitter(handle) => write!(f, "twitter:{}", handle),
+            FeedId::Discord(channel_id) => write!(f, "discord:{}", channel_id),
+            FeedId::Rss(url) => write!(f, "rss:{}", url),
+        }
+    }
+}
+
+// Per-feed settings, stored after the name as key=value columns
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FeedOptions {
+    // Fixed polling interval instead of the adaptive one
+    pub interval_secs: Option<u64>,
+    // Relays the feed publishes to instead of ADD_RELAY, stored separated by spaces
+    pub relays: Vec<String>,
+    // bunker:// URI of the NIP-46 remote signer signing the feed's events
+    pub bunker: Option<String>,
+    // Public key of the owner the feed's key was sent to with !claim, the bot no longer posts as it
+    pub handed_over_to: Option<String>,
+}
+
+impl FeedOptions {
+    fn parse(columns: &[&str]) -> FeedOptions {
+        let mut options = FeedOptions::default();
+        for column in columns {
+            match column.split_once('=') {
+                Some(("interval", value)) => options.interval_secs = value.parse().ok(),
+                Some(("relays", value)) => options.relays = value.split(' ').filter(|relay| !relay.is_empty()).map(decode_key).collect(),
+                Some(("bunker", value)) => options.bunker = Some(decode_key(value)),
+                Some(("handed_over", value)) => options.handed_over_to = Some(value.to_string()),
+                _ => warn!("Unknown feed option >{}<, ignoring", column),
+            }
+        }
+        options
+    }
+
+    fn columns(&self) -> Vec<String> {
+        let mut columns = vec![];
+        if let Some(interval_secs) = self.interval_secs {
+            columns.push(format!("interval={}", interval_secs));
+        }
+        if !self.relays.is_empty() {
+            let relays = self.relays.iter().map(|relay| encode_key(relay)).collect::<Vec<_>>();
+            columns.push(format!("relays={}", relays.join(" ")));
+        }
+        if let Some(bunker) = &self.bunker {
+            columns.push(format!("bunker={}", encode_key(bunker)));
+        }
+        if let Some(owner) = &self.handed_over_to {
+            columns.push(format!("handed_over={}", owner));
+        }
+        columns
+    }
+}
+
+struct Row {
+    // Hex secret key, only kept in memory when the file is encrypted
+    seckey: String,
+    // Secret key as written to the file, an ncryptsec or the hex key
+    stored: String,
+    name: String,
+    options: FeedOptions,
+}
+
+// Secret keys are written to the file as NIP-49 ncryptsec encrypted with `passphrase`
+#[derive(Clone)]
+pub struct KeyEncryption {
+    passphrase: String,
+    // scrypt rounds, 2^log_n
+    log_n: u8,
+}
+
+impl KeyEncryption {
+    pub fn new(passphrase: &str, log_n: u8) -> KeyEncryption {
+        KeyEncryption {
+            passphrase: passphrase.to_string(),
+            log_n,
+        }
+    }
+
+    pub fn from_config(config: &utils::Config) -> KeyEncryption {
+        KeyEncryption::new(&config.key_passphrase, config.key_scrypt_log_n)
+    }
+
+    fn encrypt(&self, seckey: &str, security: KeySecurity) -> error::Result<String> {
+        let secret = secp256k1::SecretKey::from_str(seckey).map_err(|e| Error::Storage(format!("Invalid secret key: {}", e)))?;
+        nip49::encrypt(&secret, &self.passphrase, self.log_n, security)
+    }
+}
+
+// Secret key of a new feed as written to the file, see SimpleDatabase::insert_stored. scrypt takes
+// a while, so it runs on a blocking thread without holding any lock.
+pub async fn stored_key(encryption: Option<KeyEncryption>, seckey: String) -> error::Result<String> {
+    match encryption {
+        Some(encryption) => tokio::task::spawn_blocking(move || encryption.encrypt(&seckey, KeySecurity::Secure))
+            .await
+            .map_err(|e| Error::Storage(format!("Unable to encrypt the key: {}", e)))?,
+        None => Ok(seckey),
+    }
+}
+
+// Commas separate the columns, the ones in feed and relay URLs are stored as %2C and percent
+// signs as %25, so a literal %2C in a URL stays apart
+fn encode_key(value: &str) -> String {
+    value.replace('%', "%25").replace(',', "%2C")
+}
+
+// Other percent sequences are kept as they are, older versions only encoded commas
+fn decode_key(key: &str) -> String {
+    let mut decoded = String::with_capacity(key.len());
+    let mut rest = key;
+    while let Some(index) = rest.find('%') {
+        decoded.push_str(&rest[..index]);
+        rest = &rest[index..];
+        let (replacement, len) = match rest.get(..3) {
+            Some("%25") => ("%", 3),
+            Some("%2C") => (",", 3),
+            _ => ("%", 1),
+        };
+        decoded.push_str(replacement);
+        rest = &rest[len..];
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+fn row_line(feed_id: &FeedId, row: &Row) -> String {
+    let mut columns = vec![encode_key(&feed_id.to_string()), row.stored.clone(), row.name.clone()];
+    columns.extend(row.options.columns());
+    columns.join(",")
+}
+
 pub struct SimpleDatabase {
-    follows: std::collections::HashMap<String, (String, String)>,
+    follows: std::collections::HashMap<FeedId, Row>,
     file: String,
+    // None keeps the keys in plaintext
+    encryption: Option<KeyEncryption>,
 }
 
 impl SimpleDatabase {
-    pub fn from_file(path: String) -> SimpleDatabase {
+    // Keeps the keys in plaintext, only tests use that
+    #[cfg(test)]
+    pub fn from_file(path: String) -> error::Result<SimpleDatabase> {
+        SimpleDatabase::open(path, None)
+    }
+
+    // Decrypts the keys of the file, plaintext keys left from older versions get encrypted. Fails
+    // when a key doesn't decrypt, going on would lose it once the file is written again.
+    pub fn from_file_encrypted(path: String, encryption: KeyEncryption) -> error::Result<SimpleDatabase> {
+        SimpleDatabase::open(path, Some(encryption))
+    }
+
+    fn open(path: String, encryption: Option<KeyEncryption>) -> error::Result<SimpleDatabase> {
         let mut db = SimpleDatabase {
             follows: std::collections::HashMap::new(),
             file: path.clone(),
+            encryption,
         };
 
         if !std::path::Path::new(&path).exists() {
             warn!("Database path {} doesn't exist, creating a new file", path);
-            std::fs::File::create(path.clone()).expect("Failed to create file");
+            std::fs::File::create(path.clone())?;
         }
 
-        let content = std::fs::read_to_string(path).expect("Failed opening database file");
+        let content = std::fs::read_to_string(&path)?;
 
+        let mut migrated = 0;
         for line in content.lines() {
             let split = line.split(',').collect::<Vec<_>>();
-            if split.len() != 3 {
+            if split.len() < 3 {
                 debug!("unable to parse line: >{:?}<, skipping", split);
                 continue;
             }
-            let channel_id = split[0];
-            let seckey = split[1];
-            let name = split[2];
-
-            match db.follows.insert(channel_id.to_string(), (seckey.to_string(), name.to_string())) {
-                Some(_) => panic!(
-                    "Inconsistent database, channel_id {} is more than once in the database",
-                    channel_id
-                ),
-                None => {
-                    debug!(
-                        "Read from file: inserting channel_id {} into database",
-                        channel_id
-                    );
+            let key = decode_key(split[0]);
+            let (key, seckey, name) = (key.as_str(), split[1], split[2]);
+            let options = FeedOptions::parse(&split[3..]);
+
+            let (feed_id, legacy) = match FeedId::parse(key) {
+                Some(feed_id) => (feed_id, false),
+                None => match FeedId::from_legacy(key) {
+                    Some(feed_id) => {
+                        info!("Migrating database key {} to {}", key, feed_id);
+                        migrated += 1;
+                        (feed_id, true)
+                    }
+                    None => {
+                        warn!("Unknown database key {}, skipping", key);
+                        continue;
+                    }
+                },
+            };
+
+            if db.follows.contains_key(&feed_id) {
+                // Old keys on different Nitter instances can end up as the same feed, the first
+                // one keeps its key
+                if legacy {
+                    warn!("{} is in the database more than once, keeping the first key", feed_id);
+                    continue;
+                }
+                return Err(Error::Storage(format!("Inconsistent database, feed {} is more than once in the database", feed_id)));
+            }
+
+            let plaintext = seckey.to_string();
+            let seckey = match (seckey.starts_with("ncryptsec1"), &db.encryption) {
+                (false, _) => plaintext.clone(),
+                (true, Some(encryption)) => match nip49::decrypt(seckey, &encryption.passphrase) {
+                    Ok(secret) => secret.display_secret().to_string(),
+                    Err(e) => {
+                        return Err(Error::Storage(format!("Unable to decrypt the key of {}, is KEY_PASSPHRASE right? {}", feed_id, e)));
+                    }
+                },
+                (true, None) => {
+                    error!("The key of {} is encrypted, skipping", feed_id);
+                    continue;
+                }
+            };
+
+            debug!("Read from file: inserting feed {} into database", feed_id);
+            db.follows.insert(
+                feed_id,
+                Row {
+                    seckey,
+                    stored: plaintext,
+                    name: name.to_string(),
+                    options,
+                },
+            );
+        }
+
+        let mut encrypted = 0;
+        if let Some(encryption) = db.encryption.clone() {
+            for (feed_id, row) in db.follows.iter_mut().filter(|(_, row)| row.stored == row.seckey) {
+                match encryption.encrypt(&row.seckey, KeySecurity::Insecure) {
+                    Ok(stored) => {
+                        row.stored = stored;
+                        encrypted += 1;
+                    }
+                    Err(e) => error!("Unable to encrypt the key of {}: {}", feed_id, e),
                 }
             }
         }
 
-        db
+        if migrated > 0 {
+            // With KEY_PASSPHRASE the backup gets the keys encrypted as well
+            let backup = format!("{}.bak", path);
+            let backup_content = match &db.encryption {
+                Some(encryption) => db.encrypted_backup(&content, encryption),
+                None => content.clone(),
+            };
+            std::fs::write(&backup, backup_content)?;
+            info!("Migrated {} database keys, the old file is kept as {}", migrated, backup);
+        }
+        if migrated > 0 || encrypted > 0 {
+            db.save()?;
+        }
+        if encrypted > 0 {
+            info!("Encrypted {} plaintext keys in {}", encrypted, path);
+        }
+
+        Ok(db)
+    }
+
+    // `content` of the file with plaintext keys replaced by the ones stored now, keys that didn't
+    // make it into the database are encrypted on their own
+    fn encrypted_backup(&self, content: &str, encryption: &KeyEncryption) -> String {
+        let stored = self.follows.values().map(|row| (row.seckey.as_str(), row.stored.as_str())).collect::<std::collections::HashMap<_, _>>();
+        let mut backup = String::new();
+        for line in content.lines() {
+            let mut columns = line.split(',').map(str::to_string).collect::<Vec<_>>();
+            if columns.len() > 1 && !columns[1].starts_with("ncryptsec1") {
+                columns[1] = match stored.get(columns[1].as_str()) {
+                    Some(stored) => stored.to_string(),
+                    None => encryption.encrypt(&columns[1], KeySecurity::Insecure).unwrap_or_else(|e| {
+                        warn!("Unable to encrypt a key of the backup, leaving it out: {}", e);
+                        String::new()
+                    }),
+                };
+            }
+            backup.push_str(&columns.join(","));
+            backup.push('\n');
+        }
+        backup
+    }
+
+    // Writes a temporary file and renames it over the database, a crash midway leaves the previous one
+    fn save(&self) -> std::io::Result<()> {
+        let mut rows = self.follows.iter().collect::<Vec<_>>();
+        rows.sort_by(|a, b| a.0.cmp(b.0));
+
+        let temporary = format!("{}.tmp", self.file);
+        let mut file = std::fs::File::create(&temporary)?;
+        for (feed_id, row) in rows {
+            writeln!(file, "{}", row_line(feed_id, row))?;
+        }
+        file.sync_all()?;
+        std::fs::rename(&temporary, &self.file)
+    }
+
+    // Encrypts the key in place, only tests use that
+    #[cfg(test)]
+    pub fn insert(&mut self, feed_id: FeedId, seckey: String, name: String) -> error::Result<()> {
+        let stored = match &self.encryption {
+            Some(encryption) => encryption.encrypt(&seckey, KeySecurity::Secure)?,
+            None => seckey.clone(),
+        };
+        self.insert_stored(feed_id, seckey, stored, name)
     }
 
-    pub fn insert(&mut self, channel_id: String, seckey: String, name: String) -> Result<(), String> {
-        if self.follows.contains_key(&channel_id) {
-            return Err("Key already in the database".to_string());
+    // Adds a feed with its key as `stored_key` returned it
+    pub fn insert_stored(&mut self, feed_id: FeedId, seckey: String, stored: String, name: String) -> error::Result<()> {
+        if self.follows.contains_key(&feed_id) {
+            return Err(Error::Storage(format!("{} is already in the database", feed_id)));
         }
 
-        self.follows.insert(channel_id.clone(), (seckey.clone(), name.clone()));
-        debug!("Added {} to the database", channel_id);
+        debug!("Added {} to the database", feed_id);
 
         let mut file = std::fs::OpenOptions::new()
-            .write(true)
             .append(true)
-            .open(self.file.clone())
-            .unwrap();
+            .open(self.file.clone())?;
 
-        writeln!(file, "{},{},{}", channel_id, seckey, name).unwrap();
+        let row = Row {
+            seckey,
+            stored,
+            name,
+            options: FeedOptions::default(),
+        };
+        writeln!(file, "{}", row_line(&feed_id, &row))?;
+        self.follows.insert(feed_id, row);
         debug!("Wrote updated database to the file");
         Ok(())
     }
 
-    pub fn get(&self, key: &str) -> (String, String) {
-        self.follows.get(key).unwrap().clone()
+    pub fn encryption(&self) -> Option<KeyEncryption> {
+        self.encryption.clone()
+    }
+
+    pub fn get(&self, feed_id: &FeedId) -> error::Result<(String, String)> {
+        match self.follows.get(feed_id) {
+            Some(row) => Ok((row.seckey.clone(), row.name.clone())),
+            None => Err(Error::Storage(format!("{} is not in the database", feed_id))),
+        }
+    }
+
+    pub fn get_options(&self, feed_id: &FeedId) -> FeedOptions {
+        self.follows
+            .get(feed_id)
+            .map(|row| row.options.clone())
+            .unwrap_or_default()
+    }
+
+    pub fn set_options(&mut self, feed_id: &FeedId, options: FeedOptions) -> error::Result<()> {
+        match self.follows.get_mut(feed_id) {
+            Some(row) => row.options = options,
+            None => return Err(Error::Storage(format!("{} is not in the database", feed_id))),
+        }
+        Ok(self.save()?)
     }
 
-    pub fn contains_key(&self, key: &str) -> bool {
-        self.follows.contains_key(key)
+    pub fn contains_key(&self, feed_id: &FeedId) -> bool {
+        self.follows.contains_key(feed_id)
     }
 
-    pub fn get_follows(&self) -> std::collections::HashMap<String, (secp256k1::KeyPair, String)> {
-        let mut result = std::collections::HashMap::<String, (secp256k1::KeyPair, String)>::new();
+    // Feeds the bot posts as, the ones handed over to their owners stay in the file only
+    pub fn get_follows(&self) -> std::collections::HashMap<FeedId, (secp256k1::KeyPair, String)> {
+        let mut result = std::collections::HashMap::<FeedId, (secp256k1::KeyPair, String)>::new();
         let secp = secp256k1::Secp256k1::new();
-        for (channel_id, (secret, name)) in &self.follows {
-            let keypair = secp256k1::KeyPair::from_seckey_str(&secp, secret).unwrap();
+        for (feed_id, row) in self.follows.iter().filter(|(_, row)| row.options.handed_over_to.is_none()) {
+            let keypair = match secp256k1::KeyPair::from_seckey_str(&secp, &row.seckey) {
+                Ok(keypair) => keypair,
+                Err(e) => {
+                    error!("Invalid secret key of {} in the database, skipping: {}", feed_id, e);
+                    continue;
+                }
+            };
             result.insert(
-                channel_id.clone(),
-                (keypair, name.clone()),
+                feed_id.clone(),
+                (keypair, row.name.clone()),
             );
         }
         result
     }
 
     pub fn follows_count(&self) -> usize {
-        self.follows.len()
+        self.follows.values().filter(|row| row.options.handed_over_to.is_none()).count()
     }
 }
 
-pub fn get_channel_keypair(channel_id: &str, db: Database) -> secp256k1::KeyPair {
+pub fn get_channel_keypair(feed_id: &FeedId, db: Database) -> error::Result<secp256k1::KeyPair> {
     let secp = secp256k1::Secp256k1::new();
-    let (existing_secret, _) = db.lock().unwrap().get(channel_id);
-    secp256k1::KeyPair::from_seckey_str(&secp, &existing_secret).unwrap()
+    let (existing_secret, _) = db.lock().unwrap().get(feed_id)?;
+    secp256k1::KeyPair::from_seckey_str(&secp, &existing_secret)
+        .map_err(|e| Error::Storage(format!("Invalid secret key of {}: {}", feed_id, e)))
 }
+
+#[cfg(test)]
+mod tests;
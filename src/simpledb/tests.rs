@@ -0,0 +1,186 @@
+use super::*;
+
+const SECRET_1: &str = "a6e3a3f8d5b5a9c3b8d1e0f2c4a7b6d9e8f1a2b3c4d5e6f7a8b9c0d1e2f3a4b5";
+const SECRET_2: &str = "b6e3a3f8d5b5a9c3b8d1e0f2c4a7b6d9e8f1a2b3c4d5e6f7a8b9c0d1e2f3a4b5";
+const SECRET_3: &str = "c6e3a3f8d5b5a9c3b8d1e0f2c4a7b6d9e8f1a2b3c4d5e6f7a8b9c0d1e2f3a4b5";
+
+fn temp_file(name: &str, content: &str) -> String {
+    let dir = std::env::temp_dir().join(format!("dostr-simpledb-{}-{}", name, std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("channels");
+    std::fs::write(&path, content).unwrap();
+    path.to_string_lossy().to_string()
+}
+
+#[test]
+fn feed_ids_round_trip() {
+    for id in ["twitter:nasa", "discord:1111088216607567974", "rss:https://example.com/feed.xml?a=1,2"] {
+        assert_eq!(FeedId::parse(id).unwrap().to_string(), id);
+    }
+    assert_eq!(FeedId::parse("twitter:NASA"), Some(FeedId::Twitter("nasa".to_string())));
+    assert_eq!(FeedId::parse("discord:abc"), None);
+    assert_eq!(FeedId::parse("https://nitter.net/nasa/rss"), None);
+}
+
+#[test]
+fn migrates_url_keyed_rows() {
+    let path = temp_file(
+        "migrate",
+        &format!(
+            "https://nitter.net/NASA/rss,{},NASA\n1111088216607567974,{},jack\nhttps://nitter.example/nasa/rss,{},nasa\n",
+            SECRET_1, SECRET_2, SECRET_3
+        ),
+    );
+
+    let db = SimpleDatabase::from_file(path.clone()).unwrap();
+    assert_eq!(db.follows_count(), 2);
+    // The first key of an account stays its identity
+    assert_eq!(db.get(&FeedId::twitter("nasa")).unwrap(), (SECRET_1.to_string(), "NASA".to_string()));
+    assert_eq!(db.get(&FeedId::Discord(1111088216607567974)).unwrap(), (SECRET_2.to_string(), "jack".to_string()));
+
+    assert_eq!(
+        std::fs::read_to_string(&path).unwrap(),
+        format!("twitter:nasa,{},NASA\ndiscord:1111088216607567974,{},jack\n", SECRET_1, SECRET_2)
+    );
+    assert!(std::fs::read_to_string(format!("{}.bak", path)).unwrap().contains("nitter.example"));
+
+    // Reading the migrated file again doesn't change anything
+    let db = SimpleDatabase::from_file(path.clone()).unwrap();
+    assert_eq!(db.follows_count(), 2);
+    std::fs::remove_dir_all(std::path::Path::new(&path).parent().unwrap()).ok();
+}
+
+#[test]
+fn keeps_commas_in_feed_urls() {
+    let path = temp_file("commas", "");
+    let mut db = SimpleDatabase::from_file(path.clone()).unwrap();
+    let feed = FeedId::Rss("https://example.com/feed?tags=a,b".to_string());
+    db.insert(feed.clone(), SECRET_1.to_string(), "example".to_string()).unwrap();
+    assert!(db.insert(feed.clone(), SECRET_2.to_string(), "example".to_string()).is_err());
+
+    // A literal %2C isn't taken for an encoded comma
+    let encoded = FeedId::Rss("https://example.com/feed?tags=a%2Cb%25".to_string());
+    db.insert(encoded.clone(), SECRET_2.to_string(), "encoded".to_string()).unwrap();
+
+    let db = SimpleDatabase::from_file(path.clone()).unwrap();
+    assert_eq!(db.get(&feed).unwrap(), (SECRET_1.to_string(), "example".to_string()));
+    assert_eq!(db.get(&encoded).unwrap(), (SECRET_2.to_string(), "encoded".to_string()));
+    assert_eq!(db.follows_count(), 2);
+    std::fs::remove_dir_all(std::path::Path::new(&path).parent().unwrap()).ok();
+}
+
+#[test]
+fn reads_commas_encoded_by_older_versions() {
+    let path = temp_file("old_encoding", &format!("rss:https://example.com/feed?tags=a%2Cb&q=%20,{},example\n", SECRET_1));
+    let db = SimpleDatabase::from_file(path.clone()).unwrap();
+    let feed = FeedId::Rss("https://example.com/feed?tags=a,b&q=%20".to_string());
+    assert_eq!(db.get(&feed).unwrap(), (SECRET_1.to_string(), "example".to_string()));
+    std::fs::remove_dir_all(std::path::Path::new(&path).parent().unwrap()).ok();
+}
+
+#[test]
+fn stores_feed_options() {
+    let path = temp_file("options", &format!("twitter:nasa,{},NASA,interval=600,relays=wss://nos.lol\n", SECRET_1));
+    let mut db = SimpleDatabase::from_file(path.clone()).unwrap();
+    let nasa = FeedId::twitter("nasa");
+    assert_eq!(db.get_options(&nasa).interval_secs, Some(600));
+    assert_eq!(db.get_options(&nasa).relays, vec!["wss://nos.lol"]);
+    assert_eq!(db.get(&nasa).unwrap(), (SECRET_1.to_string(), "NASA".to_string()));
+
+    let feed = FeedId::Rss("https://example.com/feed?tags=a,b".to_string());
+    db.insert(feed.clone(), SECRET_2.to_string(), "example".to_string()).unwrap();
+    let relays = vec!["wss://relay.example/?topics=a,b".to_string(), "wss://nos.lol".to_string()];
+    let bunker = "bunker://79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798?relay=wss://relay.example&secret=a,b".to_string();
+    db.set_options(&feed, FeedOptions { interval_secs: Some(60), relays: relays.clone(), bunker: Some(bunker.clone()), handed_over_to: None }).unwrap();
+    db.set_options(&nasa, FeedOptions::default()).unwrap();
+    assert!(db.set_options(&FeedId::twitter("jack"), FeedOptions::default()).is_err());
+    assert!(db.get(&FeedId::twitter("jack")).is_err());
+    assert!(!std::path::Path::new(&format!("{}.tmp", path)).exists());
+
+    let db = SimpleDatabase::from_file(path.clone()).unwrap();
+    assert_eq!(db.get_options(&nasa), FeedOptions::default());
+    assert_eq!(db.get_options(&feed).interval_secs, Some(60));
+    assert_eq!(db.get_options(&feed).relays, relays);
+    assert_eq!(db.get_options(&feed).bunker, Some(bunker));
+    assert_eq!(db.get(&feed).unwrap(), (SECRET_2.to_string(), "example".to_string()));
+    std::fs::remove_dir_all(std::path::Path::new(&path).parent().unwrap()).ok();
+}
+
+#[test]
+fn skips_rows_with_invalid_keys() {
+    let path = temp_file("invalid", &format!("twitter:nasa,{},NASA\ntwitter:jack,not-a-key,jack\n", SECRET_1));
+    let db = SimpleDatabase::from_file(path.clone()).unwrap();
+    let follows = db.get_follows();
+    assert_eq!(follows.len(), 1);
+    assert!(follows.contains_key(&FeedId::twitter("nasa")));
+
+    let db: Database = std::sync::Arc::new(std::sync::Mutex::new(db));
+    assert!(get_channel_keypair(&FeedId::twitter("jack"), db.clone()).is_err());
+    assert!(get_channel_keypair(&FeedId::twitter("elon"), db).is_err());
+    std::fs::remove_dir_all(std::path::Path::new(&path).parent().unwrap()).ok();
+}
+
+#[test]
+fn refuses_feeds_stored_twice() {
+    let path = temp_file("duplicate", &format!("twitter:nasa,{},NASA\ntwitter:nasa,{},NASA\n", SECRET_1, SECRET_2));
+    assert!(matches!(SimpleDatabase::from_file(path.clone()), Err(Error::Storage(_))));
+    std::fs::remove_dir_all(std::path::Path::new(&path).parent().unwrap()).ok();
+}
+
+#[test]
+fn leaves_handed_over_feeds_out_of_the_follows() {
+    let owner = "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+    let path = temp_file("handed_over", &format!("twitter:nasa,{},NASA\ntwitter:jack,{},jack,handed_over={}\n", SECRET_1, SECRET_2, owner));
+    let db = SimpleDatabase::from_file(path.clone()).unwrap();
+    assert_eq!(db.get_follows().keys().collect::<Vec<_>>(), vec![&FeedId::twitter("nasa")]);
+    assert_eq!(db.follows_count(), 1);
+    // The key stays for sending it again
+    assert!(db.contains_key(&FeedId::twitter("jack")));
+    assert_eq!(db.get_options(&FeedId::twitter("jack")).handed_over_to.as_deref(), Some(owner));
+    std::fs::remove_dir_all(std::path::Path::new(&path).parent().unwrap()).ok();
+}
+
+#[test]
+fn encrypts_keys_at_rest() {
+    let path = temp_file("encrypted", &format!("twitter:nasa,{},NASA,interval=60\n", SECRET_1));
+    let encryption = KeyEncryption::new("correct horse battery staple", 4);
+
+    // Plaintext keys of older versions are encrypted on start
+    let mut db = SimpleDatabase::from_file_encrypted(path.clone(), encryption.clone()).unwrap();
+    assert_eq!(db.get(&FeedId::twitter("nasa")).unwrap(), (SECRET_1.to_string(), "NASA".to_string()));
+    db.insert(FeedId::twitter("jack"), SECRET_2.to_string(), "jack".to_string()).unwrap();
+    let content = std::fs::read_to_string(&path).unwrap();
+    assert!(!content.contains(SECRET_1) && !content.contains(SECRET_2), "{}", content);
+    let ncryptsec = content.lines().find(|line| line.starts_with("twitter:nasa,")).unwrap().split(',').nth(1).unwrap();
+    assert_eq!(nip49::decrypt(ncryptsec, "correct horse battery staple").unwrap().display_secret().to_string(), SECRET_1);
+
+    let db = SimpleDatabase::from_file_encrypted(path.clone(), encryption).unwrap();
+    assert_eq!(db.get(&FeedId::twitter("jack")).unwrap(), (SECRET_2.to_string(), "jack".to_string()));
+    assert_eq!(db.get_options(&FeedId::twitter("nasa")).interval_secs, Some(60));
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), content);
+
+    // A wrong passphrase stops the bot instead of losing the keys
+    let wrong = SimpleDatabase::from_file_encrypted(path.clone(), KeyEncryption::new("wrong", 4));
+    assert!(matches!(wrong, Err(Error::Storage(reason)) if reason.contains("KEY_PASSPHRASE")));
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), content);
+    std::fs::remove_dir_all(std::path::Path::new(&path).parent().unwrap()).ok();
+}
+
+#[test]
+fn migration_backups_keep_keys_encrypted() {
+    let path = temp_file("migrate-encrypted", &format!("https://nitter.net/nasa/rss,{},nasa\nhttps://nitter.example/nasa/rss,{},nasa\n", SECRET_1, SECRET_2));
+
+    let db = SimpleDatabase::from_file_encrypted(path.clone(), KeyEncryption::new("correct horse battery staple", 4)).unwrap();
+    assert_eq!(db.get(&FeedId::twitter("nasa")).unwrap(), (SECRET_1.to_string(), "nasa".to_string()));
+
+    // The backup keeps the old rows, the key of the one left out too
+    let backup = std::fs::read_to_string(format!("{}.bak", path)).unwrap();
+    assert!(!backup.contains(SECRET_1) && !backup.contains(SECRET_2), "{}", backup);
+    let keys = backup.lines().map(|line| line.split(',').nth(1).unwrap()).collect::<Vec<_>>();
+    assert_eq!(keys.len(), 2);
+    for (ncryptsec, secret) in keys.iter().zip([SECRET_1, SECRET_2]) {
+        assert_eq!(nip49::decrypt(ncryptsec, "correct horse battery staple").unwrap().display_secret().to_string(), secret);
+    }
+    assert!(backup.contains("https://nitter.example/nasa/rss,"));
+    std::fs::remove_dir_all(std::path::Path::new(&path).parent().unwrap()).ok();
+}
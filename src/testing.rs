@@ -0,0 +1,1122 @@
+// In-process stand-ins for a nostr relay and a Nitter instance, so tests can run the bot end to
+// end without network access.
+//
+// - MockRelay  websocket relay, verifies and records every EVENT it gets and answers with OK
+// - MockNitter HTTP server with RSS feeds and tweet pages of the accounts tests post to
+// - MockBunker NIP-46 remote signer along with the relay it listens on
+// - MockMediaServer Blossom or NIP-96 server taking uploads, hosting the original media too
+// - MockShortener HTTP server redirecting short links
+// - TestBot    the real nostr bot connected to both, with a temporary database and web folder
+//
+// Workers poll once per second, items posted to MockNitter are dated to the next full second so
+// they show up in the next poll.
+
+use crate::alerts;
+use crate::dm::{self, DmKind};
+use crate::dostr::{self, DostrState, State};
+use crate::fetch::FeedClient;
+use crate::media::MediaServerKind;
+use crate::nitter;
+use crate::outbox::EventOutbox;
+use crate::relays::Relays;
+use crate::scheduler::FetchScheduler;
+use crate::simpledb::{FeedId, KeyEncryption, SimpleDatabase};
+use crate::utils;
+use futures_util::{SinkExt, StreamExt};
+use secp256k1::hashes::{sha256, Hash};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tungstenite::Message;
+use warp::{Filter, Reply};
+
+pub const TIMEOUT: Duration = Duration::from_secs(10);
+
+// Checks id and signature of an event the way a relay would (NIP-01)
+pub fn verify_event(event: &Value) -> bool {
+    let serialized = json!([
+        0,
+        event["pubkey"],
+        event["created_at"],
+        event["kind"],
+        event["tags"],
+        event["content"]
+    ])
+    .to_string();
+    let id = sha256::Hash::hash(serialized.as_bytes());
+    if event["id"].as_str() != Some(id.to_string().as_str()) {
+        return false;
+    }
+
+    let secp = secp256k1::Secp256k1::verification_only();
+    let (pubkey, sig) = match (
+        secp256k1::XOnlyPublicKey::from_str(event["pubkey"].as_str().unwrap_or_default()),
+        secp256k1::schnorr::Signature::from_str(event["sig"].as_str().unwrap_or_default()),
+    ) {
+        (Ok(pubkey), Ok(sig)) => (pubkey, sig),
+        _ => return false,
+    };
+    let message = secp256k1::Message::from_slice(&id[..]).unwrap();
+    secp.verify_schnorr(&sig, &message, &pubkey).is_ok()
+}
+
+// Rumor inside a NIP-17 gift wrap to `recipient`
+pub fn open_gift_wrap(recipient: &secp256k1::KeyPair, wrap: &Value) -> Value {
+    let open = |event: &Value| {
+        let pubkey = secp256k1::XOnlyPublicKey::from_str(event["pubkey"].as_str().unwrap()).unwrap();
+        let key = dm::conversation_key(&recipient.secret_key(), &pubkey);
+        serde_json::from_str::<Value>(&dm::nip44_decrypt(&key, event["content"].as_str().unwrap()).unwrap()).unwrap()
+    };
+    let seal = open(wrap);
+    assert!(verify_event(&seal));
+    open(&seal)
+}
+
+pub struct MockRelay {
+    url: String,
+    events: Arc<Mutex<Vec<Value>>>,
+    rejection: Arc<Mutex<Option<String>>>,
+    auth: Arc<Mutex<MockAuth>>,
+}
+
+// NIP-42 state of a MockRelay
+#[derive(Default)]
+struct MockAuth {
+    required: bool,
+    // Public keys that sent a valid AUTH, on any connection
+    authenticated: Vec<String>,
+}
+
+impl MockRelay {
+    pub async fn start() -> MockRelay {
+        MockRelay::start_on("127.0.0.1:0").await
+    }
+
+    pub async fn start_on(address: &str) -> MockRelay {
+        let listener = tokio::net::TcpListener::bind(address).await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let events = Arc::new(Mutex::new(vec![]));
+        let rejection = Arc::new(Mutex::new(None));
+        let auth = Arc::new(Mutex::new(MockAuth::default()));
+
+        let (events_clone, rejection_clone, auth_clone) = (events.clone(), rejection.clone(), auth.clone());
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(handle_relay_connection(stream, events_clone.clone(), rejection_clone.clone(), auth_clone.clone()));
+            }
+        });
+
+        MockRelay { url, events, rejection, auth }
+    }
+
+    // Rejects events with auth-required: unless their author sent a valid AUTH on the connection
+    pub fn set_requiring_auth(&self, required: bool) {
+        self.auth.lock().unwrap().required = required;
+    }
+
+    pub fn authenticated(&self) -> Vec<String> {
+        self.auth.lock().unwrap().authenticated.clone()
+    }
+
+    // Answers every event with OK false and `reason` instead of storing it, None accepts again
+    pub fn set_rejecting(&self, reason: Option<&str>) {
+        *self.rejection.lock().unwrap() = reason.map(str::to_string);
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    // Events with valid signatures the relay accepted, in the order they arrived
+    pub fn events(&self) -> Vec<Value> {
+        self.events.lock().unwrap().clone()
+    }
+
+    pub fn events_by(&self, pubkey: &str, kind: u64) -> Vec<Value> {
+        self.events()
+            .into_iter()
+            .filter(|event| event["pubkey"] == pubkey && event["kind"] == kind)
+            .collect()
+    }
+
+    // Waits until `count` events of `pubkey` with `kind` arrived, panics after TIMEOUT
+    pub async fn wait_for(&self, pubkey: &str, kind: u64, count: usize) -> Vec<Value> {
+        self.wait_until(count, &format!("events of kind {} from {}", kind, pubkey), |event| {
+            event["pubkey"] == pubkey && event["kind"] == kind
+        })
+        .await
+    }
+
+    // Same for events of `kind` addressed to `pubkey` with a p tag, e.g. DMs
+    pub async fn wait_for_to(&self, pubkey: &str, kind: u64, count: usize) -> Vec<Value> {
+        self.wait_until(count, &format!("events of kind {} to {}", kind, pubkey), |event| {
+            event["kind"] == kind && event["tags"].as_array().unwrap().iter().any(|tag| tag[0] == "p" && tag[1] == pubkey)
+        })
+        .await
+    }
+
+    async fn wait_until(&self, count: usize, what: &str, filter: impl Fn(&Value) -> bool) -> Vec<Value> {
+        let started = std::time::Instant::now();
+        loop {
+            let events = self.events().into_iter().filter(|event| filter(event)).collect::<Vec<_>>();
+            if events.len() >= count {
+                return events;
+            }
+            assert!(
+                started.elapsed() < TIMEOUT,
+                "Timed out waiting for {} {}, got {:#?}",
+                count,
+                what,
+                events
+            );
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+}
+
+async fn handle_relay_connection(
+    stream: tokio::net::TcpStream,
+    events: Arc<Mutex<Vec<Value>>>,
+    rejection: Arc<Mutex<Option<String>>>,
+    auth: Arc<Mutex<MockAuth>>,
+) {
+    let ws = match tokio_tungstenite::accept_async(stream).await {
+        Ok(ws) => ws,
+        Err(_) => return,
+    };
+    let (mut sink, mut stream) = ws.split();
+
+    // Like most relays the challenge comes right away, whether AUTH is required or not
+    let challenge = format!("{:016x}", rand::random::<u64>());
+    let mut authenticated = vec![];
+    if sink.send(Message::Text(json!(["AUTH", challenge]).to_string())).await.is_err() {
+        return;
+    }
+
+    while let Some(Ok(message)) = stream.next().await {
+        let text = match message {
+            Message::Text(text) => text,
+            Message::Close(_) => break,
+            _ => continue,
+        };
+        let message: Value = match serde_json::from_str(&text) {
+            Ok(message) => message,
+            Err(_) => continue,
+        };
+
+        let reply = match message[0].as_str() {
+            Some("EVENT") => {
+                let event = &message[1];
+                let rejection = rejection.lock().unwrap().clone();
+                let unauthenticated = auth.lock().unwrap().required && !authenticated.contains(&event["pubkey"]);
+                if let Some(reason) = rejection {
+                    json!(["OK", event["id"], false, reason])
+                } else if unauthenticated {
+                    json!(["OK", event["id"], false, "auth-required: we only accept events from members"])
+                } else {
+                    let valid = verify_event(event);
+                    if valid {
+                        events.lock().unwrap().push(event.clone());
+                    }
+                    json!(["OK", event["id"], valid, if valid { "" } else { "invalid: bad signature" }])
+                }
+            }
+            Some("AUTH") => {
+                let event = &message[1];
+                let tags = event["tags"].as_array().cloned().unwrap_or_default();
+                let valid = verify_event(event)
+                    && event["kind"] == 22242
+                    && tags.iter().any(|tag| tag[0] == "challenge" && tag[1] == challenge.as_str())
+                    && tags.iter().any(|tag| tag[0] == "relay");
+                if valid {
+                    authenticated.push(event["pubkey"].clone());
+                    auth.lock().unwrap().authenticated.push(event["pubkey"].as_str().unwrap().to_string());
+                }
+                json!(["OK", event["id"], valid, if valid { "" } else { "invalid: bad AUTH event" }])
+            }
+            Some("REQ") => json!(["EOSE", message[1]]),
+            _ => continue,
+        };
+
+        if sink.send(Message::Text(reply.to_string())).await.is_err() {
+            break;
+        }
+    }
+}
+
+// NIP-46 state of a MockBunker
+struct BunkerState {
+    user: secp256k1::KeyPair,
+    secret: Option<String>,
+    // Client keys that connected with the secret or were authorized up front
+    authorized: Vec<String>,
+    signed: Vec<Value>,
+    // Offline bunkers close their connections and refuse new ones
+    offline: bool,
+}
+
+pub struct MockBunker {
+    url: String,
+    remote: secp256k1::KeyPair,
+    state: Arc<Mutex<BunkerState>>,
+}
+
+impl MockBunker {
+    // Signs as `user` for clients knowing `secret`, None lets every client connect
+    pub async fn start(user: secp256k1::KeyPair, secret: Option<&str>) -> MockBunker {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let remote = utils::get_random_keypair();
+        let state = Arc::new(Mutex::new(BunkerState {
+            user,
+            secret: secret.map(str::to_string),
+            authorized: vec![],
+            signed: vec![],
+            offline: false,
+        }));
+
+        let state_clone = state.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                if state_clone.lock().unwrap().offline {
+                    continue;
+                }
+                tokio::spawn(handle_bunker_connection(stream, remote, state_clone.clone()));
+            }
+        });
+
+        MockBunker { url, remote, state }
+    }
+
+    pub fn uri(&self, secret: Option<&str>) -> String {
+        let remote = self.remote.x_only_public_key().0;
+        match secret {
+            Some(secret) => format!("bunker://{}?relay={}&secret={}", remote, self.url, secret),
+            None => format!("bunker://{}?relay={}", remote, self.url),
+        }
+    }
+
+    pub fn authorize(&self, client: &secp256k1::XOnlyPublicKey) {
+        self.state.lock().unwrap().authorized.push(client.to_string());
+    }
+
+    // Events the bunker signed, in order
+    pub fn signed(&self) -> Vec<Value> {
+        self.state.lock().unwrap().signed.clone()
+    }
+
+    pub fn set_offline(&self, offline: bool) {
+        self.state.lock().unwrap().offline = offline;
+    }
+}
+
+// Result or error of a NIP-46 request from `client`
+fn bunker_response(state: &Mutex<BunkerState>, client: &str, method: &str, params: &[Value]) -> (String, String) {
+    let mut state = state.lock().unwrap();
+    if method == "connect" {
+        let secret_matches = state.secret.is_none() || params.get(1).and_then(Value::as_str) == state.secret.as_deref();
+        if !secret_matches && !state.authorized.iter().any(|authorized| authorized == client) {
+            return (String::new(), "invalid secret".to_string());
+        }
+        state.authorized.push(client.to_string());
+        return ("ack".to_string(), String::new());
+    }
+    if !state.authorized.iter().any(|authorized| authorized == client) {
+        return (String::new(), "unauthorized".to_string());
+    }
+    match method {
+        "get_public_key" => (state.user.x_only_public_key().0.to_string(), String::new()),
+        "sign_event" => {
+            let unsigned: Value = serde_json::from_str(params[0].as_str().unwrap()).unwrap();
+            let tags = serde_json::from_value::<Vec<Vec<String>>>(unsigned["tags"].clone()).unwrap();
+            let event = nostr_bot::Event::new(
+                &state.user,
+                unsigned["created_at"].as_u64().unwrap(),
+                unsigned["kind"].as_u64().unwrap(),
+                tags,
+                unsigned["content"].as_str().unwrap().to_string(),
+            );
+            let signed = serde_json::from_str::<Value>(&event.format()).unwrap()[1].clone();
+            state.signed.push(signed.clone());
+            (signed.to_string(), String::new())
+        }
+        _ => (String::new(), format!("unknown method {}", method)),
+    }
+}
+
+async fn handle_bunker_connection(stream: tokio::net::TcpStream, remote: secp256k1::KeyPair, state: Arc<Mutex<BunkerState>>) {
+    let ws = match tokio_tungstenite::accept_async(stream).await {
+        Ok(ws) => ws,
+        Err(_) => return,
+    };
+    let (mut sink, mut stream) = ws.split();
+    let remote_pubkey = remote.x_only_public_key().0.to_string();
+    let mut subscription = Value::Null;
+
+    while let Some(Ok(Message::Text(text))) = stream.next().await {
+        if state.lock().unwrap().offline {
+            return;
+        }
+        let message: Value = match serde_json::from_str(&text) {
+            Ok(message) => message,
+            Err(_) => continue,
+        };
+        let mut replies = vec![];
+        match message[0].as_str() {
+            Some("REQ") => {
+                subscription = message[1].clone();
+                replies.push(json!(["EOSE", subscription]));
+            }
+            Some("EVENT") => {
+                let event = &message[1];
+                replies.push(json!(["OK", event["id"], verify_event(event), ""]));
+                let addressed = event["tags"].as_array().unwrap().iter().any(|tag| tag[0] == "p" && tag[1] == remote_pubkey.as_str());
+                if event["kind"] == 24133 && addressed && verify_event(event) {
+                    let client = secp256k1::XOnlyPublicKey::from_str(event["pubkey"].as_str().unwrap()).unwrap();
+                    let key = dm::conversation_key(&remote.secret_key(), &client);
+                    let request: Value = serde_json::from_str(&dm::nip44_decrypt(&key, event["content"].as_str().unwrap()).unwrap()).unwrap();
+                    let params = request["params"].as_array().cloned().unwrap_or_default();
+                    let (result, error) = bunker_response(&state, &client.to_string(), request["method"].as_str().unwrap(), &params);
+                    let content = dm::nip44_encrypt(&key, &json!({"id": request["id"], "result": result, "error": error}).to_string()).unwrap();
+                    let tags = vec![vec!["p".to_string(), client.to_string()]];
+                    let response = nostr_bot::Event::new(&remote, utils::unix_timestamp(), 24133, tags, content);
+                    let response = serde_json::from_str::<Value>(&response.format()).unwrap()[1].clone();
+                    replies.push(json!(["EVENT", subscription, response]));
+                }
+            }
+            _ => continue,
+        }
+        for reply in replies {
+            if sink.send(Message::Text(reply.to_string())).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+// URL shortener answering with the redirects tests set up, other paths are the pages they lead
+// to. Paths under /slow/ answer after SLOW_ANSWER.
+pub struct MockShortener {
+    url: String,
+    redirects: Arc<Mutex<HashMap<String, String>>>,
+    requests: Arc<Mutex<Vec<String>>>,
+}
+
+const SLOW_ANSWER: Duration = Duration::from_secs(5);
+
+impl MockShortener {
+    pub async fn start() -> MockShortener {
+        let redirects: Arc<Mutex<HashMap<String, String>>> = Arc::new(Mutex::new(HashMap::new()));
+        let requests = Arc::new(Mutex::new(vec![]));
+
+        let routes = {
+            let (redirects, requests) = (redirects.clone(), requests.clone());
+            warp::path::full().and_then(move |path: warp::path::FullPath| {
+                let path = path.as_str().to_string();
+                requests.lock().unwrap().push(path.clone());
+                let location = redirects.lock().unwrap().get(&path).cloned();
+                async move {
+                    if path.starts_with("/slow/") {
+                        tokio::time::sleep(SLOW_ANSWER).await;
+                    }
+                    Ok::<_, warp::Rejection>(match location {
+                        Some(location) => warp::reply::with_header(warp::http::StatusCode::FOUND, "Location", location).into_response(),
+                        None => warp::reply::html("<html></html>").into_response(),
+                    })
+                }
+            })
+        };
+        let (addr, server) = warp::serve(routes).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        MockShortener {
+            url: format!("http://{}", addr),
+            redirects,
+            requests,
+        }
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("{}{}", self.url, path)
+    }
+
+    // `from` redirects to the path or URL `to`
+    pub fn redirect(&self, from: &str, to: &str) {
+        self.redirects.lock().unwrap().insert(from.to_string(), to.to_string());
+    }
+
+    // Paths of all requests made so far
+    pub fn requests(&self) -> Vec<String> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+// Upload a MockMediaServer took, with the NIP-98 or Blossom authorization event it came with
+#[derive(Clone, Debug)]
+pub struct Upload {
+    pub auth: Value,
+    pub sha256: String,
+    pub mime: String,
+    pub size: usize,
+}
+
+struct MediaServerState {
+    // Original files by name, as served under /files/
+    files: HashMap<String, (Vec<u8>, String)>,
+    uploads: Vec<Upload>,
+    failing: bool,
+}
+
+type MediaState = Arc<Mutex<MediaServerState>>;
+
+// Blossom or NIP-96 media server, also hosting the original files the bot downloads
+pub struct MockMediaServer {
+    url: String,
+    state: MediaState,
+}
+
+impl MockMediaServer {
+    pub async fn start(kind: MediaServerKind) -> MockMediaServer {
+        let state = Arc::new(Mutex::new(MediaServerState {
+            files: HashMap::new(),
+            uploads: vec![],
+            failing: false,
+        }));
+        let addr = serve_media(kind, state.clone());
+        MockMediaServer {
+            url: format!("http://{}", addr),
+            state,
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    // Serves `bytes` as /files/`name`, returns its URL
+    pub fn host(&self, name: &str, bytes: &[u8], mime: &str) -> String {
+        self.state.lock().unwrap().files.insert(name.to_string(), (bytes.to_vec(), mime.to_string()));
+        format!("{}/files/{}", self.url, name)
+    }
+
+    // A failing server answers every upload with 500
+    pub fn set_failing(&self, failing: bool) {
+        self.state.lock().unwrap().failing = failing;
+    }
+
+    pub fn uploads(&self) -> Vec<Upload> {
+        self.state.lock().unwrap().uploads.clone()
+    }
+}
+
+// Authorization event of a "Nostr <base64>" header, if its signature holds
+fn media_auth(header: Option<String>, kind: u64) -> Option<Value> {
+    use base64::Engine;
+    let encoded = header?.strip_prefix("Nostr ")?.to_string();
+    let json = base64::engine::general_purpose::STANDARD.decode(encoded).ok()?;
+    let event = serde_json::from_slice::<Value>(&json).ok()?;
+    (verify_event(&event) && event["kind"] == kind).then_some(event)
+}
+
+fn auth_tag<'a>(event: &'a Value, name: &str) -> Option<&'a str> {
+    event["tags"].as_array()?.iter().find(|tag| tag[0] == name).and_then(|tag| tag[1].as_str())
+}
+
+// Stores an upload, returns its URL on the server
+fn store_upload(state: &MediaState, url: &str, auth: Value, bytes: Vec<u8>, mime: String) -> (String, Upload) {
+    let sha256 = sha256::Hash::hash(&bytes).to_string();
+    let upload = Upload {
+        auth,
+        sha256: sha256.clone(),
+        mime: mime.clone(),
+        size: bytes.len(),
+    };
+    let mut state = state.lock().unwrap();
+    state.uploads.push(upload.clone());
+    state.files.insert(sha256.clone(), (bytes, mime));
+    (format!("{}/files/{}", url, sha256), upload)
+}
+
+fn serve_media(kind: MediaServerKind, state: MediaState) -> std::net::SocketAddr {
+    use futures_util::TryStreamExt;
+    use warp::Buf;
+
+    // URL of the server as the bot reached it
+    let base_url = || {
+        warp::host::optional().map(|host: Option<warp::host::Authority>| format!("http://{}", host.map(|host| host.to_string()).unwrap_or_default()))
+    };
+
+    let files = {
+        let state = state.clone();
+        warp::get().or(warp::head()).unify().and(warp::path!("files" / String)).map(move |name: String| {
+            match state.lock().unwrap().files.get(&name).cloned() {
+                Some((bytes, mime)) => warp::reply::with_header(bytes, "Content-Type", mime).into_response(),
+                None => warp::http::StatusCode::NOT_FOUND.into_response(),
+            }
+        })
+    };
+
+    // BUD-02 upload, authorized by a kind 24242 event with the hash of the body
+    let blossom = {
+        let state = state.clone();
+        warp::put()
+            .and(warp::path("upload"))
+            .and(base_url())
+            .and(warp::header::optional::<String>("authorization"))
+            .and(warp::header::optional::<String>("content-type"))
+            .and(warp::body::bytes())
+            .map(move |url: String, authorization: Option<String>, mime: Option<String>, body: warp::hyper::body::Bytes| {
+                if state.lock().unwrap().failing {
+                    let reply = warp::reply::with_status(warp::reply(), warp::http::StatusCode::INTERNAL_SERVER_ERROR);
+                    return warp::reply::with_header(reply, "X-Reason", "Storage is full").into_response();
+                }
+                let sha256 = sha256::Hash::hash(&body).to_string();
+                let auth = match media_auth(authorization, 24242) {
+                    Some(auth) if auth_tag(&auth, "t") == Some("upload") && auth_tag(&auth, "x") == Some(sha256.as_str()) => auth,
+                    _ => return warp::http::StatusCode::UNAUTHORIZED.into_response(),
+                };
+                let mime = mime.unwrap_or_else(|| "application/octet-stream".to_string());
+                let (url, upload) = store_upload(&state, &url, auth, body.to_vec(), mime);
+                warp::reply::json(&json!({"url": url, "sha256": upload.sha256, "size": upload.size, "type": upload.mime})).into_response()
+            })
+    };
+
+    // NIP-96 upload, authorized by a NIP-98 event for the API URL
+    let nip96_info = {
+        warp::get()
+            .and(warp::path!(".well-known" / "nostr" / "nip96.json"))
+            .and(base_url())
+            .map(|url: String| warp::reply::json(&json!({"api_url": format!("{}/nip96", url)})).into_response())
+    };
+    let nip96 = {
+        let state = state.clone();
+        warp::post()
+            .and(warp::path("nip96"))
+            .and(base_url())
+            .and(warp::header::optional::<String>("authorization"))
+            .and(warp::multipart::form())
+            .and_then(move |url: String, authorization: Option<String>, form: warp::multipart::FormData| {
+                let state = state.clone();
+                async move {
+                    let parts = form
+                        .and_then(|part| async move {
+                            let name = part.name().to_string();
+                            let bytes = part
+                                .stream()
+                                .try_fold(vec![], |mut bytes, chunk| async move {
+                                    bytes.extend_from_slice(chunk.chunk());
+                                    Ok(bytes)
+                                })
+                                .await?;
+                            Ok((name, bytes))
+                        })
+                        .try_collect::<Vec<_>>()
+                        .await
+                        .map_err(|_| warp::reject())?;
+
+                    if state.lock().unwrap().failing {
+                        let reply = warp::reply::json(&json!({"status": "error", "message": "Storage is full"}));
+                        return Ok::<_, warp::Rejection>(warp::reply::with_status(reply, warp::http::StatusCode::INTERNAL_SERVER_ERROR).into_response());
+                    }
+                    let api_url = format!("{}/nip96", url);
+                    let auth = match media_auth(authorization, 27235) {
+                        Some(auth) if auth_tag(&auth, "u") == Some(api_url.as_str()) && auth_tag(&auth, "method") == Some("POST") => auth,
+                        _ => return Ok(warp::http::StatusCode::UNAUTHORIZED.into_response()),
+                    };
+                    // warp only tells the type of the file part without its subtype, NIP-96 sends it as a field too
+                    let mut parts = parts.into_iter().collect::<HashMap<_, _>>();
+                    let mime = String::from_utf8(parts.remove("content_type").unwrap_or_default()).unwrap_or_default();
+                    let bytes = match parts.remove("file") {
+                        Some(bytes) => bytes,
+                        None => return Ok(warp::http::StatusCode::BAD_REQUEST.into_response()),
+                    };
+                    let (url, upload) = store_upload(&state, &url, auth, bytes, mime);
+                    let tags = json!([["url", url], ["m", upload.mime], ["x", upload.sha256], ["ox", upload.sha256], ["dim", "2x1"]]);
+                    Ok(warp::reply::json(&json!({"status": "success", "nip94_event": {"tags": tags}})).into_response())
+                }
+            })
+    };
+
+    let uploads = match kind {
+        MediaServerKind::Blossom => blossom.boxed(),
+        MediaServerKind::Nip96 => nip96_info.or(nip96).unify().boxed(),
+    };
+    let (addr, server) = warp::serve(files.or(uploads)).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(server);
+    addr
+}
+
+#[derive(Clone)]
+struct FeedItem {
+    id: u64,
+    description: String,
+    pub_date: chrono::DateTime<chrono::Utc>,
+    video: Option<String>,
+}
+
+type Feeds = Arc<Mutex<HashMap<String, Vec<FeedItem>>>>;
+
+// Nitter instances serving the same accounts, like instances in front of the same Twitter
+pub struct MockNitter {
+    urls: Vec<String>,
+    feeds: Feeds,
+    failures: Vec<Arc<Mutex<Option<Failure>>>>,
+    requests: Arc<Mutex<Vec<(usize, String)>>>,
+    not_modified: Arc<AtomicUsize>,
+    missing: Arc<Mutex<Vec<String>>>,
+}
+
+impl MockNitter {
+    pub async fn start() -> MockNitter {
+        MockNitter::start_instances(1).await
+    }
+
+    pub async fn start_instances(count: usize) -> MockNitter {
+        let feeds: Feeds = Arc::new(Mutex::new(HashMap::new()));
+        let requests = Arc::new(Mutex::new(vec![]));
+        let not_modified = Arc::new(AtomicUsize::new(0));
+        let missing = Arc::new(Mutex::new(vec![]));
+        let mut urls = vec![];
+        let mut failures = vec![];
+
+        for index in 0..count {
+            let failure = Arc::new(Mutex::new(None));
+            let addr = serve_nitter_instance(index, feeds.clone(), failure.clone(), requests.clone(), not_modified.clone(), missing.clone());
+            urls.push(format!("http://{}", addr));
+            failures.push(failure);
+        }
+
+        MockNitter {
+            urls,
+            feeds,
+            failures,
+            requests,
+            not_modified,
+            missing,
+        }
+    }
+
+    pub fn urls(&self) -> Vec<String> {
+        self.urls.clone()
+    }
+
+    // A down instance answers every request with 503
+    pub fn set_down(&self, index: usize, down: bool) {
+        *self.failures[index].lock().unwrap() = if down { Some(Failure::Down) } else { None };
+    }
+
+    // A rate limiting instance answers every request with 429 and Retry-After
+    pub fn set_rate_limited(&self, index: usize, retry_after_secs: u64) {
+        *self.failures[index].lock().unwrap() = Some(Failure::RateLimited(retry_after_secs));
+    }
+
+    // Feeds of handles that don't exist are answered with 404 like Nitter does
+    pub fn set_missing(&self, handle: &str) {
+        self.missing.lock().unwrap().push(handle.to_string());
+    }
+
+    // Paths of all requests made so far
+    pub fn requests(&self) -> Vec<String> {
+        self.requests.lock().unwrap().iter().map(|(_, path)| path.clone()).collect()
+    }
+
+    // Number of feed requests answered with 304 Not Modified
+    pub fn not_modified(&self) -> usize {
+        self.not_modified.load(Ordering::SeqCst)
+    }
+
+    // Paths of the requests made to instance `index`
+    pub fn requests_to(&self, index: usize) -> Vec<String> {
+        self.requests
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(i, _)| *i == index)
+            .map(|(_, path)| path.clone())
+            .collect()
+    }
+
+    // Adds a tweet with HTML `description` dated to the next full second, returns its id
+    pub fn post(&self, handle: &str, description: &str) -> u64 {
+        self.add_item(handle, description, None, next_second())
+    }
+
+    pub fn post_video(&self, handle: &str, description: &str, video: &str) -> u64 {
+        self.add_item(handle, description, Some(video.to_string()), next_second())
+    }
+
+    // Adds a tweet that was posted before the bot started
+    pub fn post_old(&self, handle: &str, description: &str) -> u64 {
+        self.add_item(handle, description, None, chrono::Utc::now() - chrono::Duration::hours(1))
+    }
+
+    fn add_item(&self, handle: &str, description: &str, video: Option<String>, pub_date: chrono::DateTime<chrono::Utc>) -> u64 {
+        let mut feeds = self.feeds.lock().unwrap();
+        let id = 1000 + feeds.values().map(|items| items.len() as u64).sum::<u64>();
+        feeds.entry(handle.to_string()).or_default().push(FeedItem {
+            id,
+            description: description.to_string(),
+            pub_date,
+            video,
+        });
+        id
+    }
+}
+
+fn serve_nitter_instance(
+    index: usize,
+    feeds: Feeds,
+    failure: Arc<Mutex<Option<Failure>>>,
+    requests: Arc<Mutex<Vec<(usize, String)>>>,
+    not_modified: Arc<AtomicUsize>,
+    missing: Arc<Mutex<Vec<String>>>,
+) -> std::net::SocketAddr {
+    let log = warp::path::full().map(move |path: warp::path::FullPath| {
+        requests.lock().unwrap().push((index, path.as_str().to_string()));
+    });
+
+    let available = warp::any().and_then(move || {
+        let failure = *failure.lock().unwrap();
+        async move {
+            match failure {
+                Some(failure) => Err(warp::reject::custom(failure)),
+                None => Ok(()),
+            }
+        }
+    });
+
+    // Feeds carry an ETag and unchanged feeds are answered with 304 like Nitter does
+    let rss = {
+        let feeds = feeds.clone();
+        let not_modified = not_modified.clone();
+        warp::path!(String / "rss")
+            .and(warp::host::optional())
+            .and(warp::header::optional::<String>("if-none-match"))
+            .map(move |handle: String, host: Option<warp::host::Authority>, if_none_match: Option<String>| {
+                if missing.lock().unwrap().contains(&handle) {
+                    return warp::http::StatusCode::NOT_FOUND.into_response();
+                }
+                let host = host.map(|host| host.to_string()).unwrap_or_default();
+                let items = feeds.lock().unwrap().get(&handle).cloned().unwrap_or_default();
+                let body = rss_feed(&host, &handle, &items);
+                let etag = format!("\"{}\"", sha256::Hash::hash(body.as_bytes()));
+
+                if if_none_match.as_deref() == Some(etag.as_str()) {
+                    not_modified.fetch_add(1, Ordering::SeqCst);
+                    return warp::reply::with_status(warp::reply(), warp::http::StatusCode::NOT_MODIFIED).into_response();
+                }
+                let reply = warp::reply::with_header(body, "Content-Type", "application/rss+xml");
+                warp::reply::with_header(reply, "ETag", etag).into_response()
+            })
+    };
+
+    let status = warp::path!(String / "status" / u64).map(move |handle: String, id: u64| {
+        let video = feeds
+            .lock()
+            .unwrap()
+            .get(&handle)
+            .and_then(|items| items.iter().find(|item| item.id == id).cloned())
+            .and_then(|item| item.video);
+        let body = match video {
+            Some(video) => format!(r#"<html><body><div id="m"><video><source src="{}"></video></div></body></html>"#, video),
+            None => r#"<html><body><div id="m"></div></body></html>"#.to_string(),
+        };
+        warp::reply::html(body)
+    });
+
+    let routes = log
+        .untuple_one()
+        .and(available)
+        .untuple_one()
+        .and(rss.or(status))
+        .recover(|err: warp::Rejection| async move {
+            match err.find::<Failure>() {
+                Some(Failure::Down) => Ok(warp::http::StatusCode::SERVICE_UNAVAILABLE.into_response()),
+                Some(Failure::RateLimited(retry_after_secs)) => Ok(warp::reply::with_header(
+                    warp::http::StatusCode::TOO_MANY_REQUESTS,
+                    "Retry-After",
+                    retry_after_secs.to_string(),
+                )
+                .into_response()),
+                None => Err(err),
+            }
+        });
+    let (addr, server) = warp::serve(routes).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(server);
+    addr
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Failure {
+    Down,
+    RateLimited(u64),
+}
+
+impl warp::reject::Reject for Failure {}
+
+fn next_second() -> chrono::DateTime<chrono::Utc> {
+    let now = chrono::Utc::now().timestamp();
+    chrono::DateTime::from_utc(chrono::NaiveDateTime::from_timestamp_opt(now + 1, 0).unwrap(), chrono::Utc)
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+// RSS feed in the shape Nitter serves it, newest item first
+fn rss_feed(host: &str, handle: &str, items: &[FeedItem]) -> String {
+    let mut xml = format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<rss version="2.0" xmlns:atom="http://www.w3.org/2005/Atom" xmlns:dc="http://purl.org/dc/elements/1.1/">
+<channel>
+<title>{name} / @{handle}</title>
+<link>http://{host}/{handle}</link>
+<description>Twitter feed for: @{handle}. Generated by {host}</description>
+<image>
+<title>{name} / @{handle}</title>
+<link>http://{host}/{handle}</link>
+<url>http://{host}/pic/profile_images%2F1%2F{handle}_400x400.jpg</url>
+</image>
+"#,
+        name = handle.to_uppercase(),
+        handle = handle,
+        host = host
+    );
+
+    for item in items.iter().rev() {
+        xml += &format!(
+            r#"<item>
+<title>{title}</title>
+<dc:creator>@{handle}</dc:creator>
+<description>{description}</description>
+<pubDate>{pub_date}</pubDate>
+<guid>http://{host}/{handle}/status/{id}#m</guid>
+<link>http://{host}/{handle}/status/{id}#m</link>
+</item>
+"#,
+            title = escape_xml(&item.description),
+            handle = handle,
+            description = escape_xml(&item.description),
+            pub_date = item.pub_date.to_rfc2822(),
+            host = host,
+            id = item.id
+        );
+    }
+
+    xml + "</channel>\n</rss>\n"
+}
+
+pub struct TestBot {
+    pub relay: MockRelay,
+    pub nitter: MockNitter,
+    pub state: State,
+    pub config: utils::Config,
+    // Gets the alerts of the bot
+    pub operator: secp256k1::KeyPair,
+    dir: PathBuf,
+    stop: Option<tokio::sync::oneshot::Sender<()>>,
+}
+
+impl TestBot {
+    pub fn config(
+        relay: &MockRelay,
+        nitter: &MockNitter,
+        keypair: &secp256k1::KeyPair,
+        operator: &secp256k1::KeyPair,
+        dir: &std::path::Path,
+    ) -> utils::Config {
+        utils::Config {
+            name: "dostr".to_string(),
+            about: "Test bot".to_string(),
+            picture_url: "https://example.com/dostr.png".to_string(),
+            hello_message: "Hello".to_string(),
+            secret: keypair.display_secret().to_string(),
+            botpub: keypair.x_only_public_key().0.to_string(),
+            apik: "".to_string(),
+            web_port: 0,
+            nitter_instances: nitter.urls(),
+            nitter_health_check_secs: 1,
+            nitter_health_handle: "jack".to_string(),
+            domain: "example.com".to_string(),
+            refresh_interval_secs: 1,
+            max_refresh_interval_secs: 2,
+            max_concurrent_fetches: 4,
+            max_fetches_per_host: 2,
+            host_request_interval_ms: 0,
+            relays: vec![relay.url().to_string()],
+            max_follows: 10,
+            media_server: "".to_string(),
+            media_server_type: "blossom".to_string(),
+            media_probe: "off".to_string(),
+            link_frontend: "x.com".to_string(),
+            unshorten_domains: vec![],
+            unshorten_timeout_secs: 5,
+            web_dir: dir.join("web").to_string_lossy().to_string(),
+            tor_socks_address: "127.0.0.1:9050".to_string(),
+            operator_pubkeys: vec![operator.x_only_public_key().0],
+            alert_dm_kind: DmKind::Nip17,
+            alert_failure_threshold: 2,
+            alert_repeat_secs: 0,
+            relay_check_secs: 0,
+            outbox_retry_secs: 1,
+            outbox_max_age_secs: 0,
+            derive_feed_keys: false,
+            key_passphrase: "correct horse battery staple".to_string(),
+            // Fast enough for tests, far too fast for real keys
+            key_scrypt_log_n: 4,
+            bunker_url: String::new(),
+            use_tor: false,
+        }
+    }
+
+    // Starts the bot following Twitter `handles`, returns once it is connected to the relay
+    pub async fn start(handles: &[&str]) -> TestBot {
+        TestBot::start_with(handles, MockNitter::start().await).await
+    }
+
+    pub async fn start_with(handles: &[&str], nitter: MockNitter) -> TestBot {
+        let relay = MockRelay::start().await;
+        let keypair = utils::get_random_keypair();
+
+        let dir = std::env::temp_dir().join(format!("dostr-test-{}", keypair.x_only_public_key().0));
+        std::fs::create_dir_all(dir.join("web/.well-known")).unwrap();
+        std::fs::write(dir.join("web/.well-known/nostr.json"), r#"{"names":{}}"#).unwrap();
+
+        let operator = utils::get_random_keypair();
+        let config = TestBot::config(&relay, &nitter, &keypair, &operator, &dir);
+
+        let mut db = SimpleDatabase::from_file_encrypted(
+            dir.join("channels").to_string_lossy().to_string(),
+            KeyEncryption::from_config(&config),
+        )
+        .unwrap();
+        for handle in handles {
+            let secret = utils::get_random_keypair().display_secret().to_string();
+            db.insert(FeedId::twitter(handle), secret, handle.to_string()).unwrap();
+        }
+
+        let (tx, rx) = tokio::sync::mpsc::channel::<alerts::ConnectionMessage>(64);
+
+        let sender = nostr_bot::new_sender();
+        let pool = Relays::from_config(&config, dir.join("relays"));
+        pool.add_key(&keypair);
+        let outbox = EventOutbox::from_config(&config, pool.clone(), dir.join("outbox"));
+        let feeds = Arc::new(FeedClient::from_config(&config));
+        let state = nostr_bot::wrap_state(DostrState {
+            config: config.clone(),
+            db: Arc::new(Mutex::new(db)),
+            error_sender: tx.clone(),
+            started_timestamp: nostr_bot::unix_timestamp(),
+            discord_context: Arc::new(tokio::sync::Mutex::new(None)),
+            media_cache: crate::media::new_cache(),
+            link_cache: crate::fetch::new_link_cache(),
+            nitter: nitter::NitterPool::from_config(&config, feeds.clone()),
+            feeds,
+            scheduler: FetchScheduler::from_config(&config),
+            outbox: outbox.clone(),
+            relays: pool,
+        });
+
+        let health_checker = {
+            let nitter = state.lock().await.nitter.clone();
+            nitter::health_checker(nitter, config.nitter_health_check_secs, tx)
+        };
+
+        let alert_listener = alerts::alert_listener(rx, outbox, keypair, config.clone());
+
+        let start_existing = {
+            let state = state.clone();
+            async move {
+                dostr::start_existing(state).await;
+            }
+        };
+
+        // Bot::run isn't Send, it gets a thread of its own and stops with the runtime when
+        // TestBot is dropped
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        {
+            let state = state.clone();
+            let relay_url = relay.url().to_string();
+            let config = config.clone();
+            std::thread::spawn(move || {
+                let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+                runtime.block_on(async move {
+                    let mut bot = nostr_bot::Bot::<State>::new(keypair, vec![relay_url.as_str()], state)
+                        .name(&config.name)
+                        .about(&config.about)
+                        .picture(&config.picture_url)
+                        .sender(sender)
+                        .spawn(Box::pin(start_existing))
+                        .spawn(Box::pin(health_checker))
+                        .spawn(Box::pin(alert_listener));
+                    tokio::select! {
+                        _ = bot.run() => {}
+                        _ = stopped => {}
+                    }
+                });
+            });
+        }
+
+        // The bot publishes its profile once connected
+        relay.wait_for(&config.botpub, 0, 1).await;
+
+        TestBot {
+            relay,
+            nitter,
+            state,
+            config,
+            operator,
+            dir,
+            stop: Some(stop),
+        }
+    }
+
+    // Sends `content` to the bot as a mention from a random user and returns the reply
+    pub async fn command<F, Fut>(&self, handler: F, content: &str) -> nostr_bot::EventNonSigned
+    where
+        F: FnOnce(nostr_bot::Event, State) -> Fut,
+        Fut: std::future::Future<Output = nostr_bot::EventNonSigned>,
+    {
+        self.command_as(&utils::get_random_keypair(), handler, content).await
+    }
+
+    // Same as command, sent by `user`
+    pub async fn command_as<F, Fut>(&self, user: &secp256k1::KeyPair, handler: F, content: &str) -> nostr_bot::EventNonSigned
+    where
+        F: FnOnce(nostr_bot::Event, State) -> Fut,
+        Fut: std::future::Future<Output = nostr_bot::EventNonSigned>,
+    {
+        let event = nostr_bot::Event::new(
+            user,
+            utils::unix_timestamp(),
+            1,
+            vec![vec!["p".to_string(), self.config.botpub.clone()]],
+            content.to_string(),
+        );
+        handler(event, self.state.clone()).await
+    }
+
+    // Public key of the mirror account following `handle`
+    pub async fn mirror_pubkey(&self, handle: &str) -> String {
+        let db = self.state.lock().await.db.clone();
+        let keypair = crate::simpledb::get_channel_keypair(&FeedId::twitter(handle), db).unwrap();
+        keypair.x_only_public_key().0.to_string()
+    }
+
+    pub fn nostr_json(&self) -> Value {
+        serde_json::from_str(&std::fs::read_to_string(self.config.nostr_json_path()).unwrap()).unwrap()
+    }
+}
+
+impl Drop for TestBot {
+    fn drop(&mut self) {
+        if let Some(stop) = self.stop.take() {
+            stop.send(()).ok();
+        }
+        std::fs::remove_dir_all(&self.dir).ok();
+    }
+}
@@ -9,11 +9,41 @@ pub struct Config {
     pub botpub: String,
     pub apik: String,
     pub web_port: u16,
-    pub nitter_instance: String,
+    pub nitter_instances: Vec<String>,
+    pub nitter_health_check_secs: u64,
+    pub nitter_health_handle: String,
     pub domain: String,
     pub refresh_interval_secs: u64,
+    pub max_refresh_interval_secs: u64,
+    pub max_concurrent_fetches: usize,
+    pub max_fetches_per_host: usize,
+    pub host_request_interval_ms: u64,
     pub relays: Vec<String>,
     pub max_follows: usize,
+    pub media_server: String,
+    pub media_server_type: String,
+    pub media_probe: String,
+    pub link_frontend: String,
+    pub unshorten_domains: Vec<String>,
+    pub unshorten_timeout_secs: u64,
+    pub web_dir: String,
+    pub tor_socks_address: String,
+    pub operator_pubkeys: Vec<secp256k1::XOnlyPublicKey>,
+    pub alert_dm_kind: crate::dm::DmKind,
+    pub alert_failure_threshold: u32,
+    pub alert_repeat_secs: u64,
+    pub relay_check_secs: u64,
+    pub outbox_retry_secs: u64,
+    pub outbox_max_age_secs: u64,
+    // New feeds get keys derived from SECRET instead of random ones
+    pub derive_feed_keys: bool,
+    // Feed keys are stored encrypted with this passphrase (NIP-49), SECRET unless set
+    pub key_passphrase: String,
+    pub key_scrypt_log_n: u8,
+    // NIP-46 remote signer of the bot's own events, SECRET signs them when empty
+    pub bunker_url: String,
+    // Set by the --tor argument
+    pub use_tor: bool,
 }
 
 
@@ -28,15 +58,47 @@ impl std::fmt::Debug for Config {
             .field("botpub", &self.botpub)
             .field("apik", &"***")
             .field("web_port", &self.web_port)
-            .field("nitter_instance", &self.nitter_instance)
+            .field("nitter_instances", &self.nitter_instances)
+            .field("nitter_health_check_secs", &self.nitter_health_check_secs)
+            .field("nitter_health_handle", &self.nitter_health_handle)
             .field("domain", &self.domain)
             .field("refresh_interval_secs", &self.refresh_interval_secs)
+            .field("max_refresh_interval_secs", &self.max_refresh_interval_secs)
+            .field("max_concurrent_fetches", &self.max_concurrent_fetches)
+            .field("max_fetches_per_host", &self.max_fetches_per_host)
+            .field("host_request_interval_ms", &self.host_request_interval_ms)
             .field("relays", &self.relays)
             .field("max_follows", &self.max_follows)
+            .field("media_server", &self.media_server)
+            .field("media_server_type", &self.media_server_type)
+            .field("media_probe", &self.media_probe)
+            .field("link_frontend", &self.link_frontend)
+            .field("unshorten_domains", &self.unshorten_domains)
+            .field("unshorten_timeout_secs", &self.unshorten_timeout_secs)
+            .field("web_dir", &self.web_dir)
+            .field("tor_socks_address", &self.tor_socks_address)
+            .field("operator_pubkeys", &self.operator_pubkeys)
+            .field("alert_dm_kind", &self.alert_dm_kind)
+            .field("alert_failure_threshold", &self.alert_failure_threshold)
+            .field("alert_repeat_secs", &self.alert_repeat_secs)
+            .field("relay_check_secs", &self.relay_check_secs)
+            .field("outbox_retry_secs", &self.outbox_retry_secs)
+            .field("outbox_max_age_secs", &self.outbox_max_age_secs)
+            .field("derive_feed_keys", &self.derive_feed_keys)
+            .field("key_passphrase", &"***")
+            .field("key_scrypt_log_n", &self.key_scrypt_log_n)
+            .field("bunker_url", &if self.bunker_url.is_empty() { "" } else { "***" })
+            .field("use_tor", &self.use_tor)
             .finish()
     }
 }
 
+impl Config {
+    pub fn nostr_json_path(&self) -> std::path::PathBuf {
+        std::path::Path::new(&self.web_dir).join(".well-known/nostr.json")
+    }
+}
+
 pub fn parse_config() -> Config {
     let name = std::env::var("BOTNAME").unwrap_or_default();
     let about = std::env::var("ABOUT").unwrap_or_default();
@@ -46,12 +108,71 @@ pub fn parse_config() -> Config {
     let botpub = std::env::var("BOTPUB").unwrap_or_default();
     let apik = std::env::var("APIK").unwrap_or_default();
     let web_port = std::env::var("WEB_PORT").unwrap_or_default().parse::<u16>().unwrap_or_default();
-    let nitter_instance = std::env::var("NITTER_INSTANCE").unwrap_or_default();
+    // Comma separated hostnames, base URLs with a scheme (e.g. http://localhost:8080) work as well
+    let nitter_instances: Vec<String> = std::env::var("NITTER_INSTANCE")
+        .unwrap_or_default()
+        .split(',')
+        .map(|s| s.trim().trim_end_matches('/'))
+        .filter(|s| !s.is_empty())
+        .map(|s| if s.contains("://") { s.to_string() } else { format!("https://{}", s) })
+        .collect();
+    // Zero turns the periodic health checks off
+    let nitter_health_check_secs = std::env::var("NITTER_HEALTH_CHECK_SECS").unwrap_or_else(|_| "300".to_string()).parse::<u64>().unwrap_or_default();
+    let nitter_health_handle = std::env::var("NITTER_HEALTH_HANDLE").unwrap_or_else(|_| "jack".to_string());
     let domain = std::env::var("DOMAIN").unwrap_or_default();
     let refresh_interval_secs = std::env::var("REFRESH_INTERVAL_SECS").unwrap_or_default().parse::<u64>().unwrap_or_default();
+    // Quiet and failing feeds are polled less often, up to this interval
+    let max_refresh_interval_secs = std::env::var("MAX_REFRESH_INTERVAL_SECS").unwrap_or_else(|_| "3600".to_string()).parse::<u64>().unwrap_or_default();
+    // Feed polls running at the same time, and requests in flight / their spacing per host
+    let max_concurrent_fetches = std::env::var("MAX_CONCURRENT_FETCHES").unwrap_or_else(|_| "8".to_string()).parse::<usize>().unwrap_or_default();
+    let max_fetches_per_host = std::env::var("MAX_FETCHES_PER_HOST").unwrap_or_else(|_| "2".to_string()).parse::<usize>().unwrap_or_default();
+    let host_request_interval_ms = std::env::var("HOST_REQUEST_INTERVAL_MS").unwrap_or_else(|_| "250".to_string()).parse::<u64>().unwrap_or_default();
     let max_follows = std::env::var("MAX_FOLLOWS").unwrap_or_default().parse::<usize>().unwrap_or_default();
     let add_relay = std::env::var("ADD_RELAY").unwrap_or_default();
     let relays: Vec<String> = add_relay.split(',').map(|s| s.to_string()).collect();
+    // Media rehosting is optional, empty MEDIA_SERVER turns it off
+    let media_server = std::env::var("MEDIA_SERVER").unwrap_or_default();
+    let media_server_type = std::env::var("MEDIA_SERVER_TYPE").unwrap_or_else(|_| "blossom".to_string());
+    let media_probe = std::env::var("MEDIA_PROBE").unwrap_or_else(|_| "full".to_string());
+    // Empty LINK_FRONTEND keeps links pointing to the Nitter instance
+    let link_frontend = std::env::var("LINK_FRONTEND").unwrap_or_else(|_| "x.com".to_string());
+    // Empty UNSHORTEN_DOMAINS turns link expansion off
+    let unshorten_domains: Vec<String> = std::env::var("UNSHORTEN_DOMAINS")
+        .unwrap_or_else(|_| "t.co,bit.ly,buff.ly,ow.ly,tinyurl.com,dlvr.it,trib.al,lnkd.in".to_string())
+        .split(',')
+        .map(|s| s.trim().to_lowercase())
+        .filter(|s| !s.is_empty())
+        .collect();
+    let unshorten_timeout_secs = std::env::var("UNSHORTEN_TIMEOUT_SECS").unwrap_or_else(|_| "5".to_string()).parse::<u64>().unwrap_or_default();
+    // Folder holding .well-known/nostr.json
+    let web_dir = std::env::var("WEB_DIR").unwrap_or_else(|_| "web".to_string());
+    // SOCKS5 proxy of the Tor daemon, used with --tor
+    let tor_socks_address = std::env::var("TOR_SOCKS_ADDRESS").unwrap_or_else(|_| "127.0.0.1:9050".to_string());
+    // Comma separated hex or npub keys that get alerts as DMs, empty turns the DMs off
+    let operator_pubkeys: Vec<Option<secp256k1::XOnlyPublicKey>> = std::env::var("OPERATOR_PUBKEYS")
+        .unwrap_or_default()
+        .split(',')
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .map(parse_pubkey)
+        .collect();
+    let alert_dm_kind = crate::dm::DmKind::parse(&std::env::var("ALERT_DM_KIND").unwrap_or_else(|_| "nip17".to_string()));
+    // Consecutive failures of a component before the operators hear about it
+    let alert_failure_threshold = std::env::var("ALERT_FAILURE_THRESHOLD").unwrap_or_else(|_| "3".to_string()).parse::<u32>().unwrap_or_default();
+    // Reminder interval while a component keeps failing, zero sends a single alert
+    let alert_repeat_secs = std::env::var("ALERT_REPEAT_SECS").unwrap_or_else(|_| "86400".to_string()).parse::<u64>().unwrap_or_default();
+    // Zero turns the periodic relay checks off
+    let relay_check_secs = std::env::var("RELAY_CHECK_SECS").unwrap_or_else(|_| "300".to_string()).parse::<u64>().unwrap_or_default();
+    // First retry of an event a relay didn't accept, later ones back off. Events are dropped
+    // after OUTBOX_MAX_AGE_SECS, zero keeps them until every relay accepted them
+    let outbox_retry_secs = std::env::var("OUTBOX_RETRY_SECS").unwrap_or_else(|_| "30".to_string()).parse::<u64>().unwrap_or_default();
+    let outbox_max_age_secs = std::env::var("OUTBOX_MAX_AGE_SECS").unwrap_or_else(|_| "86400".to_string()).parse::<u64>().unwrap_or_default();
+    // Passphrase the feed keys in data/channels are encrypted with, SECRET if empty
+    let key_passphrase = std::env::var("KEY_PASSPHRASE").ok().filter(|s| !s.is_empty()).unwrap_or_else(|| secret.clone());
+    let key_scrypt_log_n = std::env::var("KEY_SCRYPT_LOG_N").unwrap_or_else(|_| "16".to_string()).parse::<u8>().unwrap_or_default();
+    // bunker:// URI, its secret is only needed until the bunker authorized the bot's client key
+    let bunker_url = std::env::var("BUNKER_URL").unwrap_or_default();
+    let derive_feed_keys = std::env::var("DERIVE_FEED_KEYS").unwrap_or_else(|_| "false".to_string()).parse::<bool>().ok();
 
     assert!(!name.is_empty(), "The NAME environment variable is not set.");
     assert!(!about.is_empty(), "The ABOUT environment variable is not set.");
@@ -61,11 +182,24 @@ pub fn parse_config() -> Config {
     assert!(!botpub.is_empty(), "The BOTPUB environment variable is not set");
     assert!(!apik.is_empty(), "The APIK environment variable is not set.");
     assert!(web_port > 0, "The WEB_PORT environment variable is not set or zero.");
-    assert!(!nitter_instance.is_empty(), "The NITTER_INSTANCE environment variable is not set.");
+    assert!(!nitter_instances.is_empty(), "The NITTER_INSTANCE environment variable is not set.");
     assert!(!domain.is_empty(), "The DOMAIN environment variable is not set.");
     assert!(refresh_interval_secs > 0, "The REFRESH_INTERVAL_SECS environment variable is not set or zero.");
+    assert!(max_refresh_interval_secs >= refresh_interval_secs, "The MAX_REFRESH_INTERVAL_SECS environment variable has to be at least REFRESH_INTERVAL_SECS.");
+    assert!(max_concurrent_fetches > 0, "The MAX_CONCURRENT_FETCHES environment variable is zero or invalid.");
+    assert!(max_fetches_per_host > 0, "The MAX_FETCHES_PER_HOST environment variable is zero or invalid.");
     assert!(!relays.is_empty(), "The ADD_RELAY environment variable is not set.");
     assert!(max_follows > 0, "The MAX_FOLLOWS environment variable is not set or zero.");
+    assert!(media_server_type == "blossom" || media_server_type == "nip96", "The MEDIA_SERVER_TYPE environment variable has to be blossom or nip96.");
+    assert!(["off", "headers", "full"].contains(&media_probe.as_str()), "The MEDIA_PROBE environment variable has to be off, headers or full.");
+    assert!(unshorten_timeout_secs > 0, "The UNSHORTEN_TIMEOUT_SECS environment variable is zero or invalid.");
+    assert!(operator_pubkeys.iter().all(Option::is_some), "The OPERATOR_PUBKEYS environment variable has an invalid key.");
+    assert!(alert_dm_kind.is_some(), "The ALERT_DM_KIND environment variable has to be nip04 or nip17.");
+    assert!(alert_failure_threshold > 0, "The ALERT_FAILURE_THRESHOLD environment variable is zero or invalid.");
+    assert!(outbox_retry_secs > 0, "The OUTBOX_RETRY_SECS environment variable is zero or invalid.");
+    assert!((1..=30).contains(&key_scrypt_log_n), "The KEY_SCRYPT_LOG_N environment variable has to be between 1 and 30.");
+    assert!(derive_feed_keys.is_some(), "The DERIVE_FEED_KEYS environment variable has to be true or false.");
+    assert!(bunker_url.is_empty() || bunker_url.starts_with("bunker://"), "The BUNKER_URL environment variable has to be a bunker:// URI.");
 
     Config {
         name,
@@ -76,14 +210,82 @@ pub fn parse_config() -> Config {
         botpub,
         apik,
         web_port,
-        nitter_instance,
+        nitter_instances,
+        nitter_health_check_secs,
+        nitter_health_handle,
         domain,
         refresh_interval_secs,
+        max_refresh_interval_secs,
+        max_concurrent_fetches,
+        max_fetches_per_host,
+        host_request_interval_ms,
         relays,
         max_follows,
+        media_server,
+        media_server_type,
+        media_probe,
+        link_frontend,
+        unshorten_domains,
+        unshorten_timeout_secs,
+        web_dir,
+        tor_socks_address,
+        operator_pubkeys: operator_pubkeys.into_iter().flatten().collect(),
+        alert_dm_kind: alert_dm_kind.unwrap(),
+        alert_failure_threshold,
+        alert_repeat_secs,
+        relay_check_secs,
+        outbox_retry_secs,
+        outbox_max_age_secs,
+        derive_feed_keys: derive_feed_keys.unwrap(),
+        key_passphrase,
+        key_scrypt_log_n,
+        bunker_url,
+        use_tor: false,
+    }
+}
+
+
+// Every HTTP client starts from this builder, with --tor it sends all requests through the Tor
+// SOCKS proxy. socks5h resolves the hostnames through Tor as well.
+pub fn http_client_builder(config: &Config) -> reqwest::ClientBuilder {
+    let builder = reqwest::Client::builder();
+    if !config.use_tor {
+        return builder;
+    }
+    let proxy = reqwest::Proxy::all(format!("socks5h://{}", config.tor_socks_address))
+        .expect("The TOR_SOCKS_ADDRESS environment variable is not a valid address.");
+    builder.proxy(proxy)
+}
+
+// Client shared by Nitter, RSS feeds, pages linked from them, media and Discord
+pub fn http_client(config: &Config) -> reqwest::Client {
+    http_client_builder(config)
+        .timeout(std::time::Duration::from_secs(30))
+        .build()
+        .expect("Failed to create HTTP client")
+}
+
+// Public key in hex or as an npub
+pub fn parse_pubkey(key: &str) -> Option<secp256k1::XOnlyPublicKey> {
+    use bech32::FromBase32;
+    if let Ok((hrp, data, bech32::Variant::Bech32)) = bech32::decode(key) {
+        if hrp != "npub" {
+            return None;
+        }
+        let bytes = Vec::<u8>::from_base32(&data).ok()?;
+        return secp256k1::XOnlyPublicKey::from_slice(&bytes).ok();
     }
+    key.parse().ok()
 }
 
+pub fn encode_nsec(secret: &secp256k1::SecretKey) -> String {
+    use bech32::ToBase32;
+    bech32::encode("nsec", secret.secret_bytes().to_base32(), bech32::Variant::Bech32).expect("nsec is a valid prefix")
+}
+
+pub fn is_relay_url(relay: &str) -> bool {
+    url::Url::parse(relay).is_ok_and(|url| (url.scheme() == "ws" || url.scheme() == "wss") && url.host_str().is_some())
+}
 
 pub fn unix_timestamp() -> u64 {
     std::time::SystemTime::now()
@@ -97,3 +299,73 @@ pub fn get_random_keypair() -> secp256k1::KeyPair {
     let secret = secp256k1::SecretKey::new(&mut rand::thread_rng());
     secret.keypair(&secp)
 }
+
+// Key of `feed` derived from the master key with HKDF-SHA256, the same master key and feed
+// always give the same key
+pub fn derive_keypair(master: &secp256k1::KeyPair, feed: &crate::simpledb::FeedId) -> secp256k1::KeyPair {
+    hkdf_keypair(master, b"dostr feed key", feed.to_string().as_bytes())
+}
+
+// Key the bot talks to NIP-46 bunkers with, the same for every bunker and run so a bunker only
+// has to authorize it once
+pub fn bunker_client_keypair(master: &secp256k1::KeyPair) -> secp256k1::KeyPair {
+    hkdf_keypair(master, b"dostr nip46 client", b"")
+}
+
+fn hkdf_keypair(master: &secp256k1::KeyPair, salt: &[u8], info: &[u8]) -> secp256k1::KeyPair {
+    let secp = secp256k1::Secp256k1::new();
+    let hkdf = hkdf::Hkdf::<sha2::Sha256>::new(Some(salt), &master.secret_bytes());
+    // Out of range outputs are about 2^-128 likely, the counter picks the next one
+    for counter in 0u32.. {
+        let mut secret = [0u8; 32];
+        let info = [info, &counter.to_be_bytes()].concat();
+        hkdf.expand(&info, &mut secret).expect("32 bytes is a valid HKDF length");
+        if let Ok(secret) = secp256k1::SecretKey::from_slice(&secret) {
+            return secret.keypair(&secp);
+        }
+    }
+    unreachable!()
+}
+
+// Key of a feed added now, derived with DERIVE_FEED_KEYS and random otherwise
+pub fn new_feed_keypair(config: &Config, feed: &crate::simpledb::FeedId) -> secp256k1::KeyPair {
+    if config.derive_feed_keys {
+        derive_keypair(&nostr_bot::keypair_from_secret(&config.secret), feed)
+    } else {
+        get_random_keypair()
+    }
+}
+
+// Map that forgets its oldest entries once it holds `capacity` of them
+pub struct BoundedCache<V> {
+    capacity: usize,
+    entries: std::collections::HashMap<String, V>,
+    // Keys from the oldest to the newest
+    order: std::collections::VecDeque<String>,
+}
+
+impl<V> BoundedCache<V> {
+    pub fn new(capacity: usize) -> BoundedCache<V> {
+        BoundedCache {
+            capacity,
+            entries: std::collections::HashMap::new(),
+            order: std::collections::VecDeque::new(),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&V> {
+        self.entries.get(key)
+    }
+
+    pub fn insert(&mut self, key: String, value: V) {
+        if self.entries.insert(key.clone(), value).is_some() {
+            return;
+        }
+        self.order.push_back(key);
+        while self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.entries.remove(&oldest);
+            }
+        }
+    }
+}